@@ -0,0 +1,18 @@
+-- Add migration script here
+
+create table user_action (
+  id bigserial primary key,
+  action varchar not null,
+  created timestamptz not null default current_timestamp
+);
+
+create table user_action_delivery (
+  user_action_id bigint not null references user_action,
+  device_id bigint not null references device,
+  success bool,
+  error varchar,
+  acked timestamptz,
+  primary key (user_action_id, device_id)
+);
+
+create index user_action_delivery_device_idx on user_action_delivery using btree(device_id);
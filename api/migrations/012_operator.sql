@@ -0,0 +1,11 @@
+-- Add migration script here
+
+create table operator (
+  id bigserial primary key,
+  name varchar not null unique,
+  token_hash varchar not null unique,
+  active boolean not null default true,
+  created timestamptz not null default current_timestamp
+);
+
+alter table entry_log add column operator_id bigint references operator;
@@ -0,0 +1,12 @@
+-- Add migration script here
+
+create table customer_device (
+  customer_id bigint not null references customer,
+  device_id bigint not null references device,
+  primary key (customer_id, device_id)
+);
+
+create index customer_device_device_idx on customer_device using btree(device_id);
+
+-- Every door used to accept every code, keep it that way for existing data
+insert into customer_device (customer_id, device_id) select c.id, d.id from customer c, device d;
@@ -0,0 +1,23 @@
+-- Add migration script here
+
+create table schedule (
+  id bigserial primary key,
+  name varchar not null unique,
+  -- POSIX TZ string evaluated by the doors
+  timezone varchar not null,
+  created timestamptz not null default current_timestamp
+);
+
+create table schedule_window (
+  id bigserial primary key,
+  schedule_id bigint not null references schedule on delete cascade,
+  -- Bit mask of weekdays, bit 0 is Sunday
+  weekdays smallint not null,
+  start_time time not null,
+  end_time time not null
+);
+
+create index schedule_window_schedule_idx on schedule_window using btree(schedule_id);
+
+alter table customer add column schedule_id bigint references schedule;
+alter table staff add column schedule_id bigint references schedule;
@@ -0,0 +1,10 @@
+-- Add migration script here
+
+alter table entry_log add column reason varchar;
+
+-- Doors could only reject unknown codes before reasons were reported
+update entry_log set reason = case when success then 'granted' else 'unknown_code' end;
+
+alter table entry_log alter column reason set not null;
+
+create index entry_log_reason_idx on entry_log using btree(reason);
@@ -0,0 +1,25 @@
+-- Add migration script here
+
+create table firmware_release (
+  id bigserial primary key,
+  version varchar not null unique,
+  sha256 varchar not null,
+  size int not null,
+  image bytea not null,
+  created timestamptz not null default current_timestamp
+);
+
+create table firmware_update (
+  id bigserial primary key,
+  device_id bigint not null references device,
+  release_id bigint not null references firmware_release,
+  operator_id bigint not null references operator,
+  -- pending, downloading, rebooting, completed, rolled_back or failed
+  state varchar not null default 'pending',
+  progress smallint not null default 0,
+  error varchar,
+  created timestamptz not null default current_timestamp,
+  updated timestamptz not null default current_timestamp
+);
+
+create index firmware_update_device_idx on firmware_update using btree(device_id);
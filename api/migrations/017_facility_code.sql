@@ -0,0 +1,16 @@
+-- Add migration script here
+
+-- Cards were stored as 26-bit codes, facility code in the upper bits
+alter table staff add column facility_code int;
+update staff set facility_code = fob >> 16, fob = fob & 65535 where fob is not null;
+
+alter table staff drop constraint staff_fob_key;
+alter table staff add constraint unique_staff_card unique (facility_code, fob);
+alter table staff add constraint staff_card_facility check ((fob is null) = (facility_code is null));
+
+alter table entry_log add column facility_code int;
+update entry_log set facility_code = code >> 16, code = code & 65535
+where code_type = 'fob' and code <> 0;
+
+-- Facility codes the door accepts, empty accepts any
+alter table device add column facility_codes int[] not null default '{}';
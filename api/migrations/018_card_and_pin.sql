@@ -0,0 +1,8 @@
+-- Add migration script here
+
+-- Cards must be followed by the holder's pin, either for the staff member or
+-- for everyone on the door
+alter table staff add column card_and_pin boolean not null default false;
+alter table device add column card_and_pin boolean not null default false;
+
+alter table staff add constraint staff_card_and_pin check (not card_and_pin or fob is not null);
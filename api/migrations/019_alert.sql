@@ -0,0 +1,15 @@
+-- Add migration script here
+
+create table alert (
+  id bigserial primary key,
+  device_id bigint not null references device,
+  -- lockout
+  kind varchar not null,
+  message varchar not null,
+  event_date timestamptz not null,
+  created timestamptz not null default current_timestamp,
+  acknowledged timestamptz,
+  operator_id bigint references operator
+);
+
+create index alert_open_idx on alert using btree(created) where acknowledged is null;
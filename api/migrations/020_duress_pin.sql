@@ -0,0 +1,7 @@
+-- Add migration script here
+
+-- Opens like the pin and raises a silent alarm
+alter table staff add column duress_pin int unique;
+alter table staff add constraint staff_duress_pin check (duress_pin <> pin);
+
+alter table entry_log add column duress boolean not null default false;
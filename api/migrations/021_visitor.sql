@@ -0,0 +1,10 @@
+-- Add migration script here
+
+-- Visitor codes only open the doors within their time limits and for the uses
+-- left, regular staff leaves them empty
+alter table staff add column valid_from timestamptz;
+alter table staff add column valid_until timestamptz;
+alter table staff add column uses_left int;
+
+alter table staff add constraint staff_validity check (valid_until > valid_from);
+alter table staff add constraint staff_uses_left check (uses_left >= 0);
@@ -0,0 +1,10 @@
+-- Add migration script here
+
+-- Secret the codes are hashed with before they reach the doors. The api
+-- creates the single row the first time it needs it.
+create table site_key (
+  id int primary key default 1 check (id = 1),
+  salt bytea not null check (length(salt) = 16),
+  key bytea not null check (length(key) = 32),
+  created timestamptz not null default current_timestamp
+);
@@ -0,0 +1,5 @@
+-- Add migration script here
+
+-- Images are only served to the device an update was sent to
+alter table firmware_update add column token_hash varchar;
+alter table firmware_update add column token_expires timestamptz;
@@ -0,0 +1,21 @@
+-- Add migration script here
+
+-- Pins and duress pins share one space, a duress pin equal to somebody else's
+-- pin would open as that person without raising the alarm
+create function staff_pin_unique() returns trigger as $$
+begin
+  -- Serializes the check between concurrent writers
+  perform pg_advisory_xact_lock(hashtext('staff_pin_unique'));
+  if exists (
+    select 1 from staff
+    where id <> new.id
+      and (duress_pin = new.pin or (new.duress_pin is not null and pin = new.duress_pin))
+  ) then
+    raise exception 'pin already in use' using errcode = 'unique_violation', constraint = 'staff_pin_unique';
+  end if;
+  return new;
+end;
+$$ language plpgsql;
+
+create trigger staff_pin_unique before insert or update of pin, duress_pin on staff
+  for each row execute function staff_pin_unique();
@@ -0,0 +1,142 @@
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use doorsys_protocol::{Alert as DeviceAlert, AlertKind};
+use serde::Serialize;
+use sqlx::{PgPool, Postgres, Transaction};
+
+use super::entry_log::EntryLog;
+
+/// Channel alerts are announced on as they arrive, carrying the alert as json
+const NOTIFY_CHANNEL: &str = "doorsys_alert";
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Alert {
+    pub id: i64,
+    pub device_id: i64,
+    pub kind: String,
+    pub message: String,
+    pub event_date: DateTime<Utc>,
+    pub created: DateTime<Utc>,
+    pub acknowledged: Option<DateTime<Utc>>,
+    pub operator_id: Option<i64>,
+}
+
+/// Human readable description of the alert
+fn describe(kind: &AlertKind) -> String {
+    match kind {
+        AlertKind::Lockout { failures, duration } => format!(
+            "Keypad locked for {}s after {} failed attempts",
+            duration.as_secs(),
+            failures
+        ),
+        AlertKind::DoorForced => "Door forced open".to_string(),
+        AlertKind::DoorHeldOpen { held_open } => {
+            format!("Door held open for more than {}s", held_open.as_secs())
+        }
+        AlertKind::DoorClosed => "Door closed".to_string(),
+        AlertKind::ReaderNoise { frames, period } => format!(
+            "Reader sent {} unreadable frames in {}s, check the wiring",
+            frames,
+            period.as_secs()
+        ),
+        AlertKind::AuditsDropped { records } => format!(
+            "Audit log overflowed while offline, {} entries were lost",
+            records
+        ),
+    }
+}
+
+/// Announces the alert to the listeners of the alert channel
+async fn notify(tx: &mut Transaction<'_, Postgres>, alert: &Alert) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"select pg_notify($1, $2)"#,
+        NOTIFY_CHANNEL,
+        serde_json::to_string(alert)?,
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct AlertRepository {
+    pub pool: PgPool,
+}
+
+impl AlertRepository {
+    /// Records an alert raised by a device and notifies the listeners of
+    /// the alert channel
+    pub async fn create(&self, alert: &DeviceAlert, net_id: &str) -> anyhow::Result<Alert> {
+        let event_date: DateTime<Utc> = alert.timestamp.into();
+        let mut tx = self.pool.begin().await?;
+        let alert = sqlx::query_as!(
+            Alert,
+            r#"
+            insert into alert (device_id, kind, message, event_date)
+            select id, $2, $3, $4 from device where net_id = $1
+            returning *
+            "#,
+            net_id,
+            alert.kind.to_string(),
+            describe(&alert.kind),
+            event_date,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        notify(&mut tx, &alert).await?;
+        tx.commit().await?;
+        Ok(alert)
+    }
+
+    /// Raises the silent alarm for an entry made with a duress pin
+    pub async fn create_duress(&self, entry: &EntryLog) -> anyhow::Result<Alert> {
+        let device_id = entry
+            .device_id
+            .context("duress entry from an unknown device")?;
+        let mut tx = self.pool.begin().await?;
+        let alert = sqlx::query_as!(
+            Alert,
+            r#"
+            insert into alert (device_id, kind, message, event_date)
+            values ($1, 'duress',
+                'Duress pin entered by ' || coalesce((select name from staff where id = $2), 'unknown staff'),
+                $3)
+            returning *
+            "#,
+            device_id,
+            entry.staff_id,
+            entry.event_date,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        notify(&mut tx, &alert).await?;
+        tx.commit().await?;
+        Ok(alert)
+    }
+
+    /// Alerts no operator has acknowledged yet, newest first
+    pub async fn fetch_open(&self) -> Result<Vec<Alert>, sqlx::Error> {
+        sqlx::query_as!(
+            Alert,
+            r#"select * from alert where acknowledged is null order by created desc"#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn acknowledge(&self, id: i64, operator_id: i64) -> Result<Alert, sqlx::Error> {
+        sqlx::query_as!(
+            Alert,
+            r#"
+            update alert set acknowledged = current_timestamp, operator_id = $2
+            where id = $1 and acknowledged is null
+            returning *
+            "#,
+            id,
+            operator_id,
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+}
@@ -9,6 +9,7 @@ pub struct Customer {
     pub email: String,
     pub active: bool,
     pub notes: Option<String>,
+    pub schedule_id: Option<i64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -17,6 +18,7 @@ pub struct NewCustomer {
     pub name: String,
     pub email: String,
     pub notes: Option<String>,
+    pub schedule_id: Option<i64>,
 }
 
 #[derive(Clone)]
@@ -48,10 +50,11 @@ impl CustomerRepository {
     ) -> Result<Customer, sqlx::Error> {
         sqlx::query_as!(
             Customer,
-            r#"update customer set name = $1, email = $2, notes = $3 where id = $4 returning *"#,
+            r#"update customer set name = $1, email = $2, notes = $3, schedule_id = $4 where id = $5 returning *"#,
             new_customer.name,
             new_customer.email,
             new_customer.notes,
+            new_customer.schedule_id,
             id,
         )
         .fetch_one(&self.pool)
@@ -72,10 +75,11 @@ impl CustomerRepository {
     pub async fn create(&self, new_customer: &NewCustomer) -> Result<Customer, sqlx::Error> {
         sqlx::query_as!(
             Customer,
-            r#"insert into customer (name, email, notes) values ($1, $2, $3) returning *"#,
+            r#"insert into customer (name, email, notes, schedule_id) values ($1, $2, $3, $4) returning *"#,
             new_customer.name,
             new_customer.email,
             new_customer.notes,
+            new_customer.schedule_id,
         )
         .fetch_one(&self.pool)
         .await
@@ -1,12 +1,31 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::Utc;
+use doorsys_protocol::{
+    ButtonConfig, DeviceCommand, DoorSensorConfig, KeypadConfig, LockoutConfig, NetworkConfig,
+    RelayConfig, SecurityMode, TlsConfig,
+};
+use rumqttc::{AsyncClient, QoS};
 use serde::Serialize;
 use sqlx::PgPool;
 
+use super::{
+    entry_log::{EntryLog, EntryLogRepository},
+    firmware::{FirmwareRepository, FirmwareUpdate},
+    operator::{generate_token, Operator},
+};
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Device {
     pub id: i64,
     pub name: String,
     pub net_id: String,
+    /// Facility codes the door accepts, empty accepts any
+    pub facility_codes: Vec<i32>,
+    /// Every card must be followed by the holder's pin
+    pub card_and_pin: bool,
 }
 
 #[derive(Clone)]
@@ -20,4 +39,361 @@ impl DeviceRepository {
             .fetch_all(&self.pool)
             .await
     }
+
+    pub async fn fetch_one(&self, id: i64) -> Result<Device, sqlx::Error> {
+        sqlx::query_as!(Device, r#"select * from device where id = $1"#, id)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    pub async fn fetch_by_customer(&self, customer_id: i64) -> Result<Vec<Device>, sqlx::Error> {
+        sqlx::query_as!(
+            Device,
+            r#"
+            select d.* from device d
+            join customer_device cd on cd.device_id = d.id
+            where cd.customer_id = $1
+            order by d.name
+            "#,
+            customer_id
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Replaces the devices the customer's staff can open, returning the ones
+    /// that gained or lost access
+    pub async fn update_customer_devices(
+        &self,
+        customer_id: i64,
+        device_ids: &[i64],
+    ) -> Result<Vec<Device>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let changed = sqlx::query_as!(
+            Device,
+            r#"
+            select * from device d
+            where (d.id = any($2)) <> exists (
+                select 1 from customer_device cd where cd.customer_id = $1 and cd.device_id = d.id
+            )
+            order by d.name
+            "#,
+            customer_id,
+            device_ids,
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"delete from customer_device where customer_id = $1 and device_id <> all($2)"#,
+            customer_id,
+            device_ids,
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"
+            insert into customer_device (customer_id, device_id)
+            select $1, unnest($2::bigint[])
+            on conflict do nothing
+            "#,
+            customer_id,
+            device_ids,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(changed)
+    }
+
+    pub async fn update_facility_codes(
+        &self,
+        id: i64,
+        facility_codes: &[i32],
+    ) -> Result<Device, sqlx::Error> {
+        sqlx::query_as!(
+            Device,
+            r#"update device set facility_codes = $1 where id = $2 returning *"#,
+            facility_codes,
+            id,
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn update_card_and_pin(
+        &self,
+        id: i64,
+        card_and_pin: bool,
+    ) -> Result<Device, sqlx::Error> {
+        sqlx::query_as!(
+            Device,
+            r#"update device set card_and_pin = $1 where id = $2 returning *"#,
+            card_and_pin,
+            id,
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn fetch_by_net_id(&self, net_id: &str) -> Result<Option<Device>, sqlx::Error> {
+        sqlx::query_as!(Device, r#"select * from device where net_id = $1"#, net_id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+}
+
+#[derive(Clone)]
+pub struct DeviceService {
+    pub device_repo: DeviceRepository,
+    pub entry_log_repo: EntryLogRepository,
+    pub firmware_repo: FirmwareRepository,
+    pub mqtt_client: AsyncClient,
+    /// Base url devices use to reach the api, needed to serve firmware images
+    pub public_url: Option<String>,
+}
+
+impl DeviceService {
+    /// Opens the door remotely and logs the entry under the operator
+    pub async fn unlock(
+        &self,
+        id: i64,
+        operator: &Operator,
+        duration: Option<Duration>,
+    ) -> anyhow::Result<EntryLog> {
+        let device = self.device_repo.fetch_one(id).await?;
+        self.send_command(&device, &DeviceCommand::Unlock { duration })
+            .await?;
+        tracing::info!("Device {} unlocked by {}", device.net_id, operator.name);
+        let entry_log = self
+            .entry_log_repo
+            .create_remote(device.id, operator.id, &Utc::now())
+            .await?;
+        Ok(entry_log)
+    }
+
+    /// Sends the release to the device and tracks the update under the operator
+    pub async fn update_firmware(
+        &self,
+        id: i64,
+        release_id: i64,
+        operator: &Operator,
+    ) -> anyhow::Result<FirmwareUpdate> {
+        let public_url = self
+            .public_url
+            .as_deref()
+            .context("PUBLIC_URL is not configured")?;
+        let device = self.device_repo.fetch_one(id).await?;
+        let release = self.firmware_repo.fetch_release(release_id).await?;
+        let token = generate_token();
+        let update = self
+            .firmware_repo
+            .create_update(device.id, release.id, operator.id, &token)
+            .await?;
+        let command = DeviceCommand::Update {
+            job: update.id as u64,
+            url: format!(
+                "{}/firmware/{}/image?job={}&token={}",
+                public_url.trim_end_matches('/'),
+                release.id,
+                update.id,
+                token
+            ),
+            sha256: release.sha256_bytes()?,
+        };
+        self.send_command(&device, &command).await?;
+        tracing::info!(
+            "Device {} updating to {} by {}",
+            device.net_id,
+            release.version,
+            operator.name
+        );
+        Ok(update)
+    }
+
+    /// Rotates the credentials the device uses to reach the network and broker
+    pub async fn set_network(
+        &self,
+        id: i64,
+        config: NetworkConfig,
+        operator: &Operator,
+    ) -> anyhow::Result<()> {
+        let device = self.device_repo.fetch_one(id).await?;
+        self.send_command(&device, &DeviceCommand::SetNetwork(config))
+            .await?;
+        tracing::info!(
+            "Device {} network credentials rotated by {}",
+            device.net_id,
+            operator.name
+        );
+        Ok(())
+    }
+
+    /// Replaces the certificates the device uses for the broker connection
+    pub async fn set_tls(
+        &self,
+        id: i64,
+        config: TlsConfig,
+        operator: &Operator,
+    ) -> anyhow::Result<()> {
+        let device = self.device_repo.fetch_one(id).await?;
+        self.send_command(&device, &DeviceCommand::SetTls(config))
+            .await?;
+        tracing::info!(
+            "Device {} certificates replaced by {}",
+            device.net_id,
+            operator.name
+        );
+        Ok(())
+    }
+
+    /// Restricts the cards the device accepts to the given facility codes
+    pub async fn set_facility_codes(
+        &self,
+        id: i64,
+        facility_codes: &[i32],
+        operator: &Operator,
+    ) -> anyhow::Result<Device> {
+        let device = self
+            .device_repo
+            .update_facility_codes(id, facility_codes)
+            .await?;
+        let facilities = facility_codes.iter().map(|&f| f as u32).collect();
+        self.send_command(&device, &DeviceCommand::SetFacilities(facilities))
+            .await?;
+        tracing::info!(
+            "Device {} facility codes set to {:?} by {}",
+            device.net_id,
+            facility_codes,
+            operator.name
+        );
+        Ok(device)
+    }
+
+    /// Switches the device between accepting cards or pins alone and requiring both
+    pub async fn set_security_mode(
+        &self,
+        id: i64,
+        card_and_pin: bool,
+        operator: &Operator,
+    ) -> anyhow::Result<Device> {
+        let device = self
+            .device_repo
+            .update_card_and_pin(id, card_and_pin)
+            .await?;
+        let mode = match card_and_pin {
+            true => SecurityMode::CardAndPin,
+            false => SecurityMode::CardOrPin,
+        };
+        self.send_command(&device, &DeviceCommand::SetSecurityMode(mode))
+            .await?;
+        tracing::info!(
+            "Device {} security mode set to {:?} by {}",
+            device.net_id,
+            mode,
+            operator.name
+        );
+        Ok(device)
+    }
+
+    pub async fn set_keypad(
+        &self,
+        id: i64,
+        config: KeypadConfig,
+        operator: &Operator,
+    ) -> anyhow::Result<()> {
+        let device = self.device_repo.fetch_one(id).await?;
+        tracing::info!(
+            "Device {} keypad set to {:?} by {}",
+            device.net_id,
+            config,
+            operator.name
+        );
+        self.send_command(&device, &DeviceCommand::SetKeypad(config))
+            .await
+    }
+
+    pub async fn set_lockout(
+        &self,
+        id: i64,
+        config: LockoutConfig,
+        operator: &Operator,
+    ) -> anyhow::Result<()> {
+        let device = self.device_repo.fetch_one(id).await?;
+        tracing::info!(
+            "Device {} lockout set to {:?} by {}",
+            device.net_id,
+            config,
+            operator.name
+        );
+        self.send_command(&device, &DeviceCommand::SetLockout(config))
+            .await
+    }
+
+    pub async fn set_door_sensor(
+        &self,
+        id: i64,
+        config: DoorSensorConfig,
+        operator: &Operator,
+    ) -> anyhow::Result<()> {
+        let device = self.device_repo.fetch_one(id).await?;
+        tracing::info!(
+            "Device {} door sensor set to {:?} by {}",
+            device.net_id,
+            config,
+            operator.name
+        );
+        self.send_command(&device, &DeviceCommand::SetDoorSensor(config))
+            .await
+    }
+
+    pub async fn set_relay(
+        &self,
+        id: i64,
+        config: RelayConfig,
+        operator: &Operator,
+    ) -> anyhow::Result<()> {
+        let device = self.device_repo.fetch_one(id).await?;
+        tracing::info!(
+            "Device {} relay set to {:?} by {}",
+            device.net_id,
+            config,
+            operator.name
+        );
+        self.send_command(&device, &DeviceCommand::SetRelay(config))
+            .await
+    }
+
+    pub async fn set_button(
+        &self,
+        id: i64,
+        config: ButtonConfig,
+        operator: &Operator,
+    ) -> anyhow::Result<()> {
+        let device = self.device_repo.fetch_one(id).await?;
+        tracing::info!(
+            "Device {} button set to {:?} by {}",
+            device.net_id,
+            config,
+            operator.name
+        );
+        self.send_command(&device, &DeviceCommand::SetButton(config))
+            .await
+    }
+
+    pub async fn send_command(
+        &self,
+        device: &Device,
+        command: &DeviceCommand,
+    ) -> anyhow::Result<()> {
+        let payload = doorsys_protocol::encode(command)?;
+        self.mqtt_client
+            .publish(
+                format!("doorsys/device/{}", device.net_id),
+                QoS::AtLeastOnce,
+                false,
+                payload,
+            )
+            .await?;
+        Ok(())
+    }
 }
@@ -1,6 +1,7 @@
 use std::ops::Range;
 
 use chrono::{DateTime, Utc};
+use doorsys_protocol::Audit;
 use serde::Serialize;
 use sqlx::PgPool;
 
@@ -15,6 +16,11 @@ pub struct EntryLog {
     pub success: bool,
     pub event_date: DateTime<Utc>,
     pub created: DateTime<Utc>,
+    pub operator_id: Option<i64>,
+    pub reason: String,
+    pub facility_code: Option<i32>,
+    /// Entered with the duress pin
+    pub duress: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -27,10 +33,14 @@ pub struct EntryLogDisplay {
     pub customer_name: Option<String>,
     pub device_id: Option<i64>,
     pub device_name: Option<String>,
+    pub operator_name: Option<String>,
     pub code: i32,
+    pub facility_code: Option<i32>,
     pub code_type: String,
     pub success: bool,
+    pub reason: String,
     pub event_date: DateTime<Utc>,
+    pub duress: bool,
 }
 
 #[derive(Clone)]
@@ -39,29 +49,61 @@ pub struct EntryLogRepository {
 }
 
 impl EntryLogRepository {
+    /// Records an access attempt reported by a door.
+    ///
+    /// Doors only hold active codes, so an unknown code that belongs to a
+    /// disabled staff member is stored as `user_disabled`. Cards match on
+    /// facility code and card number, pins are reported without a facility.
+    /// Duress entries carry the duress pin, older doors sent the regular one.
     pub async fn create_with_code(
         &self,
-        code: i32,
-        code_type: &str,
+        audit: &Audit,
         net_id: Option<&str>,
-        success: bool,
-        event_date: &DateTime<Utc>,
     ) -> Result<EntryLog, sqlx::Error> {
+        let event_date: DateTime<Utc> = audit.timestamp.into();
         sqlx::query_as!(
             EntryLog,
             r#"
-            with temp(code, net_id) as (values($1::int, $3::varchar))
-            insert into entry_log (staff_id, code, code_type, device_id, success, event_date) 
-                select s.id, t.code, $2, d.id, $4, $5
+            with temp(code, facility_code, net_id) as (values($1::int, $2::int, $4::varchar))
+            insert into entry_log (staff_id, code, facility_code, code_type, device_id, success, reason, event_date, duress)
+                select s.id, t.code, t.facility_code, $3, d.id, $5,
+                    case when $6 = 'unknown_code' and s.active is false then 'user_disabled' else $6 end,
+                    $7, $8
                 from temp t
-                left join staff s on s.pin = t.code or s.fob = t.code
+                left join staff s on (t.facility_code is null and $3 = 'pin' and (s.pin = t.code or ($8 and s.duress_pin = t.code)))
+                    or (s.fob = t.code and s.facility_code = t.facility_code)
                 left join device d on d.net_id = t.net_id
             returning *
             "#,
-            code,
-            code_type,
+            audit.code,
+            audit.facility.map(|f| f as i32),
+            audit.code_type.to_string(),
             net_id,
-            success,
+            audit.success,
+            audit.reason.to_string(),
+            event_date,
+            audit.duress,
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Records a door opened remotely by an operator
+    pub async fn create_remote(
+        &self,
+        device_id: i64,
+        operator_id: i64,
+        event_date: &DateTime<Utc>,
+    ) -> Result<EntryLog, sqlx::Error> {
+        sqlx::query_as!(
+            EntryLog,
+            r#"
+            insert into entry_log (code, code_type, device_id, operator_id, success, reason, event_date)
+            values (0, 'remote', $1, $2, true, 'granted', $3)
+            returning *
+            "#,
+            device_id,
+            operator_id,
             event_date
         )
         .fetch_one(&self.pool)
@@ -73,6 +115,7 @@ impl EntryLogRepository {
         date_range: Range<DateTime<Utc>>,
         device_id: Option<i64>,
         customer_id: Option<i64>,
+        reason: Option<&str>,
     ) -> Result<Vec<EntryLogDisplay>, sqlx::Error> {
         sqlx::query_as!(
             EntryLogDisplay,
@@ -85,23 +128,30 @@ impl EntryLogRepository {
                 c.name as "customer_name?",
                 d.id as "device_id?",
                 d.name as "device_name?",
+                o.name as "operator_name?",
                 e.code,
+                e.facility_code,
                 e.code_type,
                 e.success,
-                e.event_date
+                e.reason,
+                e.event_date,
+                e.duress
             from entry_log e
             left join staff s on s.id = e.staff_id
             left join customer c on s.customer_id = c.id
             left join device d on d.id = e.device_id
+            left join operator o on o.id = e.operator_id
             where e.event_date between $1 and $2
             and (d.id = $3 or $3 is null)
             and (c.id = $4 or $4 is null)
+            and (e.reason = $5 or $5 is null)
             order by e.event_date desc
             "#,
             date_range.start,
             date_range.end,
             device_id,
             customer_id,
+            reason,
         )
         .fetch_all(&self.pool)
         .await
@@ -0,0 +1,195 @@
+use chrono::{DateTime, Utc};
+use doorsys_protocol::UpdateState;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+use super::operator::hash_token;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FirmwareRelease {
+    pub id: i64,
+    pub version: String,
+    pub sha256: String,
+    pub size: i32,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FirmwareUpdate {
+    pub id: i64,
+    pub device_id: i64,
+    pub release_id: i64,
+    pub version: String,
+    pub operator_id: i64,
+    pub state: String,
+    pub progress: i16,
+    pub error: Option<String>,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+}
+
+impl FirmwareRelease {
+    /// Hash in the form carried by the update command
+    pub fn sha256_bytes(&self) -> anyhow::Result<[u8; 32]> {
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let hex = self.sha256.get(i * 2..i * 2 + 2).unwrap_or_default();
+            *byte = u8::from_str_radix(hex, 16)?;
+        }
+        Ok(bytes)
+    }
+}
+
+/// Splits a device report into state, progress and error columns
+fn status_columns(state: &UpdateState) -> (&'static str, Option<i16>, Option<&str>) {
+    match state {
+        UpdateState::Downloading(percent) => ("downloading", Some(*percent as i16), None),
+        UpdateState::Rebooting => ("rebooting", Some(100), None),
+        UpdateState::Completed { .. } => ("completed", Some(100), None),
+        UpdateState::RolledBack => ("rolled_back", None, None),
+        UpdateState::Failed(e) => ("failed", None, Some(e)),
+    }
+}
+
+#[derive(Clone)]
+pub struct FirmwareRepository {
+    pub pool: PgPool,
+}
+
+impl FirmwareRepository {
+    pub async fn create_release(
+        &self,
+        version: &str,
+        image: &[u8],
+    ) -> Result<FirmwareRelease, sqlx::Error> {
+        let sha256 = format!("{:x}", Sha256::digest(image));
+        sqlx::query_as!(
+            FirmwareRelease,
+            r#"
+            insert into firmware_release (version, sha256, size, image) values ($1, $2, $3, $4)
+            returning id, version, sha256, size, created
+            "#,
+            version,
+            sha256,
+            image.len() as i32,
+            image,
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn fetch_releases(&self) -> Result<Vec<FirmwareRelease>, sqlx::Error> {
+        sqlx::query_as!(
+            FirmwareRelease,
+            r#"select id, version, sha256, size, created from firmware_release order by created desc"#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn fetch_release(&self, id: i64) -> Result<FirmwareRelease, sqlx::Error> {
+        sqlx::query_as!(
+            FirmwareRelease,
+            r#"select id, version, sha256, size, created from firmware_release where id = $1"#,
+            id
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Image of the release for the update holding the download token, `None`
+    /// once the token expired
+    pub async fn fetch_image(
+        &self,
+        id: i64,
+        update_id: i64,
+        token: &str,
+    ) -> Result<Option<Vec<u8>>, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"
+            select r.image from firmware_release r
+            join firmware_update fu on fu.release_id = r.id
+            where r.id = $1 and fu.id = $2 and fu.token_hash = $3 and fu.token_expires > now()
+            "#,
+            id,
+            update_id,
+            hash_token(token),
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Creates the update along with the token the device downloads the image
+    /// with, the device gets an hour to start the download
+    pub async fn create_update(
+        &self,
+        device_id: i64,
+        release_id: i64,
+        operator_id: i64,
+        token: &str,
+    ) -> Result<FirmwareUpdate, sqlx::Error> {
+        sqlx::query_as!(
+            FirmwareUpdate,
+            r#"
+            with fu as (
+                insert into firmware_update (device_id, release_id, operator_id, token_hash, token_expires)
+                values ($1, $2, $3, $4, now() + interval '1 hour')
+                returning *
+            ) select fu.id, fu.device_id, fu.release_id, r.version, fu.operator_id,
+                fu.state, fu.progress, fu.error, fu.created, fu.updated
+            from fu join firmware_release r on r.id = fu.release_id
+            "#,
+            device_id,
+            release_id,
+            operator_id,
+            hash_token(token),
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn fetch_updates(&self, device_id: i64) -> Result<Vec<FirmwareUpdate>, sqlx::Error> {
+        sqlx::query_as!(
+            FirmwareUpdate,
+            r#"
+            select fu.id, fu.device_id, fu.release_id, r.version, fu.operator_id,
+                fu.state, fu.progress, fu.error, fu.created, fu.updated
+            from firmware_update fu join firmware_release r on r.id = fu.release_id
+            where fu.device_id = $1
+            order by fu.created desc
+            "#,
+            device_id,
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Stores the progress reported by the device running the update
+    pub async fn update_status(
+        &self,
+        id: i64,
+        net_id: &str,
+        state: &UpdateState,
+    ) -> Result<u64, sqlx::Error> {
+        let (state, progress, error) = status_columns(state);
+        let result = sqlx::query!(
+            r#"
+            update firmware_update fu
+            set state = $3, progress = coalesce($4, fu.progress), error = $5, updated = current_timestamp
+            from device d
+            where fu.device_id = d.id and d.net_id = $2 and fu.id = $1
+            "#,
+            id,
+            net_id,
+            state,
+            progress,
+            error,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+}
@@ -1,4 +1,10 @@
+pub mod alert;
 pub mod customer;
 pub mod device;
 pub mod entry_log;
+pub mod firmware;
+pub mod operator;
+pub mod schedule;
+pub mod site_key;
 pub mod staff;
+pub mod user_action;
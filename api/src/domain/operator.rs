@@ -0,0 +1,62 @@
+use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+const TOKEN_LENGTH: usize = 40;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Operator {
+    pub id: i64,
+    pub name: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub active: bool,
+    pub created: DateTime<Utc>,
+}
+
+/// Tokens are only stored hashed
+pub(crate) fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+pub(crate) fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct OperatorRepository {
+    pub pool: PgPool,
+}
+
+impl OperatorRepository {
+    /// Creates a new operator returning the plain token, only its hash is stored
+    pub async fn create(&self, name: &str) -> Result<(Operator, String), sqlx::Error> {
+        let token = generate_token();
+        let operator = sqlx::query_as!(
+            Operator,
+            r#"insert into operator (name, token_hash) values ($1, $2) returning *"#,
+            name,
+            hash_token(&token),
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok((operator, token))
+    }
+
+    pub async fn fetch_by_token(&self, token: &str) -> Result<Option<Operator>, sqlx::Error> {
+        sqlx::query_as!(
+            Operator,
+            r#"select * from operator where token_hash = $1 and active is true"#,
+            hash_token(token),
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+}
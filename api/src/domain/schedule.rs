@@ -0,0 +1,235 @@
+use chrono::{DateTime, NaiveTime, Timelike, Utc};
+use doorsys_protocol::TimeWindow;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleWindow {
+    /// Bit mask of weekdays, bit 0 is Sunday
+    pub weekdays: i16,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Schedule {
+    pub id: i64,
+    pub name: String,
+    pub timezone: String,
+    pub created: DateTime<Utc>,
+    pub windows: Vec<ScheduleWindow>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewSchedule {
+    pub name: String,
+    /// POSIX TZ string, e.g. `EST5EDT,M3.2.0,M11.1.0`
+    pub timezone: String,
+    pub windows: Vec<ScheduleWindow>,
+}
+
+struct ScheduleRow {
+    id: i64,
+    name: String,
+    timezone: String,
+    created: DateTime<Utc>,
+}
+
+struct WindowRow {
+    schedule_id: i64,
+    weekdays: i16,
+    start_time: NaiveTime,
+    end_time: NaiveTime,
+}
+
+fn minute_of_day(time: &NaiveTime) -> u16 {
+    (time.hour() * 60 + time.minute()) as u16
+}
+
+impl Schedule {
+    fn from_rows(row: ScheduleRow, windows: &[WindowRow]) -> Self {
+        let windows = windows
+            .iter()
+            .filter(|w| w.schedule_id == row.id)
+            .map(|w| ScheduleWindow {
+                weekdays: w.weekdays,
+                start_time: w.start_time,
+                end_time: w.end_time,
+            })
+            .collect();
+        Schedule {
+            id: row.id,
+            name: row.name,
+            timezone: row.timezone,
+            created: row.created,
+            windows,
+        }
+    }
+
+    /// Converts into the form evaluated by the doors
+    pub fn to_protocol(&self) -> doorsys_protocol::Schedule {
+        doorsys_protocol::Schedule {
+            id: self.id as u32,
+            timezone: self.timezone.clone(),
+            windows: self
+                .windows
+                .iter()
+                .map(|w| TimeWindow {
+                    weekdays: w.weekdays as u8,
+                    start: minute_of_day(&w.start_time),
+                    end: minute_of_day(&w.end_time),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl NewSchedule {
+    /// Describes the first problem found in the schedule, if any
+    pub fn validate(&self) -> Option<&'static str> {
+        if self.timezone.trim().is_empty() {
+            return Some("timezone is required");
+        }
+        if self
+            .windows
+            .iter()
+            .any(|w| !(1..=0x7f).contains(&w.weekdays))
+        {
+            return Some("weekdays must be a bit mask between 1 and 127");
+        }
+        None
+    }
+}
+
+#[derive(Clone)]
+pub struct ScheduleRepository {
+    pub pool: PgPool,
+}
+
+impl ScheduleRepository {
+    pub async fn fetch_all(&self) -> Result<Vec<Schedule>, sqlx::Error> {
+        let rows = sqlx::query_as!(
+            ScheduleRow,
+            r#"select id, name, timezone, created from schedule order by name"#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let windows = sqlx::query_as!(
+            WindowRow,
+            r#"
+            select schedule_id, weekdays, start_time, end_time from schedule_window
+            order by schedule_id, id
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| Schedule::from_rows(row, &windows))
+            .collect())
+    }
+
+    pub async fn fetch_one(&self, id: i64) -> Result<Schedule, sqlx::Error> {
+        let row = sqlx::query_as!(
+            ScheduleRow,
+            r#"select id, name, timezone, created from schedule where id = $1"#,
+            id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        let windows = sqlx::query_as!(
+            WindowRow,
+            r#"
+            select schedule_id, weekdays, start_time, end_time from schedule_window
+            where schedule_id = $1 order by id
+            "#,
+            id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(Schedule::from_rows(row, &windows))
+    }
+
+    pub async fn create(&self, new_schedule: &NewSchedule) -> Result<Schedule, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let id = sqlx::query_scalar!(
+            r#"insert into schedule (name, timezone) values ($1, $2) returning id"#,
+            new_schedule.name,
+            new_schedule.timezone,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        Self::insert_windows(&mut tx, id, &new_schedule.windows).await?;
+        tx.commit().await?;
+        self.fetch_one(id).await
+    }
+
+    pub async fn update(
+        &self,
+        id: i64,
+        new_schedule: &NewSchedule,
+    ) -> Result<Schedule, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            r#"update schedule set name = $1, timezone = $2 where id = $3 returning id"#,
+            new_schedule.name,
+            new_schedule.timezone,
+            id,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query!(r#"delete from schedule_window where schedule_id = $1"#, id)
+            .execute(&mut *tx)
+            .await?;
+        Self::insert_windows(&mut tx, id, &new_schedule.windows).await?;
+        tx.commit().await?;
+        self.fetch_one(id).await
+    }
+
+    pub async fn delete(&self, id: i64) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(r#"delete from schedule where id = $1"#, id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Checks if any customer or staff member is bound to the schedule
+    pub async fn in_use(&self, id: i64) -> Result<bool, sqlx::Error> {
+        let in_use = sqlx::query_scalar!(
+            r#"
+            select exists(select 1 from customer where schedule_id = $1)
+                or exists(select 1 from staff where schedule_id = $1) as "in_use!"
+            "#,
+            id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(in_use)
+    }
+
+    async fn insert_windows(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        schedule_id: i64,
+        windows: &[ScheduleWindow],
+    ) -> Result<(), sqlx::Error> {
+        let weekdays: Vec<i16> = windows.iter().map(|w| w.weekdays).collect();
+        let start_times: Vec<NaiveTime> = windows.iter().map(|w| w.start_time).collect();
+        let end_times: Vec<NaiveTime> = windows.iter().map(|w| w.end_time).collect();
+        sqlx::query!(
+            r#"
+            insert into schedule_window (schedule_id, weekdays, start_time, end_time)
+            select $1, * from unnest($2::smallint[], $3::time[], $4::time[])
+            "#,
+            schedule_id,
+            &weekdays,
+            &start_times,
+            &end_times,
+        )
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+use anyhow::Context;
+use doorsys_protocol::SiteKey;
+use rand::Rng;
+use sqlx::PgPool;
+
+struct SiteKeyRow {
+    salt: Vec<u8>,
+    key: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct SiteKeyRepository {
+    pub pool: PgPool,
+}
+
+impl SiteKeyRepository {
+    /// Key the codes sent to the doors are hashed with, generated on first use.
+    ///
+    /// Changing it invalidates every code on the doors until they are synced.
+    pub async fn fetch_or_create(&self) -> anyhow::Result<SiteKey> {
+        let row = sqlx::query_as!(SiteKeyRow, r#"select salt, key from site_key"#)
+            .fetch_optional(&self.pool)
+            .await?;
+        let row = match row {
+            Some(row) => row,
+            None => self.create().await?,
+        };
+        Ok(SiteKey {
+            salt: row.salt.try_into().ok().context("invalid site salt")?,
+            key: row.key.try_into().ok().context("invalid site key")?,
+        })
+    }
+
+    /// Stores a new random key, a key created concurrently wins
+    async fn create(&self) -> Result<SiteKeyRow, sqlx::Error> {
+        let (salt, key): ([u8; 16], [u8; 32]) = rand::thread_rng().gen();
+        sqlx::query!(
+            r#"insert into site_key (salt, key) values ($1, $2) on conflict (id) do nothing"#,
+            &salt[..],
+            &key[..],
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query_as!(SiteKeyRow, r#"select salt, key from site_key"#)
+            .fetch_one(&self.pool)
+            .await
+    }
+}
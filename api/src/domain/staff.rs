@@ -1,10 +1,22 @@
-use chrono::{DateTime, Utc};
-use doorsys_protocol::UserAction;
+use std::{slice, time::SystemTime};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use doorsys_protocol::{
+    CodeHash, Credential, Digest, SiteKey, UserAction, UserUpdate, Validity, DEVICE_CAPACITY,
+};
 use rumqttc::{AsyncClient, QoS};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 
-use crate::mqtt;
+use super::{
+    device::{Device, DeviceRepository},
+    schedule::ScheduleRepository,
+    site_key::SiteKeyRepository,
+    user_action::UserActionRepository,
+};
+
+/// Time given to in flight actions before a diverging digest triggers a resync
+const RECONCILE_GRACE: TimeDelta = TimeDelta::minutes(2);
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -14,9 +26,68 @@ pub struct Staff {
     pub name: String,
     pub phone: String,
     pub pin: i32,
+    /// Card number printed on the fob
     pub fob: Option<i32>,
     pub active: bool,
     pub created: DateTime<Utc>,
+    /// Overrides the customer schedule when set
+    pub schedule_id: Option<i64>,
+    pub facility_code: Option<i32>,
+    /// The fob only opens doors followed by the pin
+    pub card_and_pin: bool,
+    /// Opens like the pin and raises a silent alarm
+    pub duress_pin: Option<i32>,
+    /// Visitor codes are refused before this time
+    pub valid_from: Option<DateTime<Utc>>,
+    /// Visitor codes are refused from this time on
+    pub valid_until: Option<DateTime<Utc>>,
+    /// Entries a visitor has left
+    pub uses_left: Option<i32>,
+}
+
+/// Time limits and uses of a visitor code as sent to the devices
+fn validity(
+    valid_from: Option<DateTime<Utc>>,
+    valid_until: Option<DateTime<Utc>>,
+    uses_left: Option<i32>,
+) -> Option<Validity> {
+    if valid_from.is_none() && valid_until.is_none() && uses_left.is_none() {
+        return None;
+    }
+    Some(Validity {
+        start: valid_from.map(SystemTime::from),
+        end: valid_until.map(SystemTime::from),
+        uses: uses_left.map(|uses| uses as u32),
+    })
+}
+
+impl Staff {
+    /// The fob as a device credential
+    fn card(&self) -> Option<(i32, u32)> {
+        Some((self.fob?, self.facility_code? as u32))
+    }
+
+    pub fn validity(&self) -> Option<Validity> {
+        validity(self.valid_from, self.valid_until, self.uses_left)
+    }
+
+    /// The pin and card the devices store, hashed with the key
+    fn codes(&self, key: &SiteKey) -> Vec<CodeHash> {
+        let mut codes = vec![key.hash(self.pin, None)];
+        if let Some((code, facility)) = self.card() {
+            codes.push(key.hash(code, Some(facility)));
+        }
+        codes
+    }
+
+    /// Whether the codes still open the doors, visitors expire with their
+    /// time limit or uses
+    fn admitted(&self) -> bool {
+        self.active
+            && !self
+                .validity()
+                .is_some_and(|v| v.expired(SystemTime::now()))
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -26,6 +97,52 @@ pub struct NewStaff {
     pub name: String,
     pub phone: String,
     pub fob: Option<i32>,
+    pub facility_code: Option<i32>,
+    pub schedule_id: Option<i64>,
+    #[serde(default)]
+    pub card_and_pin: bool,
+    /// Makes a visitor code, refused before this time
+    pub valid_from: Option<DateTime<Utc>>,
+    /// Makes a visitor code, refused from this time on
+    pub valid_until: Option<DateTime<Utc>>,
+    /// Makes a visitor code that opens the doors this many times. Only read on
+    /// creation, updates leave the uses counted since to [`StaffService::reset_uses`].
+    pub uses: Option<i32>,
+}
+
+impl NewStaff {
+    pub fn validate(&self) -> Option<&'static str> {
+        if self.fob.is_some() != self.facility_code.is_some() {
+            return Some("fob and facility code must be set together");
+        }
+        if self.card_and_pin && self.fob.is_none() {
+            return Some("card and pin requires a fob");
+        }
+        if let (Some(from), Some(until)) = (self.valid_from, self.valid_until) {
+            if until <= from {
+                return Some("valid until must be after valid from");
+            }
+        }
+        validate_uses(self.uses)
+    }
+}
+
+pub fn validate_uses(uses: Option<i32>) -> Option<&'static str> {
+    if uses.is_some_and(|uses| uses < 0) {
+        return Some("uses can't be negative");
+    }
+    None
+}
+
+struct DeviceCredential {
+    code: i32,
+    facility_code: Option<i32>,
+    schedule_id: Option<i64>,
+    pin: Option<i32>,
+    duress: Option<i32>,
+    valid_from: Option<DateTime<Utc>>,
+    valid_until: Option<DateTime<Utc>>,
+    uses_left: Option<i32>,
 }
 
 #[derive(Clone)]
@@ -37,12 +154,22 @@ impl StaffRepository {
     pub async fn create(&self, new_staff: &NewStaff, pin: i32) -> Result<Staff, sqlx::Error> {
         sqlx::query_as!(
             Staff,
-            r#"insert into staff (customer_id, name, phone, pin, fob) values ($1, $2, $3, $4, $5) returning *"#,
+            r#"
+            insert into staff (customer_id, name, phone, pin, fob, facility_code, schedule_id, card_and_pin, valid_from, valid_until, uses_left)
+            values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            returning *
+            "#,
             new_staff.customer_id,
             new_staff.name,
             new_staff.phone,
             pin,
             new_staff.fob,
+            new_staff.facility_code,
+            new_staff.schedule_id,
+            new_staff.card_and_pin,
+            new_staff.valid_from,
+            new_staff.valid_until,
+            new_staff.uses,
         )
         .fetch_one(&self.pool)
         .await
@@ -51,10 +178,21 @@ impl StaffRepository {
     pub async fn update(&self, id: i64, update_staff: &NewStaff) -> Result<Staff, sqlx::Error> {
         sqlx::query_as!(
             Staff,
-            r#"update staff set name = $1, phone = $2, fob = $3 where id = $4 returning *"#,
+            r#"
+            update staff set name = $1, phone = $2, fob = $3, facility_code = $4, schedule_id = $5, card_and_pin = $6,
+                valid_from = $7, valid_until = $8, customer_id = $9
+            where id = $10
+            returning *
+            "#,
             update_staff.name,
             update_staff.phone,
             update_staff.fob,
+            update_staff.facility_code,
+            update_staff.schedule_id,
+            update_staff.card_and_pin,
+            update_staff.valid_from,
+            update_staff.valid_until,
+            update_staff.customer_id,
             id,
         )
         .fetch_one(&self.pool)
@@ -72,6 +210,55 @@ impl StaffRepository {
         .await
     }
 
+    pub async fn update_duress_pin(
+        &self,
+        id: i64,
+        duress_pin: Option<i32>,
+    ) -> Result<Staff, sqlx::Error> {
+        sqlx::query_as!(
+            Staff,
+            r#"update staff set duress_pin = $1 where id = $2 returning *"#,
+            duress_pin,
+            id,
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Whether any staff member has the pin as their pin or duress pin
+    pub async fn pin_in_use(&self, pin: i32) -> Result<bool, sqlx::Error> {
+        let used = sqlx::query_scalar!(
+            r#"select exists (select 1 from staff where pin = $1 or duress_pin = $1) as "used!""#,
+            pin,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(used)
+    }
+
+    /// Counts an entry against a visitor's uses, `None` for codes without a
+    /// use limit or already out of uses
+    pub async fn count_use(&self, id: i64) -> Result<Option<Staff>, sqlx::Error> {
+        sqlx::query_as!(
+            Staff,
+            r#"update staff set uses_left = uses_left - 1 where id = $1 and uses_left > 0 returning *"#,
+            id,
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    pub async fn update_uses(&self, id: i64, uses: Option<i32>) -> Result<Staff, sqlx::Error> {
+        sqlx::query_as!(
+            Staff,
+            r#"update staff set uses_left = $1 where id = $2 returning *"#,
+            uses,
+            id,
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
     pub async fn update_status(&self, id: i64, active: bool) -> Result<Staff, sqlx::Error> {
         sqlx::query_as!(
             Staff,
@@ -114,15 +301,78 @@ impl StaffRepository {
             .await
     }
 
-    pub async fn fetch_all_codes(&self) -> Result<Vec<Option<i32>>, sqlx::Error> {
+    /// Whether a card with the facility code opens the device, either allowed
+    /// on it or held by staff with access to it
+    pub async fn uses_facility(&self, device_id: i64, facility: i32) -> Result<bool, sqlx::Error> {
+        let used = sqlx::query_scalar!(
+            r#"
+            select exists (
+                select 1 from device d where d.id = $1 and $2 = any(d.facility_codes)
+            ) or exists (
+                select 1 from staff s
+                join customer_device cd on cd.customer_id = s.customer_id
+                where cd.device_id = $1 and s.fob is not null and s.facility_code = $2
+            ) as "used!"
+            "#,
+            device_id,
+            facility,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(used)
+    }
+
+    /// Schedule that applies to the staff member, their own or the customer's
+    pub async fn fetch_effective_schedule(&self, id: i64) -> Result<Option<i64>, sqlx::Error> {
         sqlx::query_scalar!(
             r#"
-            with all_codes(code, active) as (
-                select pin, active from staff 
-                union 
-                select fob, active from staff
-            ) select code from all_codes where code is not null and active is true order by code
+            select coalesce(s.schedule_id, c.schedule_id) from staff s
+            join customer c on c.id = s.customer_id
+            where s.id = $1
             "#,
+            id
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Active codes of the staff whose customer has access to the device,
+    /// along with the schedule that applies to each of them.
+    ///
+    /// Cards that need the pin carry it and their pin isn't accepted alone,
+    /// the duress pin goes along with the pin. Expired visitors are left out.
+    async fn fetch_device_credentials(
+        &self,
+        device_id: i64,
+    ) -> Result<Vec<DeviceCredential>, sqlx::Error> {
+        sqlx::query_as!(
+            DeviceCredential,
+            r#"
+            with device_staff as (
+                select s.pin, s.duress_pin, s.fob, s.facility_code, s.active, coalesce(s.schedule_id, c.schedule_id) as schedule_id,
+                    s.card_and_pin or d.card_and_pin as card_and_pin, s.valid_from, s.valid_until, s.uses_left
+                from staff s
+                join customer c on c.id = s.customer_id
+                join customer_device cd on cd.customer_id = s.customer_id
+                join device d on d.id = cd.device_id
+                where cd.device_id = $1
+                    and (s.valid_until is null or s.valid_until > now())
+                    and (s.uses_left is null or s.uses_left > 0)
+            ), all_codes(code, facility_code, schedule_id, pin, duress, active, valid_from, valid_until, uses_left) as (
+                select pin, null::int, schedule_id, null::int, duress_pin, active, valid_from, valid_until, uses_left
+                from device_staff
+                where not card_and_pin
+                union
+                select fob, facility_code, schedule_id, case when card_and_pin then pin end,
+                    case when card_and_pin then duress_pin end, active, valid_from, valid_until, uses_left
+                from device_staff
+            ) select distinct on (facility_code, code) code as "code!", facility_code, schedule_id, pin, duress,
+                valid_from, valid_until, uses_left
+            from all_codes
+            where code is not null and active is true
+            order by facility_code nulls first, code, schedule_id nulls first
+            "#,
+            device_id,
         )
         .fetch_all(&self.pool)
         .await
@@ -132,7 +382,84 @@ impl StaffRepository {
 #[derive(Clone)]
 pub struct StaffService {
     pub staff_repo: StaffRepository,
+    pub device_repo: DeviceRepository,
+    pub schedule_repo: ScheduleRepository,
+    pub user_action_repo: UserActionRepository,
+    pub site_key_repo: SiteKeyRepository,
     pub mqtt_client: AsyncClient,
+    /// Whether the broker connection is encrypted, the site key is only sent
+    /// over one that is
+    pub mqtt_tls: bool,
+}
+
+fn action_name(action: &UserAction) -> &'static str {
+    match action {
+        UserAction::Put(_) => "put",
+        UserAction::Del(_) => "del",
+        UserAction::Replace { .. } => "replace",
+        UserAction::SetSchedule(_) => "schedule",
+        UserAction::DelSchedule(_) => "del_schedule",
+        UserAction::Sync { .. } => "sync",
+    }
+}
+
+/// Actions that bring a device in line with the staff member's codes, hashed
+/// with the site key.
+///
+/// With `card_and_pin` the pin is bound to the card and removed as a code of its own.
+fn credential_actions(
+    staff: &Staff,
+    schedule: Option<u32>,
+    card_and_pin: bool,
+    key: &SiteKey,
+) -> Vec<UserAction> {
+    let admitted = staff.admitted();
+    let pin = key.hash(staff.pin, None);
+    let duress = staff.duress_pin.map(|duress| key.hash(duress, None));
+    let mut actions = vec![match admitted && !card_and_pin {
+        true => UserAction::Put(Credential {
+            code: pin,
+            card: false,
+            schedule,
+            pin: None,
+            duress,
+            validity: staff.validity(),
+        }),
+        false => UserAction::Del(pin),
+    }];
+    if let Some((code, facility)) = staff.card() {
+        let code = key.hash(code, Some(facility));
+        actions.push(match admitted {
+            true => UserAction::Put(Credential {
+                code,
+                card: true,
+                schedule,
+                pin: card_and_pin.then_some(pin),
+                duress: duress.filter(|_| card_and_pin),
+                validity: staff.validity(),
+            }),
+            false => UserAction::Del(code),
+        });
+    }
+    actions
+}
+
+/// Hashes the codes a device holds with the key, sorted by their hash as the
+/// digest expects
+fn hash_credentials(credentials: Vec<DeviceCredential>, key: &SiteKey) -> Vec<Credential> {
+    let mut credentials: Vec<_> = credentials
+        .into_iter()
+        .map(|c| Credential {
+            code: key.hash(c.code, c.facility_code.map(|f| f as u32)),
+            card: c.facility_code.is_some(),
+            schedule: c.schedule_id.map(|id| id as u32),
+            pin: c.pin.map(|pin| key.hash(pin, None)),
+            duress: c.duress.map(|pin| key.hash(pin, None)),
+            validity: validity(c.valid_from, c.valid_until, c.uses_left),
+        })
+        .collect();
+    credentials.sort_by_key(|c| c.code);
+    credentials
 }
 
 impl StaffService {
@@ -147,33 +474,569 @@ impl StaffService {
         Ok(())
     }
 
+    /// Counts a granted entry against the visitor's uses, removing the codes
+    /// from the devices once they run out.
+    ///
+    /// Each device counts its own entries down, pushing the count back would
+    /// undo the entries made on a device while its audits were in flight.
+    pub async fn count_use(&self, id: i64) -> anyhow::Result<()> {
+        let Some(staff) = self.staff_repo.count_use(id).await? else {
+            return Ok(());
+        };
+        tracing::info!("Visitor {} has {:?} uses left", staff.id, staff.uses_left);
+        if staff.uses_left == Some(0) {
+            self.send_mqtt_message(&staff).await?;
+        }
+        Ok(())
+    }
+
     pub async fn update_status(&self, id: i64, active: bool) -> anyhow::Result<Staff> {
         let staff = self.staff_repo.update_status(id, active).await?;
         self.send_mqtt_message(&staff).await?;
         Ok(staff)
     }
 
+    /// Sets the entries a visitor has left, the operator's count replaces the
+    /// one on the doors. Doors keep the lower of their count and the one sent,
+    /// so the codes are removed before they are sent again.
+    pub async fn reset_uses(&self, id: i64, uses: Option<i32>) -> anyhow::Result<Staff> {
+        let staff = self.staff_repo.update_uses(id, uses).await?;
+        let key = self.site_key_repo.fetch_or_create().await?;
+        for code in staff.codes(&key) {
+            self.publish(&staff, UserAction::Del(code)).await?;
+        }
+        self.send_mqtt_message(&staff).await?;
+        Ok(staff)
+    }
+
     pub async fn send_mqtt_message(&self, staff: &Staff) -> anyhow::Result<()> {
-        let pin_action = match staff.active {
-            true => UserAction::Add(staff.pin),
-            false => UserAction::Del(staff.pin),
+        let schedule = self
+            .staff_repo
+            .fetch_effective_schedule(staff.id)
+            .await?
+            .map(|id| id as u32);
+        let key = self.site_key_repo.fetch_or_create().await?;
+        let (paired, single) = self.devices_by_mode(staff).await?;
+        for (devices, card_and_pin) in [(single, false), (paired, true)] {
+            for action in credential_actions(staff, schedule, card_and_pin, &key) {
+                self.publish_to(&devices, action).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the pin on every device, doors pairing it with the card get
+    /// the card again.
+    ///
+    /// Doors keep the grant of the old pin, counted down uses included, and the
+    /// credentials follow so a door missing the old pin still gets the new one.
+    /// Staff no longer admitted only get their codes removed.
+    pub async fn replace_pin(&self, staff: &Staff, old_pin: i32) -> anyhow::Result<()> {
+        let key = self.site_key_repo.fetch_or_create().await?;
+        let (paired, single) = self.devices_by_mode(staff).await?;
+        let schedule = self
+            .staff_repo
+            .fetch_effective_schedule(staff.id)
+            .await?
+            .map(|id| id as u32);
+        let old = key.hash(old_pin, None);
+        let replace_pin = match staff.admitted() {
+            true => UserAction::Replace {
+                old,
+                new: key.hash(staff.pin, None),
+            },
+            false => UserAction::Del(old),
         };
+        self.publish_to(&single, replace_pin).await?;
+        for (devices, card_and_pin) in [(&single, false), (&paired, true)] {
+            for action in credential_actions(staff, schedule, card_and_pin, &key) {
+                self.publish_to(devices, action).await?;
+            }
+        }
+        Ok(())
+    }
 
-        let payload = bincode::encode_to_vec(pin_action, mqtt::BINCODE_CONFIG)?;
-        self.mqtt_client
-            .publish("doorsys/user", QoS::AtLeastOnce, false, payload)
+    /// Devices the staff member has access to, split between the ones that
+    /// need their card followed by the pin and the ones that don't
+    async fn devices_by_mode(&self, staff: &Staff) -> anyhow::Result<(Vec<Device>, Vec<Device>)> {
+        let devices = self
+            .device_repo
+            .fetch_by_customer(staff.customer_id)
             .await?;
+        Ok(devices
+            .into_iter()
+            .partition(|d| staff.card_and_pin || d.card_and_pin))
+    }
+
+    /// Removes the card the staff member held before an update from the doors
+    /// they had access to
+    pub async fn revoke_card(&self, old_staff: &Staff, staff: &Staff) -> anyhow::Result<()> {
+        match old_staff.card() {
+            Some((code, facility)) if old_staff.card() != staff.card() => {
+                let key = self.site_key_repo.fetch_or_create().await?;
+                let action = UserAction::Del(key.hash(code, Some(facility)));
+                self.publish(old_staff, action).await
+            }
+            _ => Ok(()),
+        }
+    }
 
-        if let Some(fob) = staff.fob {
-            let fob_action = match staff.active {
-                true => UserAction::Add(fob),
-                false => UserAction::Del(fob),
+    /// Removes the codes of a staff member moved to another customer from the
+    /// doors only the previous customer has access to
+    pub async fn revoke_customer(&self, old_staff: &Staff, staff: &Staff) -> anyhow::Result<()> {
+        if old_staff.customer_id == staff.customer_id {
+            return Ok(());
+        }
+        let kept = self
+            .device_repo
+            .fetch_by_customer(staff.customer_id)
+            .await?;
+        let devices: Vec<Device> = self
+            .device_repo
+            .fetch_by_customer(old_staff.customer_id)
+            .await?
+            .into_iter()
+            .filter(|d| kept.iter().all(|k| k.id != d.id))
+            .collect();
+        let key = self.site_key_repo.fetch_or_create().await?;
+        for code in old_staff.codes(&key) {
+            self.publish_to(&devices, UserAction::Del(code)).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether the device can be sent a sync of the credentials. Devices refuse
+    /// more codes than they hold, and the key only goes over an encrypted broker
+    fn can_sync(&self, device: &Device, credentials: &[Credential]) -> bool {
+        if !self.mqtt_tls {
+            tracing::error!(
+                "Not sending the site key to {} over an unencrypted broker",
+                device.net_id
+            );
+            return false;
+        }
+        if credentials.len() > DEVICE_CAPACITY {
+            tracing::error!(
+                "Device {} can't hold {} codes, capacity is {}",
+                device.net_id,
+                credentials.len(),
+                DEVICE_CAPACITY
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Sends each device the full set of credentials and schedules it should
+    /// hold, along with the site key
+    pub async fn bulk_load(&self, devices: &[Device]) -> anyhow::Result<()> {
+        let key = self.site_key_repo.fetch_or_create().await?;
+        let schedules = self.schedules().await?;
+        for device in devices {
+            let credentials = self.device_credentials(device, &key).await?;
+            if !self.can_sync(device, &credentials) {
+                continue;
+            }
+            tracing::info!("Syncing {} codes on {}", credentials.len(), device.net_id);
+            let action = UserAction::Sync {
+                key,
+                credentials,
+                schedules: schedules.clone(),
             };
-            let payload = bincode::encode_to_vec(fob_action, mqtt::BINCODE_CONFIG)?;
+            self.publish_to(slice::from_ref(device), action).await?;
+        }
+        Ok(())
+    }
+
+    /// Pushes the full credential set when a device digest disagrees with the database.
+    ///
+    /// The digest can't tell which codes differ, so the device gets a full sync.
+    /// Devices with recent unacknowledged actions are left alone until those land,
+    /// and devices that can't take a sync are flagged instead of resent one.
+    pub async fn reconcile(&self, device: &Device, digest: &Digest) -> anyhow::Result<bool> {
+        let key = self.site_key_repo.fetch_or_create().await?;
+        let credentials = self.device_credentials(device, &key).await?;
+        let expected = Digest::from_credentials(credentials.iter().copied());
+        if expected == *digest {
+            return Ok(false);
+        }
+
+        let since = Utc::now() - RECONCILE_GRACE;
+        if self
+            .user_action_repo
+            .has_pending_since(device.id, &since)
+            .await?
+        {
+            tracing::info!("Device {} has actions in flight, skipping", device.net_id);
+            return Ok(false);
+        }
+        if !self.can_sync(device, &credentials) {
+            return Ok(false);
+        }
+
+        tracing::warn!(
+            "Device {} out of sync, expected {:?} found {:?}",
+            device.net_id,
+            expected,
+            digest
+        );
+        let action = UserAction::Sync {
+            key,
+            credentials,
+            schedules: self.schedules().await?,
+        };
+        self.publish_to(slice::from_ref(device), action).await?;
+        Ok(true)
+    }
+
+    /// Publishes a new or changed schedule to every device
+    pub async fn push_schedule(&self, schedule: doorsys_protocol::Schedule) -> anyhow::Result<()> {
+        let devices = self.device_repo.fetch_all().await?;
+        self.publish_to(&devices, UserAction::SetSchedule(schedule))
+            .await
+    }
+
+    /// Removes a deleted schedule from every device
+    pub async fn remove_schedule(&self, id: i64) -> anyhow::Result<()> {
+        let devices = self.device_repo.fetch_all().await?;
+        self.publish_to(&devices, UserAction::DelSchedule(id as u32))
+            .await
+    }
+
+    /// Credentials of the device hashed with the key, sorted by their hash as
+    /// the digest expects
+    async fn device_credentials(
+        &self,
+        device: &Device,
+        key: &SiteKey,
+    ) -> Result<Vec<Credential>, sqlx::Error> {
+        let credentials = self.staff_repo.fetch_device_credentials(device.id).await?;
+        Ok(hash_credentials(credentials, key))
+    }
+
+    async fn schedules(&self) -> Result<Vec<doorsys_protocol::Schedule>, sqlx::Error> {
+        let schedules = self.schedule_repo.fetch_all().await?;
+        Ok(schedules.iter().map(|s| s.to_protocol()).collect())
+    }
+
+    /// Publishes the action to the devices the staff member has access to
+    pub async fn publish(&self, staff: &Staff, action: UserAction) -> anyhow::Result<()> {
+        let devices = self
+            .device_repo
+            .fetch_by_customer(staff.customer_id)
+            .await?;
+        self.publish_to(&devices, action).await
+    }
+
+    /// Records the action for delivery tracking and publishes it to each device
+    pub async fn publish_to(&self, devices: &[Device], action: UserAction) -> anyhow::Result<()> {
+        if devices.is_empty() {
+            tracing::debug!("No devices to receive {:?}", action_name(&action));
+            return Ok(());
+        }
+        let device_ids: Vec<i64> = devices.iter().map(|d| d.id).collect();
+        let seq = self
+            .user_action_repo
+            .create(action_name(&action), &device_ids)
+            .await?;
+        let update = UserUpdate {
+            seq: seq as u64,
+            action,
+        };
+        let payload = doorsys_protocol::encode(&update)?;
+        for device in devices {
             self.mqtt_client
-                .publish("doorsys/user", QoS::AtLeastOnce, false, payload)
+                .publish(
+                    format!("doorsys/user/{}", device.net_id),
+                    QoS::AtLeastOnce,
+                    false,
+                    payload.clone(),
+                )
                 .await?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: SiteKey = SiteKey {
+        salt: [1; 16],
+        key: [2; 32],
+    };
+
+    fn new_staff() -> NewStaff {
+        NewStaff {
+            customer_id: 1,
+            name: String::from("Alice"),
+            phone: String::from("555-0100"),
+            fob: None,
+            facility_code: None,
+            schedule_id: None,
+            card_and_pin: false,
+            valid_from: None,
+            valid_until: None,
+            uses: None,
+        }
+    }
+
+    fn staff() -> Staff {
+        Staff {
+            id: 1,
+            customer_id: 1,
+            name: String::from("Alice"),
+            phone: String::from("555-0100"),
+            pin: 111111,
+            fob: None,
+            active: true,
+            created: Utc::now(),
+            schedule_id: None,
+            facility_code: None,
+            card_and_pin: false,
+            duress_pin: None,
+            valid_from: None,
+            valid_until: None,
+            uses_left: None,
+        }
+    }
+
+    fn card_holder() -> Staff {
+        Staff {
+            fob: Some(1234),
+            facility_code: Some(12),
+            duress_pin: Some(222222),
+            ..staff()
+        }
+    }
+
+    fn device_credential(code: i32, facility_code: Option<i32>) -> DeviceCredential {
+        DeviceCredential {
+            code,
+            facility_code,
+            schedule_id: None,
+            pin: None,
+            duress: None,
+            valid_from: None,
+            valid_until: None,
+            uses_left: None,
+        }
+    }
+
+    #[test]
+    fn validate_accepts_plain_staff() {
+        assert_eq!(new_staff().validate(), None);
+    }
+
+    #[test]
+    fn validate_requires_fob_and_facility_together() {
+        let fob_only = NewStaff {
+            fob: Some(1234),
+            ..new_staff()
+        };
+        let facility_only = NewStaff {
+            facility_code: Some(12),
+            ..new_staff()
+        };
+        let both = NewStaff {
+            fob: Some(1234),
+            facility_code: Some(12),
+            ..new_staff()
+        };
+        assert!(fob_only.validate().is_some());
+        assert!(facility_only.validate().is_some());
+        assert_eq!(both.validate(), None);
+    }
+
+    #[test]
+    fn validate_requires_fob_for_card_and_pin() {
+        let staff = NewStaff {
+            card_and_pin: true,
+            ..new_staff()
+        };
+        assert!(staff.validate().is_some());
+    }
+
+    #[test]
+    fn validate_orders_validity() {
+        let from = Utc::now();
+        let staff = NewStaff {
+            valid_from: Some(from),
+            valid_until: Some(from),
+            ..new_staff()
+        };
+        assert!(staff.validate().is_some());
+        let staff = NewStaff {
+            valid_until: Some(from + TimeDelta::hours(1)),
+            ..staff
+        };
+        assert_eq!(staff.validate(), None);
+    }
+
+    #[test]
+    fn hashed_credentials_are_sorted_by_hash() {
+        let rows = vec![
+            device_credential(111111, None),
+            device_credential(222222, None),
+            device_credential(1234, Some(12)),
+        ];
+        let credentials = hash_credentials(rows, &KEY);
+        assert!(credentials.windows(2).all(|w| w[0].code < w[1].code));
+    }
+
+    #[test]
+    fn digest_ignores_row_order() {
+        let rows = || {
+            vec![
+                device_credential(111111, None),
+                device_credential(1234, Some(12)),
+            ]
+        };
+        let mut reversed = rows();
+        reversed.reverse();
+        let digest = |rows| Digest::from_credentials(hash_credentials(rows, &KEY));
+        assert_eq!(digest(rows()), digest(reversed));
+    }
+
+    #[test]
+    fn hashed_cards_keep_their_facility() {
+        let credentials = hash_credentials(vec![device_credential(1234, Some(12))], &KEY);
+        assert!(credentials[0].card);
+        assert_eq!(credentials[0].code, KEY.hash(1234, Some(12)));
+        assert_ne!(credentials[0].code, KEY.hash(1234, None));
+    }
+
+    #[test]
+    fn digest_follows_the_key() {
+        let other = SiteKey {
+            salt: [3; 16],
+            key: [4; 32],
+        };
+        let digest = |key| {
+            Digest::from_credentials(hash_credentials(vec![device_credential(111111, None)], key))
+        };
+        assert_ne!(digest(&KEY), digest(&other));
+    }
+
+    #[test]
+    fn pin_only_staff_puts_the_pin() {
+        let actions = credential_actions(&staff(), Some(3), false, &KEY);
+        assert_eq!(actions.len(), 1);
+        let UserAction::Put(pin) = &actions[0] else {
+            panic!("expected a put, got {:?}", actions[0]);
+        };
+        assert_eq!(pin.code, KEY.hash(111111, None));
+        assert!(!pin.card);
+        assert_eq!(pin.schedule, Some(3));
+    }
+
+    #[test]
+    fn card_holder_puts_pin_and_card() {
+        let actions = credential_actions(&card_holder(), None, false, &KEY);
+        let [UserAction::Put(pin), UserAction::Put(card)] = &actions[..] else {
+            panic!("expected two puts, got {:?}", actions);
+        };
+        assert_eq!(pin.duress, Some(KEY.hash(222222, None)));
+        assert_eq!(card.code, KEY.hash(1234, Some(12)));
+        assert!(card.card);
+        assert_eq!(card.pin, None);
+        assert_eq!(card.duress, None);
+    }
+
+    #[test]
+    fn card_and_pin_binds_the_pin_to_the_card() {
+        let actions = credential_actions(&card_holder(), None, true, &KEY);
+        let [UserAction::Del(pin), UserAction::Put(card)] = &actions[..] else {
+            panic!("expected a del and a put, got {:?}", actions);
+        };
+        assert_eq!(*pin, KEY.hash(111111, None));
+        assert_eq!(card.pin, Some(KEY.hash(111111, None)));
+        assert_eq!(card.duress, Some(KEY.hash(222222, None)));
+    }
+
+    #[test]
+    fn inactive_staff_deletes_every_code() {
+        let staff = Staff {
+            active: false,
+            ..card_holder()
+        };
+        let actions = credential_actions(&staff, None, false, &KEY);
+        let [UserAction::Del(pin), UserAction::Del(card)] = &actions[..] else {
+            panic!("expected two deletes, got {:?}", actions);
+        };
+        assert_eq!(*pin, KEY.hash(111111, None));
+        assert_eq!(*card, KEY.hash(1234, Some(12)));
+    }
+
+    #[test]
+    fn expired_visitor_deletes_every_code() {
+        let expired = Staff {
+            valid_until: Some(Utc::now() - TimeDelta::minutes(1)),
+            ..card_holder()
+        };
+        let used_up = Staff {
+            uses_left: Some(0),
+            ..card_holder()
+        };
+        for staff in [expired, used_up] {
+            assert!(!staff.admitted());
+            let actions = credential_actions(&staff, None, false, &KEY);
+            assert!(actions.iter().all(|a| matches!(a, UserAction::Del(_))));
+        }
+    }
+
+    #[test]
+    fn visitor_credentials_carry_their_limits() {
+        let until = Utc::now() + TimeDelta::hours(1);
+        let staff = Staff {
+            valid_until: Some(until),
+            uses_left: Some(3),
+            ..staff()
+        };
+        let actions = credential_actions(&staff, None, false, &KEY);
+        let [UserAction::Put(pin)] = &actions[..] else {
+            panic!("expected a put, got {:?}", actions);
+        };
+        let validity = pin.validity.expect("visitor limits");
+        assert_eq!(validity.start, None);
+        assert_eq!(validity.end, Some(SystemTime::from(until)));
+        assert_eq!(validity.uses, Some(3));
+    }
+
+    #[test]
+    fn staff_without_limits_has_no_validity() {
+        assert_eq!(staff().validity(), None);
+    }
+
+    #[test]
+    fn validate_refuses_negative_uses() {
+        let staff = NewStaff {
+            uses: Some(-1),
+            ..new_staff()
+        };
+        assert!(staff.validate().is_some());
+        assert_eq!(validate_uses(Some(0)), None);
+        assert_eq!(validate_uses(None), None);
+    }
+
+    #[test]
+    fn full_syncs_are_named_as_the_ack_settles_them() {
+        // UserActionRepository::ack settles older deliveries on a "sync" or "bulk" ack
+        let sync = UserAction::Sync {
+            key: KEY,
+            credentials: vec![],
+            schedules: vec![],
+        };
+        assert_eq!(action_name(&sync), "sync");
+        let put = UserAction::Put(Credential {
+            code: KEY.hash(111111, None),
+            card: false,
+            schedule: None,
+            pin: None,
+            duress: None,
+            validity: None,
+        });
+        assert_ne!(action_name(&put), "sync");
+    }
+}
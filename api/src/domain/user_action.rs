@@ -0,0 +1,118 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceSync {
+    pub device_id: i64,
+    pub device_name: String,
+    pub last_seq: Option<i64>,
+    pub last_acked_seq: Option<i64>,
+    pub pending: i64,
+    pub failed: i64,
+    pub last_ack: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone)]
+pub struct UserActionRepository {
+    pub pool: PgPool,
+}
+
+impl UserActionRepository {
+    /// Records a new action as pending on the devices and returns its sequence number
+    pub async fn create(&self, action: &str, device_ids: &[i64]) -> Result<i64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let seq = sqlx::query_scalar!(
+            r#"insert into user_action (action) values ($1) returning id"#,
+            action
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"insert into user_action_delivery (user_action_id, device_id) select $1, unnest($2::bigint[])"#,
+            seq,
+            device_ids,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(seq)
+    }
+
+    /// Stores the device acknowledgement for an action.
+    ///
+    /// A successful bulk load or sync replaces the whole code set, so it also settles
+    /// any older action still pending on that device.
+    pub async fn ack(
+        &self,
+        seq: i64,
+        net_id: &str,
+        success: bool,
+        error: Option<&str>,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            update user_action_delivery ud
+            set success = $3, error = $4, acked = current_timestamp
+            from device d
+            where ud.device_id = d.id and d.net_id = $2
+            and (
+                ud.user_action_id = $1
+                or ($3 and ud.acked is null and ud.user_action_id < $1
+                    and exists (select 1 from user_action where id = $1 and action in ('bulk', 'sync')))
+            )
+            "#,
+            seq,
+            net_id,
+            success,
+            error,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Checks if the device has unacknowledged actions issued after `since`
+    pub async fn has_pending_since(
+        &self,
+        device_id: i64,
+        since: &DateTime<Utc>,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"
+            select exists (
+                select 1 from user_action_delivery ud
+                join user_action a on a.id = ud.user_action_id
+                where ud.device_id = $1 and ud.acked is null and a.created > $2
+            ) as "exists!"
+            "#,
+            device_id,
+            since,
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn fetch_device_sync(&self) -> Result<Vec<DeviceSync>, sqlx::Error> {
+        sqlx::query_as!(
+            DeviceSync,
+            r#"
+            select
+                d.id as device_id,
+                d.name as device_name,
+                max(ud.user_action_id) as last_seq,
+                max(ud.user_action_id) filter (where ud.success) as last_acked_seq,
+                count(ud.user_action_id) filter (where ud.acked is null) as "pending!",
+                count(ud.user_action_id) filter (where ud.success is false) as "failed!",
+                max(ud.acked) as last_ack
+            from device d
+            left join user_action_delivery ud on ud.device_id = d.id
+            group by d.id
+            order by d.name
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+}
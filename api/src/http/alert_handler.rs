@@ -0,0 +1,25 @@
+use super::HttpResult;
+use crate::domain::{
+    alert::{Alert, AlertRepository},
+    operator::Operator,
+};
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+
+/// Alerts waiting for an operator
+pub async fn list(State(alert_repo): State<AlertRepository>) -> HttpResult<Json<Vec<Alert>>> {
+    let alert_list = alert_repo.fetch_open().await?;
+    Ok(Json(alert_list))
+}
+
+pub async fn acknowledge(
+    State(alert_repo): State<AlertRepository>,
+    operator: Operator,
+    Path(id): Path<i64>,
+) -> HttpResult<Json<Alert>> {
+    let alert = alert_repo.acknowledge(id, operator.id).await?;
+    tracing::info!("Alert {} acknowledged by {}", alert.id, operator.name);
+    Ok(Json(alert))
+}
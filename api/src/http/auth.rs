@@ -0,0 +1,42 @@
+use super::{error_response, AppError};
+use crate::domain::operator::{Operator, OperatorRepository};
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+/// Authenticates the operator from the `Authorization: Bearer <token>` header
+#[async_trait]
+impl<S> FromRequestParts<S> for Operator
+where
+    OperatorRepository: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let Some(token) = token else {
+            return Err(error_response(
+                StatusCode::UNAUTHORIZED,
+                "missing operator token",
+            ));
+        };
+
+        let operator_repo = OperatorRepository::from_ref(state);
+        match operator_repo.fetch_by_token(token).await {
+            Ok(Some(operator)) => Ok(operator),
+            Ok(None) => Err(error_response(
+                StatusCode::UNAUTHORIZED,
+                "invalid operator token",
+            )),
+            Err(e) => Err(AppError::from(e).into_response()),
+        }
+    }
+}
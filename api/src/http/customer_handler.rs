@@ -1,6 +1,7 @@
 use super::HttpResult;
 use crate::domain::{
     customer::{Customer, CustomerRepository, NewCustomer},
+    device::{Device, DeviceRepository},
     staff::StaffService,
 };
 use axum::{
@@ -25,10 +26,17 @@ pub async fn create(
 
 pub async fn update(
     State(customer_repo): State<CustomerRepository>,
+    State(device_repo): State<DeviceRepository>,
+    State(staff_service): State<StaffService>,
     Path(id): Path<i64>,
     Json(new_customer): Json<NewCustomer>,
 ) -> HttpResult<Json<Customer>> {
+    let old_customer = customer_repo.fetch_one(id).await?;
     let customer = customer_repo.update(id, &new_customer).await?;
+    if old_customer.schedule_id != customer.schedule_id {
+        let devices = device_repo.fetch_by_customer(id).await?;
+        staff_service.bulk_load(&devices).await?;
+    }
     Ok(Json(customer))
 }
 
@@ -60,3 +68,23 @@ pub async fn list(
     let customers = customer_repo.fetch_all(filter.active).await?;
     Ok(Json(customers))
 }
+
+pub async fn list_devices(
+    State(device_repo): State<DeviceRepository>,
+    Path(id): Path<i64>,
+) -> HttpResult<Json<Vec<Device>>> {
+    let device_list = device_repo.fetch_by_customer(id).await?;
+    Ok(Json(device_list))
+}
+
+pub async fn update_devices(
+    State(device_repo): State<DeviceRepository>,
+    State(staff_service): State<StaffService>,
+    Path(id): Path<i64>,
+    Json(device_ids): Json<Vec<i64>>,
+) -> HttpResult<Json<Vec<Device>>> {
+    let changed = device_repo.update_customer_devices(id, &device_ids).await?;
+    staff_service.bulk_load(&changed).await?;
+    let device_list = device_repo.fetch_by_customer(id).await?;
+    Ok(Json(device_list))
+}
@@ -1,8 +1,436 @@
-use super::HttpResult;
-use crate::domain::device::{Device, DeviceRepository};
-use axum::{extract::State, Json};
+use std::{slice, time::Duration};
+
+use super::{error_response, HttpResult};
+use crate::domain::{
+    device::{Device, DeviceRepository, DeviceService},
+    entry_log::EntryLog,
+    firmware::{FirmwareRepository, FirmwareUpdate},
+    operator::Operator,
+    staff::{StaffRepository, StaffService},
+    user_action::{DeviceSync, UserActionRepository},
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use doorsys_protocol::{
+    ButtonConfig, DoorSensorConfig, KeypadConfig, KeypadMode, LockoutConfig, LongPress,
+    NetworkConfig, RelayConfig, TlsConfig,
+};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Unlock {
+    /// Seconds to keep the door open, defaults to the device setting
+    duration: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum KeypadModeParam {
+    FourBit,
+    EightBit,
+    Burst,
+}
+
+/// Keypad settings, omitted fields take the firmware defaults
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetKeypad {
+    mode: Option<KeypadModeParam>,
+    min_pin_length: Option<u8>,
+    max_pin_length: Option<u8>,
+    submit_key: Option<u8>,
+    cancel_key: Option<u8>,
+    auto_submit: Option<bool>,
+    burst_facility: Option<u32>,
+}
+
+impl SetKeypad {
+    fn to_config(&self) -> KeypadConfig {
+        let default = KeypadConfig::default();
+        KeypadConfig {
+            mode: match self.mode {
+                Some(KeypadModeParam::FourBit) => KeypadMode::FourBit,
+                Some(KeypadModeParam::EightBit) => KeypadMode::EightBit,
+                Some(KeypadModeParam::Burst) => KeypadMode::Burst,
+                None => default.mode,
+            },
+            min_pin_length: self.min_pin_length.unwrap_or(default.min_pin_length),
+            max_pin_length: self.max_pin_length.unwrap_or(default.max_pin_length),
+            submit_key: self.submit_key.unwrap_or(default.submit_key),
+            cancel_key: self.cancel_key.unwrap_or(default.cancel_key),
+            auto_submit: self.auto_submit.unwrap_or(default.auto_submit),
+            burst_facility: self.burst_facility.unwrap_or(default.burst_facility),
+        }
+    }
+}
+
+/// Checks the settings, burst keypads need a facility code no card uses as
+/// their pins would otherwise be read as cards
+fn validate_keypad(keypad: &SetKeypad, config: &KeypadConfig) -> Option<&'static str> {
+    if config.mode == KeypadMode::Burst && keypad.burst_facility.is_none() {
+        return Some("burst mode requires a burst facility");
+    }
+    config.validate()
+}
+
+/// Keypad lockout, omitted fields take the firmware defaults
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetLockout {
+    /// Failed attempts that lock the keypad, 0 disables the lockout
+    max_failures: Option<u8>,
+    /// Seconds the failures are counted over
+    window: Option<u64>,
+    /// Seconds the keypad stays locked
+    duration: Option<u64>,
+}
+
+impl SetLockout {
+    fn to_config(&self) -> LockoutConfig {
+        let default = LockoutConfig::default();
+        LockoutConfig {
+            max_failures: self.max_failures.unwrap_or(default.max_failures),
+            window: self
+                .window
+                .map(Duration::from_secs)
+                .unwrap_or(default.window),
+            duration: self
+                .duration
+                .map(Duration::from_secs)
+                .unwrap_or(default.duration),
+        }
+    }
+}
+
+/// Failures the device can track
+const MAX_FAILURES: u8 = 16;
+
+fn validate_lockout(config: &LockoutConfig) -> Option<&'static str> {
+    if config.max_failures > MAX_FAILURES {
+        return Some("max failures must be between 0 and 16");
+    }
+    if config.window.is_zero() || config.duration.is_zero() {
+        return Some("window and duration must be at least a second");
+    }
+    None
+}
+
+/// Door relay wiring, omitted fields take the firmware defaults
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetRelay {
+    /// Seconds the door stays unlocked when the unlock doesn't set it
+    unlock_time: Option<u64>,
+    active_low: Option<bool>,
+    /// The lock releases without power, like maglocks
+    fail_safe: Option<bool>,
+}
+
+impl SetRelay {
+    fn to_config(&self) -> RelayConfig {
+        let default = RelayConfig::default();
+        RelayConfig {
+            unlock_time: self
+                .unlock_time
+                .map(Duration::from_secs)
+                .unwrap_or(default.unlock_time),
+            active_low: self.active_low.unwrap_or(default.active_low),
+            fail_safe: self.fail_safe.unwrap_or(default.fail_safe),
+        }
+    }
+}
+
+/// Longest unlock the device accepts
+const MAX_UNLOCK_TIME: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum LongPressParam {
+    Unlock,
+    HoldOpen,
+}
+
+/// Exit button, omitted fields take the firmware defaults
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetButton {
+    /// Seconds the button must be held for a long press
+    long_press: Option<u64>,
+    long_press_action: Option<LongPressParam>,
+}
+
+impl SetButton {
+    fn to_config(&self) -> ButtonConfig {
+        let default = ButtonConfig::default();
+        ButtonConfig {
+            long_press: self
+                .long_press
+                .map(Duration::from_secs)
+                .unwrap_or(default.long_press),
+            long_press_action: match self.long_press_action {
+                Some(LongPressParam::Unlock) => LongPress::Unlock,
+                Some(LongPressParam::HoldOpen) => LongPress::HoldOpen,
+                None => default.long_press_action,
+            },
+        }
+    }
+}
+
+/// Door position sensor, omitted fields take the firmware defaults
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetDoorSensor {
+    enabled: bool,
+    /// Seconds the door can stay open after an unlock
+    held_open: Option<u64>,
+}
+
+impl SetDoorSensor {
+    fn to_config(&self) -> DoorSensorConfig {
+        let default = DoorSensorConfig::default();
+        DoorSensorConfig {
+            enabled: self.enabled,
+            held_open: self
+                .held_open
+                .map(Duration::from_secs)
+                .unwrap_or(default.held_open),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetSecurityMode {
+    card_and_pin: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetFacilityCodes {
+    facility_codes: Vec<i32>,
+}
+
+/// PEM certificates for the device, an empty value removes the current one
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetTls {
+    ca_cert: Option<String>,
+    client_cert: Option<String>,
+    client_key: Option<String>,
+}
+
+/// New credentials for the device, omitted fields keep their current value
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetNetwork {
+    wifi_ssid: Option<String>,
+    wifi_pass: Option<String>,
+    mqtt_url: Option<String>,
+    mqtt_user: Option<String>,
+    mqtt_pass: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateFirmware {
+    release_id: i64,
+}
 
 pub async fn list(State(device_repo): State<DeviceRepository>) -> HttpResult<Json<Vec<Device>>> {
     let device_list = device_repo.fetch_all().await?;
     Ok(Json(device_list))
 }
+
+pub async fn sync_status(
+    State(user_action_repo): State<UserActionRepository>,
+    _operator: Operator,
+) -> HttpResult<Json<Vec<DeviceSync>>> {
+    let sync_list = user_action_repo.fetch_device_sync().await?;
+    Ok(Json(sync_list))
+}
+
+pub async fn unlock(
+    State(device_service): State<DeviceService>,
+    operator: Operator,
+    Path(id): Path<i64>,
+    Json(unlock): Json<Unlock>,
+) -> HttpResult<Json<EntryLog>> {
+    let duration = unlock.duration.map(Duration::from_secs);
+    let entry_log = device_service.unlock(id, &operator, duration).await?;
+    Ok(Json(entry_log))
+}
+
+pub async fn update_firmware(
+    State(device_service): State<DeviceService>,
+    operator: Operator,
+    Path(id): Path<i64>,
+    Json(update): Json<UpdateFirmware>,
+) -> HttpResult<Json<FirmwareUpdate>> {
+    let update = device_service
+        .update_firmware(id, update.release_id, &operator)
+        .await?;
+    Ok(Json(update))
+}
+
+pub async fn list_firmware_updates(
+    State(firmware_repo): State<FirmwareRepository>,
+    _operator: Operator,
+    Path(id): Path<i64>,
+) -> HttpResult<Json<Vec<FirmwareUpdate>>> {
+    let update_list = firmware_repo.fetch_updates(id).await?;
+    Ok(Json(update_list))
+}
+
+/// Sends new credentials, the device restores the previous ones if it can't reach the broker
+pub async fn set_network(
+    State(device_service): State<DeviceService>,
+    operator: Operator,
+    Path(id): Path<i64>,
+    Json(network): Json<SetNetwork>,
+) -> HttpResult<StatusCode> {
+    let config = NetworkConfig {
+        wifi_ssid: network.wifi_ssid,
+        wifi_pass: network.wifi_pass,
+        mqtt_url: network.mqtt_url,
+        mqtt_user: network.mqtt_user,
+        mqtt_pass: network.mqtt_pass,
+    };
+    device_service.set_network(id, config, &operator).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Sends new broker certificates, rolled back like the network credentials
+pub async fn set_tls(
+    State(device_service): State<DeviceService>,
+    operator: Operator,
+    Path(id): Path<i64>,
+    Json(tls): Json<SetTls>,
+) -> HttpResult<StatusCode> {
+    let config = TlsConfig {
+        ca_cert: tls.ca_cert,
+        client_cert: tls.client_cert,
+        client_key: tls.client_key,
+    };
+    device_service.set_tls(id, config, &operator).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Restricts the cards the device accepts, an empty list accepts any
+pub async fn set_facility_codes(
+    State(device_service): State<DeviceService>,
+    operator: Operator,
+    Path(id): Path<i64>,
+    Json(facilities): Json<SetFacilityCodes>,
+) -> HttpResult<Json<Device>> {
+    let device = device_service
+        .set_facility_codes(id, &facilities.facility_codes, &operator)
+        .await?;
+    Ok(Json(device))
+}
+
+pub async fn set_lockout(
+    State(device_service): State<DeviceService>,
+    operator: Operator,
+    Path(id): Path<i64>,
+    Json(lockout): Json<SetLockout>,
+) -> HttpResult<Response> {
+    let config = lockout.to_config();
+    if let Some(msg) = validate_lockout(&config) {
+        return Ok(error_response(StatusCode::BAD_REQUEST, msg));
+    }
+    device_service.set_lockout(id, config, &operator).await?;
+    Ok(StatusCode::ACCEPTED.into_response())
+}
+
+pub async fn set_door_sensor(
+    State(device_service): State<DeviceService>,
+    operator: Operator,
+    Path(id): Path<i64>,
+    Json(sensor): Json<SetDoorSensor>,
+) -> HttpResult<Response> {
+    let config = sensor.to_config();
+    if config.held_open.is_zero() {
+        let msg = "held open time must be at least a second";
+        return Ok(error_response(StatusCode::BAD_REQUEST, msg));
+    }
+    device_service
+        .set_door_sensor(id, config, &operator)
+        .await?;
+    Ok(StatusCode::ACCEPTED.into_response())
+}
+
+pub async fn set_relay(
+    State(device_service): State<DeviceService>,
+    operator: Operator,
+    Path(id): Path<i64>,
+    Json(relay): Json<SetRelay>,
+) -> HttpResult<Response> {
+    let config = relay.to_config();
+    if config.unlock_time.is_zero() || config.unlock_time > MAX_UNLOCK_TIME {
+        let msg = "unlock time must be between 1 and 60 seconds";
+        return Ok(error_response(StatusCode::BAD_REQUEST, msg));
+    }
+    device_service.set_relay(id, config, &operator).await?;
+    Ok(StatusCode::ACCEPTED.into_response())
+}
+
+pub async fn set_button(
+    State(device_service): State<DeviceService>,
+    operator: Operator,
+    Path(id): Path<i64>,
+    Json(button): Json<SetButton>,
+) -> HttpResult<Response> {
+    let config = button.to_config();
+    if config.long_press.is_zero() {
+        let msg = "long press must be at least a second";
+        return Ok(error_response(StatusCode::BAD_REQUEST, msg));
+    }
+    device_service.set_button(id, config, &operator).await?;
+    Ok(StatusCode::ACCEPTED.into_response())
+}
+
+/// Changes the security mode and resyncs the device, cards carry the pin
+/// they must be followed by
+pub async fn set_security_mode(
+    State(device_service): State<DeviceService>,
+    State(staff_service): State<StaffService>,
+    operator: Operator,
+    Path(id): Path<i64>,
+    Json(security): Json<SetSecurityMode>,
+) -> HttpResult<Json<Device>> {
+    let device = device_service
+        .set_security_mode(id, security.card_and_pin, &operator)
+        .await?;
+    staff_service.bulk_load(slice::from_ref(&device)).await?;
+    Ok(Json(device))
+}
+
+pub async fn set_keypad(
+    State(device_service): State<DeviceService>,
+    State(staff_repo): State<StaffRepository>,
+    operator: Operator,
+    Path(id): Path<i64>,
+    Json(keypad): Json<SetKeypad>,
+) -> HttpResult<Response> {
+    let config = keypad.to_config();
+    if let Some(msg) = validate_keypad(&keypad, &config) {
+        return Ok(error_response(StatusCode::BAD_REQUEST, msg));
+    }
+    if config.mode == KeypadMode::Burst
+        && staff_repo
+            .uses_facility(id, config.burst_facility as i32)
+            .await?
+    {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            "burst facility is used by cards on the device",
+        ));
+    }
+    device_service.set_keypad(id, config, &operator).await?;
+    Ok(StatusCode::ACCEPTED.into_response())
+}
@@ -14,6 +14,7 @@ pub struct Filter {
     end_date: DateTime<Utc>,
     device_id: Option<i64>,
     customer_id: Option<i64>,
+    reason: Option<String>,
 }
 
 pub async fn list(
@@ -23,7 +24,12 @@ pub async fn list(
     let date_range = filter.start_date..filter.end_date;
     tracing::debug!("Getting entry_logs for {:?}", filter);
     let entry_list = entry_log_repo
-        .fetch_all(date_range, filter.device_id, filter.customer_id)
+        .fetch_all(
+            date_range,
+            filter.device_id,
+            filter.customer_id,
+            filter.reason.as_deref(),
+        )
         .await?;
     Ok(Json(entry_list))
 }
@@ -0,0 +1,72 @@
+use super::{error_response, HttpResult};
+use crate::domain::{
+    firmware::{FirmwareRelease, FirmwareRepository},
+    operator::Operator,
+};
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+pub struct Upload {
+    version: String,
+}
+
+/// Stores the raw image sent as the request body
+pub async fn upload(
+    State(firmware_repo): State<FirmwareRepository>,
+    operator: Operator,
+    Query(upload): Query<Upload>,
+    image: Bytes,
+) -> HttpResult<Json<FirmwareRelease>> {
+    let release = firmware_repo
+        .create_release(&upload.version, &image)
+        .await?;
+    tracing::info!(
+        "Firmware {} uploaded by {}, {} bytes",
+        release.version,
+        operator.name,
+        release.size
+    );
+    Ok(Json(release))
+}
+
+pub async fn list(
+    State(firmware_repo): State<FirmwareRepository>,
+    _operator: Operator,
+) -> HttpResult<Json<Vec<FirmwareRelease>>> {
+    let release_list = firmware_repo.fetch_releases().await?;
+    Ok(Json(release_list))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Download {
+    job: i64,
+    token: String,
+}
+
+/// Serves the image to devices, which check it against the hash in the update
+/// command. The url carries the token of the update it was sent with.
+pub async fn image(
+    State(firmware_repo): State<FirmwareRepository>,
+    Path(id): Path<i64>,
+    Query(download): Query<Download>,
+) -> HttpResult<Response> {
+    let image = firmware_repo
+        .fetch_image(id, download.job, &download.token)
+        .await?;
+    match image {
+        Some(image) => {
+            Ok(([(header::CONTENT_TYPE, "application/octet-stream")], image).into_response())
+        }
+        None => Ok(error_response(
+            StatusCode::UNAUTHORIZED,
+            "invalid or expired download token",
+        )),
+    }
+}
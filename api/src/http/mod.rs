@@ -1,14 +1,20 @@
 use crate::domain::{
+    alert::AlertRepository,
     customer::CustomerRepository,
-    device::DeviceRepository,
+    device::{DeviceRepository, DeviceService},
     entry_log::EntryLogRepository,
+    firmware::FirmwareRepository,
+    operator::OperatorRepository,
+    schedule::ScheduleRepository,
+    site_key::SiteKeyRepository,
     staff::{StaffRepository, StaffService},
+    user_action::UserActionRepository,
 };
 use anyhow::Context;
 use axum::{
-    extract::{FromRef, State},
+    extract::{DefaultBodyLimit, FromRef, State},
     http::StatusCode,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     routing::{get, post, put},
     Json, Router,
 };
@@ -21,9 +27,13 @@ use tokio::{
 };
 use tower_http::trace::TraceLayer;
 
+pub mod alert_handler;
+mod auth;
 pub mod customer_handler;
 pub mod device_handler;
 pub mod entry_handler;
+pub mod firmware_handler;
+pub mod schedule_handler;
 pub mod staff_handler;
 
 #[derive(Clone)]
@@ -34,7 +44,13 @@ pub struct AppState {
     pub staff_repo: StaffRepository,
     pub entry_log_repo: EntryLogRepository,
     pub device_repo: DeviceRepository,
+    pub user_action_repo: UserActionRepository,
+    pub operator_repo: OperatorRepository,
+    pub schedule_repo: ScheduleRepository,
+    pub firmware_repo: FirmwareRepository,
+    pub alert_repo: AlertRepository,
     pub staff_service: StaffService,
+    pub device_service: DeviceService,
 }
 
 impl FromRef<AppState> for PgPool {
@@ -73,29 +89,68 @@ impl FromRef<AppState> for DeviceRepository {
     }
 }
 
+impl FromRef<AppState> for UserActionRepository {
+    fn from_ref(input: &AppState) -> Self {
+        input.user_action_repo.clone()
+    }
+}
+
+impl FromRef<AppState> for OperatorRepository {
+    fn from_ref(input: &AppState) -> Self {
+        input.operator_repo.clone()
+    }
+}
+
+impl FromRef<AppState> for ScheduleRepository {
+    fn from_ref(input: &AppState) -> Self {
+        input.schedule_repo.clone()
+    }
+}
+
+impl FromRef<AppState> for FirmwareRepository {
+    fn from_ref(input: &AppState) -> Self {
+        input.firmware_repo.clone()
+    }
+}
+
+impl FromRef<AppState> for AlertRepository {
+    fn from_ref(input: &AppState) -> Self {
+        input.alert_repo.clone()
+    }
+}
+
 impl FromRef<AppState> for StaffService {
     fn from_ref(input: &AppState) -> Self {
         input.staff_service.clone()
     }
 }
 
+impl FromRef<AppState> for DeviceService {
+    fn from_ref(input: &AppState) -> Self {
+        input.device_service.clone()
+    }
+}
+
 pub type HttpResult<T, E = AppError> = core::result::Result<T, E>;
 
 #[derive(Debug)]
 pub struct AppError(anyhow::Error);
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> axum::response::Response {
-        let status = StatusCode::INTERNAL_SERVER_ERROR;
-        let payload = json!({
-            "code": status.as_u16(),
-            "success": status.is_success(),
-            "msg": format!("{}", self.0)
-        });
+pub fn error_response(status: StatusCode, msg: impl ToString) -> Response {
+    let payload = json!({
+        "code": status.as_u16(),
+        "success": status.is_success(),
+        "msg": msg.to_string()
+    });
 
+    (status, payload.to_string()).into_response()
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
         tracing::error!("request error: {:?}", self);
 
-        (status, payload.to_string()).into_response()
+        error_response(StatusCode::INTERNAL_SERVER_ERROR, &self.0)
     }
 }
 
@@ -108,14 +163,40 @@ where
     }
 }
 
-pub async fn serve(pool: PgPool, mqtt_client: AsyncClient) -> anyhow::Result<()> {
+/// Firmware images are larger than the default body limit
+const FIRMWARE_BODY_LIMIT: usize = 4 * 1024 * 1024;
+
+pub async fn serve(
+    pool: PgPool,
+    mqtt_client: AsyncClient,
+    mqtt_tls: bool,
+    public_url: Option<String>,
+) -> anyhow::Result<()> {
     let customer_repo = CustomerRepository { pool: pool.clone() };
     let staff_repo = StaffRepository { pool: pool.clone() };
     let entry_log_repo = EntryLogRepository { pool: pool.clone() };
     let device_repo = DeviceRepository { pool: pool.clone() };
+    let user_action_repo = UserActionRepository { pool: pool.clone() };
+    let operator_repo = OperatorRepository { pool: pool.clone() };
+    let schedule_repo = ScheduleRepository { pool: pool.clone() };
+    let firmware_repo = FirmwareRepository { pool: pool.clone() };
+    let alert_repo = AlertRepository { pool: pool.clone() };
+    let site_key_repo = SiteKeyRepository { pool: pool.clone() };
     let staff_service = StaffService {
         staff_repo: staff_repo.clone(),
+        device_repo: device_repo.clone(),
+        schedule_repo: schedule_repo.clone(),
+        user_action_repo: user_action_repo.clone(),
+        site_key_repo,
+        mqtt_client: mqtt_client.clone(),
+        mqtt_tls,
+    };
+    let device_service = DeviceService {
+        device_repo: device_repo.clone(),
+        entry_log_repo: entry_log_repo.clone(),
+        firmware_repo: firmware_repo.clone(),
         mqtt_client: mqtt_client.clone(),
+        public_url,
     };
     let app_state = AppState {
         pool,
@@ -124,7 +205,13 @@ pub async fn serve(pool: PgPool, mqtt_client: AsyncClient) -> anyhow::Result<()>
         staff_repo,
         entry_log_repo,
         device_repo,
+        user_action_repo,
+        operator_repo,
+        schedule_repo,
+        firmware_repo,
+        alert_repo,
         staff_service,
+        device_service,
     };
 
     let app = Router::new()
@@ -142,15 +229,67 @@ pub async fn serve(pool: PgPool, mqtt_client: AsyncClient) -> anyhow::Result<()>
             put(customer_handler::update_status),
         )
         .route("/customers/:id/staff", get(staff_handler::list))
+        .route(
+            "/customers/:id/devices",
+            get(customer_handler::list_devices).put(customer_handler::update_devices),
+        )
         .route("/staff", post(staff_handler::create))
         .route(
             "/staff/:id",
             get(staff_handler::get).put(staff_handler::update),
         )
         .route("/staff/:id/pin", post(staff_handler::update_pin))
+        .route(
+            "/staff/:id/duress_pin",
+            post(staff_handler::update_duress_pin).delete(staff_handler::delete_duress_pin),
+        )
         .route("/staff/:id/status", put(staff_handler::update_status))
+        .route("/staff/:id/uses", put(staff_handler::update_uses))
         .route("/devices", get(device_handler::list))
+        .route("/devices/sync", get(device_handler::sync_status))
+        .route("/devices/:id/unlock", post(device_handler::unlock))
+        .route("/devices/:id/network", put(device_handler::set_network))
+        .route("/devices/:id/tls", put(device_handler::set_tls))
+        .route("/devices/:id/keypad", put(device_handler::set_keypad))
+        .route("/devices/:id/lockout", put(device_handler::set_lockout))
+        .route(
+            "/devices/:id/door_sensor",
+            put(device_handler::set_door_sensor),
+        )
+        .route("/devices/:id/relay", put(device_handler::set_relay))
+        .route("/devices/:id/button", put(device_handler::set_button))
+        .route(
+            "/devices/:id/security",
+            put(device_handler::set_security_mode),
+        )
+        .route(
+            "/devices/:id/facilities",
+            put(device_handler::set_facility_codes),
+        )
+        .route(
+            "/devices/:id/firmware",
+            get(device_handler::list_firmware_updates).post(device_handler::update_firmware),
+        )
+        .route(
+            "/firmware",
+            get(firmware_handler::list)
+                .post(firmware_handler::upload)
+                .layer(DefaultBodyLimit::max(FIRMWARE_BODY_LIMIT)),
+        )
+        .route("/firmware/:id/image", get(firmware_handler::image))
+        .route(
+            "/schedules",
+            get(schedule_handler::list).post(schedule_handler::create),
+        )
+        .route(
+            "/schedules/:id",
+            get(schedule_handler::get)
+                .put(schedule_handler::update)
+                .delete(schedule_handler::delete),
+        )
         .route("/entry_logs", get(entry_handler::list))
+        .route("/alerts", get(alert_handler::list))
+        .route("/alerts/:id/ack", post(alert_handler::acknowledge))
         .route("/admin/bulk", post(staff_handler::bulk_load_codes))
         .layer(TraceLayer::new_for_http())
         .with_state(app_state);
@@ -0,0 +1,71 @@
+use super::{error_response, HttpResult};
+use crate::domain::{
+    schedule::{NewSchedule, Schedule, ScheduleRepository},
+    staff::StaffService,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+pub async fn create(
+    State(schedule_repo): State<ScheduleRepository>,
+    State(staff_service): State<StaffService>,
+    Json(new_schedule): Json<NewSchedule>,
+) -> HttpResult<Response> {
+    if let Some(msg) = new_schedule.validate() {
+        return Ok(error_response(StatusCode::BAD_REQUEST, msg));
+    }
+    let schedule = schedule_repo.create(&new_schedule).await?;
+    staff_service.push_schedule(schedule.to_protocol()).await?;
+    Ok(Json(schedule).into_response())
+}
+
+pub async fn update(
+    State(schedule_repo): State<ScheduleRepository>,
+    State(staff_service): State<StaffService>,
+    Path(id): Path<i64>,
+    Json(new_schedule): Json<NewSchedule>,
+) -> HttpResult<Response> {
+    if let Some(msg) = new_schedule.validate() {
+        return Ok(error_response(StatusCode::BAD_REQUEST, msg));
+    }
+    let schedule = schedule_repo.update(id, &new_schedule).await?;
+    staff_service.push_schedule(schedule.to_protocol()).await?;
+    Ok(Json(schedule).into_response())
+}
+
+pub async fn delete(
+    State(schedule_repo): State<ScheduleRepository>,
+    State(staff_service): State<StaffService>,
+    Path(id): Path<i64>,
+) -> HttpResult<Response> {
+    if schedule_repo.in_use(id).await? {
+        return Ok(error_response(
+            StatusCode::CONFLICT,
+            "schedule is assigned to customers or staff",
+        ));
+    }
+    if schedule_repo.delete(id).await? == 0 {
+        return Ok(error_response(StatusCode::NOT_FOUND, "schedule not found"));
+    }
+    staff_service.remove_schedule(id).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+pub async fn get(
+    State(schedule_repo): State<ScheduleRepository>,
+    Path(id): Path<i64>,
+) -> HttpResult<Json<Schedule>> {
+    let schedule = schedule_repo.fetch_one(id).await?;
+    Ok(Json(schedule))
+}
+
+pub async fn list(
+    State(schedule_repo): State<ScheduleRepository>,
+) -> HttpResult<Json<Vec<Schedule>>> {
+    let schedules = schedule_repo.fetch_all().await?;
+    Ok(Json(schedules))
+}
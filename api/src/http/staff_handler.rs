@@ -1,43 +1,43 @@
-use super::HttpResult;
-use crate::{
-    domain::staff::{NewStaff, Staff, StaffRepository, StaffService},
-    mqtt,
+use super::{error_response, HttpResult};
+use crate::domain::{
+    device::DeviceRepository,
+    staff::{self, NewStaff, Staff, StaffRepository, StaffService},
 };
 use axum::{
     extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
     Json,
 };
-use doorsys_protocol::UserAction;
 use rand::Rng;
-use rumqttc::{AsyncClient, QoS};
 
 fn generate_pin() -> i32 {
     let mut rng = rand::thread_rng();
     rng.gen_range(100000..=999999)
 }
 
+/// Generates a pin no staff member has as their pin or duress pin
+async fn unused_pin(staff_repo: &StaffRepository) -> Result<i32, sqlx::Error> {
+    loop {
+        let pin = generate_pin();
+        if !staff_repo.pin_in_use(pin).await? {
+            return Ok(pin);
+        }
+    }
+}
+
 pub async fn create(
     State(staff_repo): State<StaffRepository>,
-    State(mqtt_client): State<AsyncClient>,
+    State(staff_service): State<StaffService>,
     Json(new_staff): Json<NewStaff>,
-) -> HttpResult<Json<Staff>> {
-    let pin = generate_pin();
-    let staff = staff_repo.create(&new_staff, pin).await?;
-
-    let user_add = UserAction::Add(pin);
-    let payload = bincode::encode_to_vec(user_add, mqtt::BINCODE_CONFIG)?;
-    mqtt_client
-        .publish("doorsys/user", QoS::AtLeastOnce, false, payload)
-        .await?;
-
-    if let Some(fob) = staff.fob {
-        let user_add = UserAction::Add(fob);
-        let payload = bincode::encode_to_vec(user_add, mqtt::BINCODE_CONFIG)?;
-        mqtt_client
-            .publish("doorsys/user", QoS::AtLeastOnce, false, payload)
-            .await?;
+) -> HttpResult<Response> {
+    if let Some(msg) = new_staff.validate() {
+        return Ok(error_response(StatusCode::BAD_REQUEST, msg));
     }
-    Ok(Json(staff))
+    let pin = unused_pin(&staff_repo).await?;
+    let staff = staff_repo.create(&new_staff, pin).await?;
+    staff_service.send_mqtt_message(&staff).await?;
+    Ok(Json(staff).into_response())
 }
 
 pub async fn get(
@@ -58,45 +58,63 @@ pub async fn list(
 
 pub async fn update(
     State(staff_repo): State<StaffRepository>,
-    State(mqtt_client): State<AsyncClient>,
+    State(staff_service): State<StaffService>,
     Path(id): Path<i64>,
     Json(update_staff): Json<NewStaff>,
-) -> HttpResult<Json<Staff>> {
+) -> HttpResult<Response> {
+    if let Some(msg) = update_staff.validate() {
+        return Ok(error_response(StatusCode::BAD_REQUEST, msg));
+    }
     let old_staff = staff_repo.fetch_one(id).await?;
     let staff = staff_repo.update(id, &update_staff).await?;
 
-    if let Some(action) = match (old_staff.fob, staff.fob) {
-        (Some(old), Some(new)) if old != new => Some(UserAction::Replace { old, new }),
-        (None, Some(fob)) => Some(UserAction::Add(fob)),
-        (Some(fob), None) => Some(UserAction::Del(fob)),
-        _ => None,
-    } {
-        let payload = bincode::encode_to_vec(action, mqtt::BINCODE_CONFIG)?;
-        mqtt_client
-            .publish("doorsys/user", QoS::AtLeastOnce, false, payload)
-            .await?;
+    staff_service.revoke_customer(&old_staff, &staff).await?;
+    staff_service.revoke_card(&old_staff, &staff).await?;
+    let card_changed = (old_staff.fob, old_staff.facility_code) != (staff.fob, staff.facility_code);
+    if card_changed
+        || old_staff.customer_id != staff.customer_id
+        || old_staff.schedule_id != staff.schedule_id
+        || old_staff.card_and_pin != staff.card_and_pin
+        || old_staff.validity() != staff.validity()
+    {
+        staff_service.send_mqtt_message(&staff).await?;
     }
-    Ok(Json(staff))
+    Ok(Json(staff).into_response())
 }
 
 pub async fn update_pin(
     State(staff_repo): State<StaffRepository>,
-    State(mqtt_client): State<AsyncClient>,
+    State(staff_service): State<StaffService>,
     Path(id): Path<i64>,
 ) -> HttpResult<Json<Staff>> {
     let old_staff = staff_repo.fetch_one(id).await?;
     let old_pin = old_staff.pin;
-    let new_pin = generate_pin();
+    let new_pin = unused_pin(&staff_repo).await?;
     let staff = staff_repo.update_pin(id, new_pin).await?;
 
-    let replace_pin = UserAction::Replace {
-        old: old_pin,
-        new: new_pin,
-    };
-    let payload = bincode::encode_to_vec(replace_pin, mqtt::BINCODE_CONFIG)?;
-    mqtt_client
-        .publish("doorsys/user", QoS::AtLeastOnce, false, payload)
-        .await?;
+    staff_service.replace_pin(&staff, old_pin).await?;
+    Ok(Json(staff))
+}
+
+/// Generates a new duress pin, distinct from every pin and duress pin
+pub async fn update_duress_pin(
+    State(staff_repo): State<StaffRepository>,
+    State(staff_service): State<StaffService>,
+    Path(id): Path<i64>,
+) -> HttpResult<Json<Staff>> {
+    let duress_pin = unused_pin(&staff_repo).await?;
+    let staff = staff_repo.update_duress_pin(id, Some(duress_pin)).await?;
+    staff_service.send_mqtt_message(&staff).await?;
+    Ok(Json(staff))
+}
+
+pub async fn delete_duress_pin(
+    State(staff_repo): State<StaffRepository>,
+    State(staff_service): State<StaffService>,
+    Path(id): Path<i64>,
+) -> HttpResult<Json<Staff>> {
+    let staff = staff_repo.update_duress_pin(id, None).await?;
+    staff_service.send_mqtt_message(&staff).await?;
     Ok(Json(staff))
 }
 
@@ -109,16 +127,25 @@ pub async fn update_status(
     Ok(Json(staff))
 }
 
+/// Resets the entries a visitor has left, `null` lifts the limit
+pub async fn update_uses(
+    State(staff_service): State<StaffService>,
+    Path(id): Path<i64>,
+    Json(uses): Json<Option<i32>>,
+) -> HttpResult<Response> {
+    if let Some(msg) = staff::validate_uses(uses) {
+        return Ok(error_response(StatusCode::BAD_REQUEST, msg));
+    }
+    let staff = staff_service.reset_uses(id, uses).await?;
+    Ok(Json(staff).into_response())
+}
+
 pub async fn bulk_load_codes(
-    State(staff_repo): State<StaffRepository>,
-    State(mqtt_client): State<AsyncClient>,
+    State(device_repo): State<DeviceRepository>,
+    State(staff_service): State<StaffService>,
 ) -> HttpResult<()> {
-    let codes = staff_repo.fetch_all_codes().await?;
-    tracing::info!("Executing bulk load of {} codes", codes.len());
-    let bulk_action = UserAction::Bulk(codes.into_iter().flatten().collect());
-    let payload = bincode::encode_to_vec(bulk_action, mqtt::BINCODE_CONFIG)?;
-    mqtt_client
-        .publish("doorsys/user", QoS::AtLeastOnce, false, payload)
-        .await?;
+    let devices = device_repo.fetch_all().await?;
+    tracing::info!("Executing bulk load on {} devices", devices.len());
+    staff_service.bulk_load(&devices).await?;
     Ok(())
 }
@@ -3,6 +3,8 @@ mod http;
 mod logging;
 mod mqtt;
 
+use anyhow::Context;
+use domain::operator::OperatorRepository;
 use sqlx::postgres::PgPoolOptions;
 use std::env;
 
@@ -19,8 +21,22 @@ async fn main() -> anyhow::Result<()> {
     tracing::info!("Connected to database, executing migrations");
     sqlx::migrate!().run(&pool).await?;
 
+    if let Some("create-operator") = env::args().nth(1).as_deref() {
+        let name = env::args()
+            .nth(2)
+            .context("usage: doorsys-api create-operator <name>")?;
+        let (operator, token) = OperatorRepository { pool }.create(&name).await?;
+        println!("Operator {} created, token: {}", operator.name, token);
+        return Ok(());
+    }
+
     let mqtt_url = env::var("MQTT_URL")?;
     let mqtt_client = mqtt::start(pool.clone(), &mqtt_url).await?;
+    let mqtt_tls = mqtt_url.starts_with("mqtts://");
+    if !mqtt_tls {
+        tracing::warn!("Broker connection isn't encrypted, doors won't be sent the site key");
+    }
 
-    http::serve(pool, mqtt_client).await
+    let public_url = env::var("PUBLIC_URL").ok();
+    http::serve(pool, mqtt_client, mqtt_tls, public_url).await
 }
@@ -1,23 +1,43 @@
-use std::time::Duration;
+use std::{slice, time::Duration};
 
-use bincode::config::Configuration;
-use doorsys_protocol::Audit;
+use doorsys_protocol::{Ack, Alert, Audit, Digest, FirmwareStatus, ResyncRequest};
 use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
 use sqlx::PgPool;
 use tokio::{task, time};
 
-use crate::domain::entry_log::EntryLogRepository;
-
-pub const BINCODE_CONFIG: Configuration = bincode::config::standard();
+use crate::domain::{
+    alert::AlertRepository,
+    device::DeviceRepository,
+    entry_log::EntryLogRepository,
+    firmware::FirmwareRepository,
+    schedule::ScheduleRepository,
+    site_key::SiteKeyRepository,
+    staff::{StaffRepository, StaffService},
+    user_action::UserActionRepository,
+};
 
 pub async fn start(pool: PgPool, mqtt_url: &str) -> anyhow::Result<AsyncClient> {
     let mqtt_opts = MqttOptions::parse_url(mqtt_url)?;
+    let mqtt_tls = mqtt_url.starts_with("mqtts://");
 
     let (client, mut connection) = AsyncClient::new(mqtt_opts, 10);
     let cloned_client = client.clone();
 
     task::spawn(async move {
-        let entry_repo = EntryLogRepository { pool };
+        let entry_repo = EntryLogRepository { pool: pool.clone() };
+        let device_repo = DeviceRepository { pool: pool.clone() };
+        let user_action_repo = UserActionRepository { pool: pool.clone() };
+        let firmware_repo = FirmwareRepository { pool: pool.clone() };
+        let alert_repo = AlertRepository { pool: pool.clone() };
+        let staff_service = StaffService {
+            staff_repo: StaffRepository { pool: pool.clone() },
+            device_repo: device_repo.clone(),
+            schedule_repo: ScheduleRepository { pool: pool.clone() },
+            user_action_repo: user_action_repo.clone(),
+            site_key_repo: SiteKeyRepository { pool },
+            mqtt_client: client.clone(),
+            mqtt_tls,
+        };
 
         loop {
             match connection.poll().await {
@@ -28,52 +48,61 @@ pub async fn start(pool: PgPool, mqtt_url: &str) -> anyhow::Result<AsyncClient>
                         p.qos,
                         p.payload.len()
                     );
-                    match bincode::decode_from_slice::<Audit, _>(&p.payload, BINCODE_CONFIG) {
-                        Ok((audit, len)) => {
-                            let net_id = p.topic.split('/').nth(2);
-                            tracing::info!(
-                                "Audit({}) [{:?}]: {:?}",
-                                len,
-                                net_id.unwrap_or(""),
-                                audit
-                            );
-                            match entry_repo
-                                .create_with_code(
-                                    audit.code,
-                                    &audit.code_type.to_string(),
-                                    net_id,
-                                    audit.success,
-                                    &audit.timestamp.into(),
-                                )
-                                .await
-                            {
-                                Ok(log) => {
-                                    tracing::info!("Log created {:?}", log);
-                                }
-                                Err(sqlx::Error::Database(e)) => {
-                                    if let Some(c) = e.constraint() {
-                                        tracing::warn!("Duplicated entry log, skpping... {}", c);
-                                    } else {
-                                        tracing::error!("Database error creating entry log {}", e);
-                                    }
-                                }
-                                Err(e) => {
-                                    tracing::error!("Error creating entry log {}", e);
-                                }
-                            }
+                    let mut topic = p.topic.split('/').skip(1);
+                    let (kind, net_id) = (topic.next(), topic.next());
+                    match kind {
+                        Some("audit") => {
+                            handle_audit(
+                                &entry_repo,
+                                &alert_repo,
+                                &staff_service,
+                                net_id,
+                                &p.payload,
+                            )
+                            .await
+                        }
+                        Some("ack") => handle_ack(&user_action_repo, net_id, &p.payload).await,
+                        Some("alert") => handle_alert(&alert_repo, net_id, &p.payload).await,
+                        Some("firmware") => {
+                            handle_firmware(&firmware_repo, net_id, &p.payload).await
+                        }
+                        Some("digest") => {
+                            // Reconciling publishes back to the broker, so it can't
+                            // hold up the event loop
+                            let device_repo = device_repo.clone();
+                            let staff_service = staff_service.clone();
+                            let net_id = net_id.map(String::from);
+                            task::spawn(async move {
+                                handle_digest(&device_repo, &staff_service, net_id, &p.payload)
+                                    .await
+                            });
                         }
-                        Err(e) => {
-                            tracing::error!("Error decoding message: {}", e);
+                        Some("resync") => {
+                            let device_repo = device_repo.clone();
+                            let staff_service = staff_service.clone();
+                            let net_id = net_id.map(String::from);
+                            task::spawn(async move {
+                                handle_resync(&device_repo, &staff_service, net_id, &p.payload)
+                                    .await
+                            });
                         }
+                        _ => tracing::warn!("unknown topic {}", p.topic),
                     }
                 }
                 Ok(Event::Incoming(Packet::ConnAck(_))) => {
                     tracing::info!("Connected to mqtt broker and subscribing to topics");
-                    if let Err(e) = client.subscribe("doorsys/audit/+", QoS::AtLeastOnce).await {
-                        tracing::error!("Error subscribing to topic {}", e);
-                    }
-                    if let Err(e) = client.subscribe("doorsys/audit", QoS::AtLeastOnce).await {
-                        tracing::error!("Error subscribing to topic {}", e);
+                    for topic in [
+                        "doorsys/audit/+",
+                        "doorsys/audit",
+                        "doorsys/ack/+",
+                        "doorsys/digest/+",
+                        "doorsys/firmware/+",
+                        "doorsys/alert/+",
+                        "doorsys/resync/+",
+                    ] {
+                        if let Err(e) = client.subscribe(topic, QoS::AtLeastOnce).await {
+                            tracing::error!("Error subscribing to topic {}", e);
+                        }
                     }
                 }
                 Err(rumqttc::ConnectionError::Io(e)) => {
@@ -89,3 +118,191 @@ pub async fn start(pool: PgPool, mqtt_url: &str) -> anyhow::Result<AsyncClient>
 
     Ok(cloned_client)
 }
+
+async fn handle_audit(
+    entry_repo: &EntryLogRepository,
+    alert_repo: &AlertRepository,
+    staff_service: &StaffService,
+    net_id: Option<&str>,
+    payload: &[u8],
+) {
+    let audit = match doorsys_protocol::decode::<Audit>(payload) {
+        Ok(audit) => audit,
+        Err(e) => {
+            tracing::error!("Error decoding message: {}", e);
+            return;
+        }
+    };
+    tracing::info!("Audit [{:?}]: {:?}", net_id.unwrap_or(""), audit);
+    match entry_repo.create_with_code(&audit, net_id).await {
+        Ok(log) => {
+            tracing::info!("Log created {:?}", log);
+            // Retried audits hit the unique constraint, so the alarm goes off once
+            if log.duress {
+                match alert_repo.create_duress(&log).await {
+                    Ok(alert) => tracing::warn!("Duress alert raised {:?}", alert),
+                    Err(e) => tracing::error!("Error raising duress alert {}", e),
+                }
+            }
+            // Updating the visitor's codes publishes back to the broker, so it
+            // can't hold up the event loop
+            if let Some(staff_id) = log.staff_id.filter(|_| log.success) {
+                let staff_service = staff_service.clone();
+                task::spawn(async move {
+                    if let Err(e) = staff_service.count_use(staff_id).await {
+                        tracing::error!("Error counting visitor use {}", e);
+                    }
+                });
+            }
+        }
+        Err(sqlx::Error::Database(e)) => {
+            if let Some(c) = e.constraint() {
+                tracing::warn!("Duplicated entry log, skpping... {}", c);
+            } else {
+                tracing::error!("Database error creating entry log {}", e);
+            }
+        }
+        Err(e) => {
+            tracing::error!("Error creating entry log {}", e);
+        }
+    }
+}
+
+async fn handle_ack(user_action_repo: &UserActionRepository, net_id: Option<&str>, payload: &[u8]) {
+    let ack = match doorsys_protocol::decode::<Ack>(payload) {
+        Ok(ack) => ack,
+        Err(e) => {
+            tracing::error!("Error decoding message: {}", e);
+            return;
+        }
+    };
+    tracing::info!("Ack [{:?}]: {:?}", net_id.unwrap_or(""), ack);
+    let Some(net_id) = net_id else {
+        tracing::warn!("Ack without device id, skipping...");
+        return;
+    };
+    // Sequence 0 is used for untracked updates
+    if ack.seq == 0 {
+        return;
+    }
+    match user_action_repo
+        .ack(ack.seq as i64, net_id, ack.success, ack.error.as_deref())
+        .await
+    {
+        Ok(0) => tracing::warn!("No pending delivery of {} for {}", ack.seq, net_id),
+        Ok(_) => {}
+        Err(e) => tracing::error!("Error recording ack {}", e),
+    }
+}
+
+async fn handle_alert(alert_repo: &AlertRepository, net_id: Option<&str>, payload: &[u8]) {
+    let alert = match doorsys_protocol::decode::<Alert>(payload) {
+        Ok(alert) => alert,
+        Err(e) => {
+            tracing::error!("Error decoding message: {}", e);
+            return;
+        }
+    };
+    tracing::warn!("Alert [{:?}]: {:?}", net_id.unwrap_or(""), alert);
+    let Some(net_id) = net_id else {
+        tracing::warn!("Alert without device id, skipping...");
+        return;
+    };
+    match alert_repo.create(&alert, net_id).await {
+        Ok(alert) => tracing::info!("Alert created {:?}", alert),
+        Err(e) => tracing::error!("Error creating alert {:#}", e),
+    }
+}
+
+async fn handle_firmware(firmware_repo: &FirmwareRepository, net_id: Option<&str>, payload: &[u8]) {
+    let status = match doorsys_protocol::decode::<FirmwareStatus>(payload) {
+        Ok(status) => status,
+        Err(e) => {
+            tracing::error!("Error decoding message: {}", e);
+            return;
+        }
+    };
+    tracing::info!("Firmware [{:?}]: {:?}", net_id.unwrap_or(""), status);
+    let Some(net_id) = net_id else {
+        tracing::warn!("Firmware status without device id, skipping...");
+        return;
+    };
+    match firmware_repo
+        .update_status(status.job as i64, net_id, &status.state)
+        .await
+    {
+        Ok(0) => tracing::warn!("No firmware update {} for {}", status.job, net_id),
+        Ok(_) => {}
+        Err(e) => tracing::error!("Error recording firmware status {}", e),
+    }
+}
+
+async fn handle_digest(
+    device_repo: &DeviceRepository,
+    staff_service: &StaffService,
+    net_id: Option<String>,
+    payload: &[u8],
+) {
+    let digest = match doorsys_protocol::decode::<Digest>(payload) {
+        Ok(digest) => digest,
+        Err(e) => {
+            tracing::error!("Error decoding message: {}", e);
+            return;
+        }
+    };
+    let Some(net_id) = net_id else {
+        tracing::warn!("Digest without device id, skipping...");
+        return;
+    };
+    tracing::debug!("Digest [{}]: {:?}", net_id, digest);
+    let device = match device_repo.fetch_by_net_id(&net_id).await {
+        Ok(Some(device)) => device,
+        Ok(None) => {
+            tracing::warn!("Digest from unknown device {}, skipping...", net_id);
+            return;
+        }
+        Err(e) => {
+            tracing::error!("Error fetching device {}", e);
+            return;
+        }
+    };
+    if let Err(e) = staff_service.reconcile(&device, &digest).await {
+        tracing::error!("Error reconciling device {}: {}", net_id, e);
+    }
+}
+
+/// Sends every code to a device that lost some of its stored ones, pending
+/// actions don't hold it back since they can't restore what was lost
+async fn handle_resync(
+    device_repo: &DeviceRepository,
+    staff_service: &StaffService,
+    net_id: Option<String>,
+    payload: &[u8],
+) {
+    let request = match doorsys_protocol::decode::<ResyncRequest>(payload) {
+        Ok(request) => request,
+        Err(e) => {
+            tracing::error!("Error decoding message: {}", e);
+            return;
+        }
+    };
+    let Some(net_id) = net_id else {
+        tracing::warn!("Resync request without device id, skipping...");
+        return;
+    };
+    tracing::warn!("Resync [{}]: {:?}", net_id, request);
+    let device = match device_repo.fetch_by_net_id(&net_id).await {
+        Ok(Some(device)) => device,
+        Ok(None) => {
+            tracing::warn!("Resync request from unknown device {}, skipping...", net_id);
+            return;
+        }
+        Err(e) => {
+            tracing::error!("Error fetching device {}", e);
+            return;
+        }
+    };
+    if let Err(e) = staff_service.bulk_load(slice::from_ref(&device)).await {
+        tracing::error!("Error resyncing device {}: {}", net_id, e);
+    }
+}
@@ -5,13 +5,10 @@ use std::{
     time::{Duration, SystemTime},
 };
 
-use bincode::config::Configuration;
 use chrono::{DateTime, Utc};
-use doorsys_protocol::{Audit, CodeType};
+use doorsys_protocol::{Audit, CodeType, Reason};
 use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
 
-const CONFIG: Configuration = bincode::config::standard();
-
 fn main() -> Result<(), Box<dyn Error>> {
     println!("Starting doorsys-cli");
 
@@ -25,8 +22,11 @@ fn main() -> Result<(), Box<dyn Error>> {
         code_type: CodeType::Pin,
         code: 1234,
         success: true,
+        reason: Reason::Granted,
+        facility: None,
+        duress: false,
     };
-    let payload = bincode::encode_to_vec(audit, CONFIG).unwrap();
+    let payload = doorsys_protocol::encode(&audit).unwrap();
     client
         .publish("doorsys/audit/aabbcc", QoS::AtLeastOnce, false, &*payload)
         .unwrap();
@@ -47,10 +47,8 @@ fn main() -> Result<(), Box<dyn Error>> {
                     "topic: {}, qos: {:?}, data: {:?}",
                     p.topic, p.qos, p.payload
                 );
-                if let Ok((audit, count)) =
-                    bincode::decode_from_slice::<Audit, _>(&p.payload, CONFIG)
-                {
-                    println!("Audit: {:?} {}", audit, count);
+                if let Ok(audit) = doorsys_protocol::decode::<Audit>(&p.payload) {
+                    println!("Audit: {:?}", audit);
                     println!("systime: {:?}", &audit.timestamp);
                     let date: DateTime<Utc> = audit.timestamp.into();
                     println!("datetime: {}", date);
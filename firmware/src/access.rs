@@ -0,0 +1,409 @@
+//! Access decisions for the credentials read at the door
+
+use std::{
+    sync::mpsc::Sender,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use doorsys_protocol::{
+    Alert, AlertKind, Audit, CodeHash, CodeType, KeypadMode, Reason, SecurityMode,
+};
+use doorsys_wiegand::lockout::{Lockout, Saved};
+use esp_idf_svc::hal::gpio::{Output, OutputPin, PinDriver};
+
+use crate::config::ConfigStore;
+use crate::door::DoorCommand;
+use crate::keypad::{self, AbortReason, Entry, Keypad, PendingCard};
+use crate::user::UserDB;
+use crate::wiegand::Packet;
+
+/// Card format burst keypads send pins as
+const BURST_FORMAT: &str = "H10301";
+
+/// Unreadable frames are summed up in a single alert at most this often, in
+/// milliseconds
+const NOISE_PERIOD: u64 = 60_000;
+
+fn keypad_feedback(
+    success: bool,
+    pin: &mut PinDriver<'_, impl OutputPin, Output>,
+) -> anyhow::Result<()> {
+    for _ in 0..8 {
+        if success {
+            pin.set_low()?;
+        } else {
+            pin.toggle()?;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    pin.set_high()?;
+    Ok(())
+}
+
+/// Three long beeps, distinct from a refused code
+fn locked_feedback(pin: &mut PinDriver<'_, impl OutputPin, Output>) -> anyhow::Result<()> {
+    for _ in 0..3 {
+        pin.set_low()?;
+        thread::sleep(Duration::from_millis(400));
+        pin.set_high()?;
+        thread::sleep(Duration::from_millis(200));
+    }
+    Ok(())
+}
+
+/// Queues an audit record for the attempt
+pub fn send_audit(
+    audit_tx: &Sender<Audit>,
+    code: i32,
+    facility: Option<u32>,
+    code_type: CodeType,
+    timestamp: SystemTime,
+    reason: Reason,
+) {
+    queue_audit(
+        audit_tx, code, facility, code_type, timestamp, reason, false,
+    );
+}
+
+/// Queues an audit record, flagged when a duress pin was entered
+fn queue_audit(
+    audit_tx: &Sender<Audit>,
+    code: i32,
+    facility: Option<u32>,
+    code_type: CodeType,
+    timestamp: SystemTime,
+    reason: Reason,
+    duress: bool,
+) {
+    let audit = Audit {
+        code,
+        code_type,
+        timestamp,
+        success: reason == Reason::Granted,
+        reason,
+        facility,
+        duress,
+    };
+    if let Err(e) = audit_tx.send(audit) {
+        log::error!("error sending audit record: {}", e);
+    }
+}
+
+/// Checks the packets coming from the reader and opens the door
+pub struct Access<P: OutputPin> {
+    user_db: UserDB,
+    config_store: ConfigStore,
+    door_tx: Sender<DoorCommand>,
+    audit_tx: Sender<Audit>,
+    alert_tx: Sender<Alert>,
+    signal_driver: PinDriver<'static, P, Output>,
+    keypad: Keypad,
+    /// Card read waiting for the pin in two factor entries
+    pending: Option<PendingCard>,
+    lockout: Lockout,
+    /// Lockout as last written to NVS
+    saved_lockout: Saved,
+    /// Unreadable frames since `noise_since` not reported yet
+    noise: u32,
+    noise_since: u64,
+}
+
+impl<P: OutputPin> Access<P> {
+    pub fn new(
+        user_db: UserDB,
+        config_store: ConfigStore,
+        door_tx: Sender<DoorCommand>,
+        audit_tx: Sender<Audit>,
+        alert_tx: Sender<Alert>,
+        signal_driver: PinDriver<'static, P, Output>,
+    ) -> Self {
+        let saved_lockout = config_store.lockout_state();
+        if saved_lockout.locked {
+            log::warn!("Keypad was locked before the restart, locking it again");
+        }
+        let settings = keypad::lockout_settings(&config_store.lockout());
+        let lockout = Lockout::restore(saved_lockout, 0, &settings);
+        Access {
+            user_db,
+            config_store,
+            door_tx,
+            audit_tx,
+            alert_tx,
+            signal_driver,
+            keypad: Keypad::default(),
+            pending: None,
+            lockout,
+            saved_lockout,
+            noise: 0,
+            noise_since: 0,
+        }
+    }
+
+    /// Handles a packet, or its absence, at `now` in milliseconds
+    pub fn handle(&mut self, packet: Option<Packet>, now: u64) {
+        let config = self.config_store.keypad();
+        let settings = keypad::settings(&config);
+        if let Some(entry) = self.keypad.poll(now, &settings) {
+            self.handle_entry(entry, now);
+        }
+        if self.pending.as_ref().is_some_and(|card| card.expired(now)) {
+            self.handle_entry(Entry::Abort(None, AbortReason::TimedOut), now);
+        }
+        self.report_noise(now);
+        match packet {
+            Some(Packet::Key { key, bits }) => {
+                if keypad::key_bits(config.mode) != Some(bits) {
+                    log::warn!("ignoring {}-bit key in {:?} mode", bits, config.mode);
+                    return;
+                }
+                if self.keypad_locked(now) {
+                    return;
+                }
+                if let Some(entry) = self.keypad.press(key, now, &settings) {
+                    self.handle_entry(entry, now);
+                }
+            }
+            // Burst keypads send the whole pin as a card, cards with a
+            // facility the door accepts are never taken for one
+            Some(Packet::Card {
+                format,
+                facility,
+                number,
+            }) if config.mode == KeypadMode::Burst
+                && format == BURST_FORMAT
+                && facility == config.burst_facility
+                && !self.user_db.lists_facility(facility) =>
+            {
+                if !self.keypad_locked(now) {
+                    self.handle_entry(Entry::Submit(number), now);
+                }
+            }
+            Some(Packet::Card {
+                format,
+                facility,
+                number,
+            }) => {
+                log::info!("Card {} facility {} ({})", number, facility, format);
+                self.handle_card(number, facility, now);
+            }
+            Some(Packet::BadParity { bits, data }) => {
+                log::warn!("parity check failed bits: {}, data: {:02X?}", bits, data);
+                self.unreadable(now);
+            }
+            Some(Packet::Unknown { bits, data }) => {
+                log::warn!("pattern not recognized bits: {}, data: {:02X?}", bits, data);
+                self.unreadable(now);
+            }
+            // Nothing received, pin entries time out on the next poll
+            None => {}
+        }
+    }
+
+    fn handle_card(&mut self, number: i32, facility: u32, now: u64) {
+        let timestamp = SystemTime::now();
+        let code = self.user_db.hash(number, Some(facility));
+        let reason = self.user_db.check(code, Some(facility), timestamp);
+        log::info!("Card {}: {:?}", number, reason);
+        self.keypad.clear();
+        let needs_pin = self.config_store.security() == SecurityMode::CardAndPin
+            || self.user_db.requires_pin(code);
+        if reason == Reason::Granted && needs_pin {
+            log::info!("Waiting for the pin of card {}", number);
+            let card = PendingCard::new(number, facility, now);
+            if let Some(card) = self.pending.replace(card) {
+                self.drop_card(card);
+            }
+            return;
+        }
+        self.open(reason, code);
+        let facility = Some(facility);
+        send_audit(
+            &self.audit_tx,
+            number,
+            facility,
+            CodeType::Fob,
+            timestamp,
+            reason,
+        );
+        self.feedback(reason == Reason::Granted);
+    }
+
+    /// Checks a finished pin entry or records why it was dropped.
+    ///
+    /// A pin following a card is checked against the card holder's. A duress
+    /// pin is checked as its holder's regular pin, only the audit of an entry
+    /// that opened the door is flagged so the alarm matches a real entry.
+    fn handle_entry(&mut self, entry: Entry, now: u64) {
+        let timestamp = SystemTime::now();
+        let success = match (entry, self.pending.take()) {
+            (Entry::Submit(pin), Some(card)) => {
+                let code = self.user_db.hash(card.number, Some(card.facility));
+                let pin = self.user_db.hash(pin, None);
+                let (reason, duress) = self.user_db.check_pair(code, card.facility, pin, timestamp);
+                log::info!("Card {} and pin: {:?}", card.number, reason);
+                self.open(reason, code);
+                let duress = duress && reason == Reason::Granted;
+                queue_audit(
+                    &self.audit_tx,
+                    card.number,
+                    Some(card.facility),
+                    CodeType::Fob,
+                    timestamp,
+                    reason,
+                    duress,
+                );
+                self.attempt(reason, now)
+            }
+            (Entry::Submit(pin), None) => {
+                let hash = self.user_db.hash(pin, None);
+                let owner = self.user_db.duress_owner(hash);
+                let code = owner.unwrap_or(hash);
+                let reason = match self.config_store.security() {
+                    SecurityMode::CardAndPin => Reason::CardRequired,
+                    SecurityMode::CardOrPin => self.user_db.check(code, None, timestamp),
+                };
+                log::info!("Pin {}: {:?}", pin, reason);
+                self.open(reason, code);
+                let duress = owner.is_some() && reason == Reason::Granted;
+                queue_audit(
+                    &self.audit_tx,
+                    pin,
+                    None,
+                    CodeType::Pin,
+                    timestamp,
+                    reason,
+                    duress,
+                );
+                self.attempt(reason, now)
+            }
+            (Entry::Abort(_, reason), Some(card)) => {
+                log::warn!("Card {} pin entry dropped: {:?}", card.number, reason);
+                self.drop_card(card);
+                false
+            }
+            (Entry::Abort(pin, reason), None) => {
+                if let Some(pin) = pin {
+                    let reason = keypad::abort_reason(reason);
+                    send_audit(&self.audit_tx, pin, None, CodeType::Pin, timestamp, reason);
+                }
+                false
+            }
+        };
+        self.feedback(success);
+    }
+
+    /// Records a card whose pin never came
+    fn drop_card(&mut self, card: PendingCard) {
+        send_audit(
+            &self.audit_tx,
+            card.number,
+            Some(card.facility),
+            CodeType::Fob,
+            SystemTime::now(),
+            Reason::PinRequired,
+        );
+    }
+
+    /// Counts a checked keypad entry towards the lockout, returns whether it
+    /// was granted
+    fn attempt(&mut self, reason: Reason, now: u64) -> bool {
+        if reason == Reason::Granted {
+            self.lockout.success();
+            self.save_lockout();
+            return true;
+        }
+        let config = self.config_store.lockout();
+        let locked = self
+            .lockout
+            .failure(now, &keypad::lockout_settings(&config));
+        self.save_lockout();
+        if locked {
+            let timestamp = SystemTime::now();
+            let locked = Reason::LockedOut;
+            send_audit(&self.audit_tx, 0, None, CodeType::Pin, timestamp, locked);
+            let alert = Alert {
+                timestamp,
+                kind: AlertKind::Lockout {
+                    failures: config.max_failures,
+                    duration: config.duration,
+                },
+            };
+            if let Err(e) = self.alert_tx.send(alert) {
+                log::error!("error sending alert: {}", e);
+            }
+        }
+        false
+    }
+
+    /// Persists the lockout when it changed, a restart doesn't clear it
+    fn save_lockout(&mut self) {
+        let saved = self.lockout.saved();
+        if saved == self.saved_lockout {
+            return;
+        }
+        match self.config_store.set_lockout_state(saved) {
+            Ok(()) => self.saved_lockout = saved,
+            Err(e) => log::error!("error saving lockout: {:#}", e),
+        }
+    }
+
+    /// Counts a frame that couldn't be decoded, line noise can send many of
+    /// them so they aren't audited
+    fn unreadable(&mut self, now: u64) {
+        if self.noise == 0 {
+            self.noise_since = now;
+        }
+        self.noise = self.noise.saturating_add(1);
+    }
+
+    /// Raises an alert with the unreadable frames once the period is over
+    fn report_noise(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.noise_since);
+        if self.noise == 0 || elapsed < NOISE_PERIOD {
+            return;
+        }
+        let alert = Alert {
+            timestamp: SystemTime::now(),
+            kind: AlertKind::ReaderNoise {
+                frames: self.noise,
+                period: Duration::from_millis(elapsed),
+            },
+        };
+        self.noise = 0;
+        if let Err(e) = self.alert_tx.send(alert) {
+            log::error!("error sending alert: {}", e);
+        }
+    }
+
+    /// Drops the key press while the keypad is locked out
+    fn keypad_locked(&mut self, now: u64) -> bool {
+        if !self.lockout.is_locked(now) {
+            self.save_lockout();
+            return false;
+        }
+        log::warn!("Keypad locked, ignoring key");
+        self.keypad.clear();
+        if let Err(e) = locked_feedback(&mut self.signal_driver) {
+            log::warn!("error playing feedback: {}", e);
+        }
+        true
+    }
+
+    /// Unlocks the door for a granted credential and counts the use of
+    /// visitor codes
+    fn open(&self, reason: Reason, code: CodeHash) {
+        if reason == Reason::Granted {
+            self.door_tx.send(DoorCommand::Unlock(None)).unwrap();
+            if let Err(e) = self.user_db.count_use(code) {
+                log::error!("error counting visitor use: {}", e);
+            }
+        }
+    }
+
+    fn feedback(&mut self, success: bool) {
+        if let Err(e) = keypad_feedback(success, &mut self.signal_driver) {
+            log::warn!("error playing feedback: {}", e);
+        }
+    }
+}
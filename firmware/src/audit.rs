@@ -0,0 +1,140 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+use anyhow::Context;
+use doorsys_protocol::Audit;
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+
+/// Audits kept while the broker is unreachable, the oldest are dropped first.
+///
+/// The 64 KB nvs partition holds 15 usable pages of 126 entries. A full code
+/// store takes about 850 of them and the settings, certificates and wifi
+/// driver up to 300 more. An audit is a blob of 3 entries, so 192 of them
+/// fill 576 entries and leave room for the store to rewrite its pages.
+const CAPACITY: u32 = 192;
+const HEAD_KEY: &str = "head";
+const TAIL_KEY: &str = "tail";
+/// Records overwritten before they were published, reported once the log drains
+const DROPPED_KEY: &str = "dropped";
+
+/// Persistent ring buffer of encoded audit records waiting to be published.
+///
+/// `head` is the sequence of the next record written and `tail` the oldest one
+/// not yet acknowledged by the broker. Records live under `a{seq % CAPACITY}`.
+#[derive(Clone)]
+pub struct AuditLog(Arc<(Mutex<AuditData>, Condvar)>);
+
+struct AuditData {
+    nvs: EspNvs<NvsDefault>,
+    head: u32,
+    tail: u32,
+    dropped: u32,
+}
+
+fn entry_key(seq: u32) -> String {
+    format!("a{}", seq % CAPACITY)
+}
+
+impl AuditLog {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> anyhow::Result<Self> {
+        let head = nvs.get_u32(HEAD_KEY)?.unwrap_or(0);
+        let tail = nvs.get_u32(TAIL_KEY)?.unwrap_or(0);
+        let dropped = nvs.get_u32(DROPPED_KEY)?.unwrap_or(0);
+        log::info!("Audit log has {} pending records", head.wrapping_sub(tail));
+        let data = AuditData {
+            nvs,
+            head,
+            tail,
+            dropped,
+        };
+        Ok(AuditLog(Arc::new((Mutex::new(data), Condvar::new()))))
+    }
+
+    /// Stores the audit, overwriting the oldest record when full
+    pub fn push(&self, audit: &Audit) -> anyhow::Result<()> {
+        let buf = doorsys_protocol::encode(audit).context("encoding failure")?;
+        let (lock, cvar) = &*self.0;
+        let mut data = lock.lock().unwrap();
+        if data.head.wrapping_sub(data.tail) >= CAPACITY {
+            log::warn!("Audit log full, dropping record {}", data.tail);
+            data.tail = data.tail.wrapping_add(1);
+            data.nvs
+                .set_u32(TAIL_KEY, data.tail)
+                .context("nvs failure")?;
+            data.dropped += 1;
+            data.nvs
+                .set_u32(DROPPED_KEY, data.dropped)
+                .context("nvs failure")?;
+        }
+        // The record only counts once head moves past it
+        data.nvs
+            .set_raw(&entry_key(data.head), &buf)
+            .context("nvs failure")?;
+        data.head = data.head.wrapping_add(1);
+        data.nvs
+            .set_u32(HEAD_KEY, data.head)
+            .context("nvs failure")?;
+        cvar.notify_all();
+        Ok(())
+    }
+
+    /// Blocks until a record is available and returns the oldest one
+    pub fn next(&self) -> (u32, Vec<u8>) {
+        let (lock, cvar) = &*self.0;
+        let mut data = lock.lock().unwrap();
+        loop {
+            while data.head == data.tail {
+                data = cvar.wait(data).unwrap();
+            }
+            let seq = data.tail;
+            match read_entry(&data.nvs, seq) {
+                Ok(buf) => return (seq, buf),
+                Err(e) => {
+                    log::error!("Skipping unreadable audit record {}: {:#}", seq, e);
+                    if let Err(e) = advance(&mut data, seq) {
+                        log::error!("error discarding audit record: {:#}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns and clears the count of records dropped while the log was full
+    pub fn take_dropped(&self) -> anyhow::Result<u32> {
+        let (lock, _) = &*self.0;
+        let mut data = lock.lock().unwrap();
+        let dropped = data.dropped;
+        if dropped > 0 {
+            data.nvs.remove(DROPPED_KEY).context("nvs failure")?;
+            data.dropped = 0;
+        }
+        Ok(dropped)
+    }
+
+    /// Discards the record once the broker acknowledged it
+    pub fn remove(&self, seq: u32) -> anyhow::Result<()> {
+        let (lock, _) = &*self.0;
+        let mut data = lock.lock().unwrap();
+        // The record may have been overwritten while it was in flight
+        if data.tail == seq {
+            advance(&mut data, seq)?;
+        }
+        Ok(())
+    }
+}
+
+fn read_entry(nvs: &EspNvs<NvsDefault>, seq: u32) -> anyhow::Result<Vec<u8>> {
+    let key = entry_key(seq);
+    let blob_size = nvs.blob_len(&key)?.context("record missing")?;
+    let mut buf = vec![0; blob_size];
+    nvs.get_raw(&key, &mut buf)?.context("record missing")?;
+    Ok(buf)
+}
+
+fn advance(data: &mut AuditData, seq: u32) -> anyhow::Result<()> {
+    data.nvs.remove(&entry_key(seq)).context("nvs failure")?;
+    data.tail = seq.wrapping_add(1);
+    data.nvs
+        .set_u32(TAIL_KEY, data.tail)
+        .context("nvs failure")?;
+    Ok(())
+}
@@ -1,17 +1,26 @@
 use std::{ffi::c_void, ptr};
 
+use doorsys_protocol::ButtonConfig;
+use doorsys_wiegand::button::Settings;
+
 use esp_idf_svc::sys::{
-    esp, gpio_config, gpio_config_t, gpio_get_level, gpio_int_type_t_GPIO_INTR_NEGEDGE,
+    esp, gpio_config, gpio_config_t, gpio_get_level, gpio_int_type_t_GPIO_INTR_ANYEDGE,
     gpio_isr_handler_add, gpio_mode_t_GPIO_MODE_INPUT, vQueueDelete, xQueueGenericCreate,
     xQueueGiveFromISR, xQueueReceive, QueueDefinition,
 };
 
+/// Wakes the button thread on both edges, the level is debounced there
+pub fn settings(config: &ButtonConfig) -> Settings {
+    Settings {
+        long_press: config.long_press.as_millis() as u64,
+        ..Default::default()
+    }
+}
+
 #[link_section = ".iram0.text"]
 unsafe extern "C" fn button_interrupt(arg: *mut c_void) {
     let button = &mut *(arg as *mut Button);
-    if gpio_get_level(button.gpio) == 0 {
-        xQueueGiveFromISR(button.queue, ptr::null_mut());
-    }
+    xQueueGiveFromISR(button.queue, ptr::null_mut());
 }
 
 pub struct Button {
@@ -31,7 +40,7 @@ impl Button {
             mode: gpio_mode_t_GPIO_MODE_INPUT,
             pull_up_en: false.into(),
             pull_down_en: false.into(),
-            intr_type: gpio_int_type_t_GPIO_INTR_NEGEDGE,
+            intr_type: gpio_int_type_t_GPIO_INTR_ANYEDGE,
         };
 
         unsafe {
@@ -49,10 +58,16 @@ impl Button {
         Ok(())
     }
 
-    pub fn wait_for_press(&self) -> bool {
+    /// Waits for the level to change, returns `false` on timeout
+    pub fn wait_for_edge(&self) -> bool {
         let res = unsafe { xQueueReceive(self.queue, ptr::null_mut(), 10000) };
         res == 1
     }
+
+    /// The button pulls the line low while pressed
+    pub fn is_pressed(&self) -> bool {
+        unsafe { gpio_get_level(self.gpio) == 0 }
+    }
 }
 
 impl Drop for Button {
@@ -0,0 +1,46 @@
+use std::{
+    env,
+    mem::MaybeUninit,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use esp_idf_svc::sys::{localtime_r, time_t, tm, tzset};
+
+/// Anything earlier means SNTP hasn't set the clock yet (2024-01-01)
+const MIN_VALID_TIME: u64 = 1_704_067_200;
+
+/// Serializes changes to the process wide TZ variable
+static TZ_LOCK: Mutex<()> = Mutex::new(());
+
+/// Whether SNTP has set the clock
+pub fn is_synchronized(now: SystemTime) -> bool {
+    now.duration_since(UNIX_EPOCH)
+        .is_ok_and(|d| d.as_secs() >= MIN_VALID_TIME)
+}
+
+/// Converts the time into weekday (0 is Sunday) and minute of the day in the
+/// POSIX `timezone`. Returns `None` while the clock is not synchronized.
+pub fn local_time(now: SystemTime, timezone: &str) -> Option<(u8, u16)> {
+    if !is_synchronized(now) {
+        log::warn!("Clock not synchronized, can't evaluate schedules");
+        return None;
+    }
+    let secs = now.duration_since(UNIX_EPOCH).ok()?.as_secs();
+
+    let _guard = TZ_LOCK.lock().unwrap();
+    env::set_var("TZ", timezone);
+    let time = secs as time_t;
+    let mut local = MaybeUninit::<tm>::uninit();
+    let local = unsafe {
+        tzset();
+        if localtime_r(&time, local.as_mut_ptr()).is_null() {
+            return None;
+        }
+        local.assume_init()
+    };
+    Some((
+        local.tm_wday as u8,
+        (local.tm_hour * 60 + local.tm_min) as u16,
+    ))
+}
@@ -0,0 +1,528 @@
+use std::{
+    io::{self, BufRead, ErrorKind},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use bincode::{Decode, Encode};
+use doorsys_protocol::{
+    ButtonConfig, DoorSensorConfig, KeypadConfig, LockoutConfig, NetworkConfig, RelayConfig,
+    SecurityMode, TlsConfig,
+};
+use doorsys_wiegand::lockout::Saved;
+use esp_idf_svc::{
+    hal::reset,
+    nvs::{EspNvs, NvsDefault},
+};
+
+const WIFI_SSID: &str = "wifi_ssid";
+const WIFI_PASS: &str = "wifi_pass";
+const MQTT_URL: &str = "mqtt_url";
+const MQTT_USER: &str = "mqtt_user";
+const MQTT_PASS: &str = "mqtt_pass";
+const KEYS: [&str; 5] = [WIFI_SSID, WIFI_PASS, MQTT_URL, MQTT_USER, MQTT_PASS];
+/// CA or pinned server certificate, the public CA bundle is used when missing
+const CA_CERT: &str = "ca_cert";
+const CLIENT_CERT: &str = "client_cert";
+const CLIENT_KEY: &str = "client_key";
+/// PEM blobs, stored with the NUL terminator mbedtls expects
+const TLS_KEYS: [&str; 3] = [CA_CERT, CLIENT_CERT, CLIENT_KEY];
+const KEYPAD_KEY: &str = "keypad";
+const SECURITY_KEY: &str = "security";
+const LOCKOUT_KEY: &str = "lockout";
+const DOOR_SENSOR_KEY: &str = "door_sensor";
+const RELAY_KEY: &str = "relay";
+const BUTTON_KEY: &str = "button";
+/// Keypad failures and lock from before a restart
+const LOCKOUT_STATE_KEY: &str = "lockout_state";
+/// Set while rotated credentials wait for their first broker connection
+const PENDING_KEY: &str = "p_pending";
+const MAX_VALUE_LEN: usize = 256;
+/// Time rotated credentials get to reach the broker before the previous ones are restored
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(180);
+const CONSOLE_POLL: Duration = Duration::from_millis(100);
+
+/// Credentials the device uses to reach the network and the broker
+#[derive(Debug, Clone)]
+pub struct NetConfig {
+    pub wifi_ssid: String,
+    pub wifi_pass: String,
+    pub mqtt_url: String,
+    pub mqtt_user: String,
+    pub mqtt_pass: String,
+    pub ca_cert: Option<Vec<u8>>,
+    pub client_cert: Option<Vec<u8>>,
+    pub client_key: Option<Vec<u8>>,
+}
+
+/// Key holding the last known good value while new credentials are pending
+fn previous_key(key: &str) -> String {
+    format!("p_{key}")
+}
+
+fn network_config_values(config: NetworkConfig) -> [(&'static str, Option<String>); 5] {
+    [
+        (WIFI_SSID, config.wifi_ssid),
+        (WIFI_PASS, config.wifi_pass),
+        (MQTT_URL, config.mqtt_url),
+        (MQTT_USER, config.mqtt_user),
+        (MQTT_PASS, config.mqtt_pass),
+    ]
+}
+
+fn tls_config_values(config: TlsConfig) -> [(&'static str, Option<String>); 3] {
+    [
+        (CA_CERT, config.ca_cert),
+        (CLIENT_CERT, config.client_cert),
+        (CLIENT_KEY, config.client_key),
+    ]
+}
+
+const BINCODE_CONFIG: bincode::config::Configuration = bincode::config::standard();
+
+/// Device configuration kept in the doorsys NVS namespace
+#[derive(Clone)]
+pub struct ConfigStore {
+    nvs: Arc<Mutex<EspNvs<NvsDefault>>>,
+    /// Read on every key press, kept in memory
+    keypad: Arc<Mutex<KeypadConfig>>,
+    security: Arc<Mutex<SecurityMode>>,
+    lockout: Arc<Mutex<LockoutConfig>>,
+    door_sensor: Arc<Mutex<DoorSensorConfig>>,
+    relay: Arc<Mutex<RelayConfig>>,
+    button: Arc<Mutex<ButtonConfig>>,
+}
+
+fn get(nvs: &EspNvs<NvsDefault>, key: &str) -> anyhow::Result<Option<String>> {
+    let mut buf = [0; MAX_VALUE_LEN];
+    let value = nvs.get_str(key, &mut buf).context("nvs failure")?;
+    Ok(value.map(String::from))
+}
+
+fn get_pem(nvs: &EspNvs<NvsDefault>, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(len) = nvs.blob_len(key).context("nvs failure")? else {
+        return Ok(None);
+    };
+    let mut buf = vec![0; len];
+    let pem = nvs.get_blob(key, &mut buf).context("nvs failure")?;
+    Ok(pem.map(<[u8]>::to_vec))
+}
+
+/// Loads a bincode encoded setting, falling back to the default when it is
+/// missing or unreadable
+fn load_setting<T: Decode<()> + Default>(nvs: &EspNvs<NvsDefault>, key: &str) -> T {
+    let load = || -> anyhow::Result<Option<T>> {
+        let Some(len) = nvs.blob_len(key).context("nvs failure")? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0; len];
+        let Some(blob) = nvs.get_blob(key, &mut buf).context("nvs failure")? else {
+            return Ok(None);
+        };
+        let (value, _) =
+            bincode::decode_from_slice(blob, BINCODE_CONFIG).context("decoding failure")?;
+        Ok(Some(value))
+    };
+    match load() {
+        Ok(value) => value.unwrap_or_default(),
+        Err(e) => {
+            log::error!("error loading {}, using defaults: {:#}", key, e);
+            T::default()
+        }
+    }
+}
+
+fn store_setting<T: Encode>(
+    nvs: &mut EspNvs<NvsDefault>,
+    key: &str,
+    value: &T,
+) -> anyhow::Result<()> {
+    let buf = bincode::encode_to_vec(value, BINCODE_CONFIG).context("encoding failure")?;
+    nvs.set_blob(key, &buf).context("nvs failure")?;
+    Ok(())
+}
+
+fn apply(nvs: &mut EspNvs<NvsDefault>, config: NetworkConfig) -> anyhow::Result<()> {
+    for (key, value) in network_config_values(config) {
+        if let Some(value) = value {
+            nvs.set_str(key, &value).context("nvs failure")?;
+        }
+    }
+    Ok(())
+}
+
+/// Stores the certificates, an empty value removes the stored one
+fn apply_tls(nvs: &mut EspNvs<NvsDefault>, config: TlsConfig) -> anyhow::Result<()> {
+    for (key, value) in tls_config_values(config) {
+        match value.as_deref().map(str::trim) {
+            Some("") => {
+                nvs.remove(key).context("nvs failure")?;
+            }
+            Some(pem) => {
+                let mut blob = pem.as_bytes().to_vec();
+                blob.push(0);
+                nvs.set_blob(key, &blob).context("nvs failure")?;
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// Keeps the current settings around until the new ones are confirmed
+fn snapshot(nvs: &mut EspNvs<NvsDefault>) -> anyhow::Result<()> {
+    // A previous rotation never connected, the snapshot is still the last good one
+    if nvs.contains(PENDING_KEY)? {
+        return Ok(());
+    }
+    for key in KEYS {
+        if let Some(value) = get(nvs, key)? {
+            nvs.set_str(&previous_key(key), &value)
+                .context("nvs failure")?;
+        }
+    }
+    for key in TLS_KEYS {
+        if let Some(pem) = get_pem(nvs, key)? {
+            nvs.set_blob(&previous_key(key), &pem)
+                .context("nvs failure")?;
+        }
+    }
+    nvs.set_u8(PENDING_KEY, 1).context("nvs failure")?;
+    Ok(())
+}
+
+impl ConfigStore {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Self {
+        let keypad = load_setting::<KeypadConfig>(&nvs, KEYPAD_KEY);
+        let keypad = match keypad.validate() {
+            Some(msg) => {
+                log::error!("invalid keypad settings, using defaults: {}", msg);
+                KeypadConfig::default()
+            }
+            None => keypad,
+        };
+        let security = load_setting(&nvs, SECURITY_KEY);
+        let lockout = load_setting(&nvs, LOCKOUT_KEY);
+        let door_sensor = load_setting(&nvs, DOOR_SENSOR_KEY);
+        let relay = load_setting(&nvs, RELAY_KEY);
+        let button = load_setting(&nvs, BUTTON_KEY);
+        ConfigStore {
+            nvs: Arc::new(Mutex::new(nvs)),
+            keypad: Arc::new(Mutex::new(keypad)),
+            security: Arc::new(Mutex::new(security)),
+            lockout: Arc::new(Mutex::new(lockout)),
+            door_sensor: Arc::new(Mutex::new(door_sensor)),
+            relay: Arc::new(Mutex::new(relay)),
+            button: Arc::new(Mutex::new(button)),
+        }
+    }
+
+    pub fn keypad(&self) -> KeypadConfig {
+        self.keypad.lock().unwrap().clone()
+    }
+
+    /// Stores the settings unless they would leave the keypad unusable
+    pub fn set_keypad(&self, config: KeypadConfig) -> anyhow::Result<()> {
+        if let Some(msg) = config.validate() {
+            bail!("invalid keypad settings: {msg}");
+        }
+        store_setting(&mut self.nvs.lock().unwrap(), KEYPAD_KEY, &config)?;
+        *self.keypad.lock().unwrap() = config;
+        Ok(())
+    }
+
+    pub fn security(&self) -> SecurityMode {
+        *self.security.lock().unwrap()
+    }
+
+    pub fn set_security(&self, mode: SecurityMode) -> anyhow::Result<()> {
+        store_setting(&mut self.nvs.lock().unwrap(), SECURITY_KEY, &mode)?;
+        *self.security.lock().unwrap() = mode;
+        Ok(())
+    }
+
+    pub fn lockout(&self) -> LockoutConfig {
+        self.lockout.lock().unwrap().clone()
+    }
+
+    pub fn set_lockout(&self, config: LockoutConfig) -> anyhow::Result<()> {
+        store_setting(&mut self.nvs.lock().unwrap(), LOCKOUT_KEY, &config)?;
+        *self.lockout.lock().unwrap() = config;
+        Ok(())
+    }
+
+    /// Keypad lockout saved before the restart, a missing or unreadable one
+    /// starts clear
+    pub fn lockout_state(&self) -> Saved {
+        match self.nvs.lock().unwrap().get_u16(LOCKOUT_STATE_KEY) {
+            Ok(Some(value)) => Saved {
+                failures: value as u8,
+                locked: value >> 8 != 0,
+            },
+            Ok(None) => Saved::default(),
+            Err(e) => {
+                log::error!("error loading lockout state: {}", e);
+                Saved::default()
+            }
+        }
+    }
+
+    pub fn set_lockout_state(&self, saved: Saved) -> anyhow::Result<()> {
+        let value = saved.failures as u16 | (saved.locked as u16) << 8;
+        self.nvs
+            .lock()
+            .unwrap()
+            .set_u16(LOCKOUT_STATE_KEY, value)
+            .context("nvs failure")?;
+        Ok(())
+    }
+
+    pub fn door_sensor(&self) -> DoorSensorConfig {
+        self.door_sensor.lock().unwrap().clone()
+    }
+
+    pub fn set_door_sensor(&self, config: DoorSensorConfig) -> anyhow::Result<()> {
+        store_setting(&mut self.nvs.lock().unwrap(), DOOR_SENSOR_KEY, &config)?;
+        *self.door_sensor.lock().unwrap() = config;
+        Ok(())
+    }
+
+    pub fn relay(&self) -> RelayConfig {
+        self.relay.lock().unwrap().clone()
+    }
+
+    pub fn set_relay(&self, config: RelayConfig) -> anyhow::Result<()> {
+        store_setting(&mut self.nvs.lock().unwrap(), RELAY_KEY, &config)?;
+        *self.relay.lock().unwrap() = config;
+        Ok(())
+    }
+
+    pub fn button(&self) -> ButtonConfig {
+        self.button.lock().unwrap().clone()
+    }
+
+    pub fn set_button(&self, config: ButtonConfig) -> anyhow::Result<()> {
+        store_setting(&mut self.nvs.lock().unwrap(), BUTTON_KEY, &config)?;
+        *self.button.lock().unwrap() = config;
+        Ok(())
+    }
+
+    /// Loads the provisioned credentials, nothing is compiled into the images
+    /// served for OTA updates. Returns `None` when the ssid or broker url is missing.
+    pub fn load(&self) -> anyhow::Result<Option<NetConfig>> {
+        let nvs = self.nvs.lock().unwrap();
+        let (Some(wifi_ssid), Some(mqtt_url)) = (get(&nvs, WIFI_SSID)?, get(&nvs, MQTT_URL)?)
+        else {
+            return Ok(None);
+        };
+        Ok(Some(NetConfig {
+            wifi_ssid,
+            mqtt_url,
+            wifi_pass: get(&nvs, WIFI_PASS)?.unwrap_or_default(),
+            mqtt_user: get(&nvs, MQTT_USER)?.unwrap_or_default(),
+            mqtt_pass: get(&nvs, MQTT_PASS)?.unwrap_or_default(),
+            ca_cert: get_pem(&nvs, CA_CERT)?,
+            client_cert: get_pem(&nvs, CLIENT_CERT)?,
+            client_key: get_pem(&nvs, CLIENT_KEY)?,
+        }))
+    }
+
+    /// Stores new credentials keeping the current ones until they are confirmed
+    pub fn rotate(&self, config: NetworkConfig) -> anyhow::Result<()> {
+        let mut nvs = self.nvs.lock().unwrap();
+        snapshot(&mut nvs)?;
+        apply(&mut nvs, config)
+    }
+
+    /// Stores new certificates keeping the current ones until they are confirmed
+    pub fn rotate_tls(&self, config: TlsConfig) -> anyhow::Result<()> {
+        let mut nvs = self.nvs.lock().unwrap();
+        snapshot(&mut nvs)?;
+        apply_tls(&mut nvs, config)
+    }
+
+    pub fn has_pending(&self) -> bool {
+        let nvs = self.nvs.lock().unwrap();
+        nvs.contains(PENDING_KEY).unwrap_or(false)
+    }
+
+    /// Drops the previous credentials once the new ones reached the broker
+    pub fn confirm(&self) -> anyhow::Result<()> {
+        let mut nvs = self.nvs.lock().unwrap();
+        if !nvs.contains(PENDING_KEY)? {
+            return Ok(());
+        }
+        for key in KEYS.iter().chain(&TLS_KEYS) {
+            nvs.remove(&previous_key(key)).context("nvs failure")?;
+        }
+        nvs.remove(PENDING_KEY).context("nvs failure")?;
+        log::info!("New network credentials confirmed");
+        Ok(())
+    }
+
+    /// Restores the credentials in use before the last rotation
+    pub fn rollback(&self) -> anyhow::Result<bool> {
+        let mut nvs = self.nvs.lock().unwrap();
+        if !nvs.contains(PENDING_KEY)? {
+            return Ok(false);
+        }
+        for key in KEYS {
+            let previous = previous_key(key);
+            if let Some(value) = get(&nvs, &previous)? {
+                nvs.set_str(key, &value).context("nvs failure")?;
+            }
+            nvs.remove(&previous).context("nvs failure")?;
+        }
+        // Certificates missing from the snapshot weren't installed before the rotation
+        for key in TLS_KEYS {
+            let previous = previous_key(key);
+            match get_pem(&nvs, &previous)? {
+                Some(pem) => {
+                    nvs.set_blob(key, &pem).context("nvs failure")?;
+                }
+                None => {
+                    nvs.remove(key).context("nvs failure")?;
+                }
+            }
+            nvs.remove(&previous).context("nvs failure")?;
+        }
+        nvs.remove(PENDING_KEY).context("nvs failure")?;
+        Ok(true)
+    }
+
+    fn save(&self, config: NetworkConfig, tls: TlsConfig) -> anyhow::Result<()> {
+        let mut nvs = self.nvs.lock().unwrap();
+        apply(&mut nvs, config)?;
+        apply_tls(&mut nvs, tls)
+    }
+}
+
+/// Restores the previous credentials if rotated ones don't reach the broker in time
+pub fn setup_rollback_watchdog(store: ConfigStore) {
+    if !store.has_pending() {
+        return;
+    }
+    log::warn!("Network credentials pending confirmation");
+    thread::spawn(move || {
+        thread::sleep(CONFIRM_TIMEOUT);
+        match store.rollback() {
+            Ok(true) => {
+                log::error!("New credentials never reached the broker, restoring previous ones");
+                reset::restart();
+            }
+            Ok(false) => {}
+            Err(e) => log::error!("error restoring credentials: {:#}", e),
+        }
+    });
+}
+
+/// Reads what was typed on the console so far, returns whether `line` holds
+/// a whole line. The console doesn't block waiting for input.
+fn poll_line(line: &mut String) -> bool {
+    match io::stdin().lock().read_line(line) {
+        Ok(_) if line.ends_with('\n') => true,
+        Ok(_) => {
+            thread::sleep(CONSOLE_POLL);
+            false
+        }
+        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+            thread::sleep(CONSOLE_POLL);
+            false
+        }
+        Err(e) => {
+            log::error!("error reading console: {}", e);
+            line.clear();
+            false
+        }
+    }
+}
+
+/// Enters the provisioning console when `provision` is typed on the serial
+/// console, the way back in needs a cable on the board
+pub fn setup_console(store: ConfigStore) {
+    thread::spawn(move || {
+        let mut line = String::new();
+        loop {
+            if !poll_line(&mut line) {
+                continue;
+            }
+            if line.trim() == "provision" {
+                provision(&store);
+            }
+            line.clear();
+        }
+    });
+}
+
+/// Serial console used when the device has no credentials or when asked for
+/// on the console.
+///
+/// Takes `<key>=<value>` lines and `save` to store them and restart.
+/// Certificates are pasted as PEM on the lines following `<key>=`.
+pub fn provision(store: &ConfigStore) -> ! {
+    log::warn!("Entering provisioning mode");
+    println!(
+        "Provisioning mode, set {} as <key>=<value> and type save",
+        KEYS.iter()
+            .chain(&TLS_KEYS)
+            .copied()
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let mut config = NetworkConfig::default();
+    let mut tls = TlsConfig::default();
+    // Certificate being pasted and the lines read so far
+    let mut pem: Option<(&str, String)> = None;
+    let mut line = String::new();
+    loop {
+        if !poll_line(&mut line) {
+            continue;
+        }
+
+        let command = line.trim();
+        if let Some((key, buf)) = &mut pem {
+            buf.push_str(command);
+            buf.push('\n');
+            if command.starts_with("-----END") {
+                let value = Some(std::mem::take(buf));
+                match *key {
+                    CA_CERT => tls.ca_cert = value,
+                    CLIENT_CERT => tls.client_cert = value,
+                    _ => tls.client_key = value,
+                }
+                pem = None;
+            }
+        } else if command == "save" {
+            let result = store
+                .save(std::mem::take(&mut config), std::mem::take(&mut tls))
+                .and_then(|_| store.load());
+            match result {
+                Ok(Some(_)) => {
+                    println!("Credentials saved, restarting");
+                    reset::restart();
+                }
+                Ok(None) => println!("{WIFI_SSID} and {MQTT_URL} are required"),
+                Err(e) => println!("error saving credentials: {:#}", e),
+            }
+        } else if let Some((key, value)) = command.split_once('=') {
+            let value = Some(value.trim().to_string());
+            match key.trim() {
+                WIFI_SSID => config.wifi_ssid = value,
+                WIFI_PASS => config.wifi_pass = value,
+                MQTT_URL => config.mqtt_url = value,
+                MQTT_USER => config.mqtt_user = value,
+                MQTT_PASS => config.mqtt_pass = value,
+                key => match TLS_KEYS.iter().find(|k| **k == key) {
+                    Some(key) => pem = Some((key, String::new())),
+                    None => println!("unknown setting {key}"),
+                },
+            }
+        } else if !command.is_empty() {
+            println!("unknown command {command}");
+        }
+        line.clear();
+    }
+}
@@ -1,22 +1,169 @@
-use esp_idf_svc::hal::gpio::{Output, OutputPin, PinDriver};
+use std::{
+    sync::mpsc::{Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant, SystemTime},
+};
 
-pub struct Door<'d, T: OutputPin> {
-    driver: PinDriver<'d, T, Output>,
+use doorsys_protocol::{Alert, AlertKind, DoorSensorConfig, RelayConfig};
+use doorsys_wiegand::door::{Door as Position, Settings, State};
+use esp_idf_svc::hal::gpio::{Input, InputPin, Level, Output, OutputPin, PinDriver, Pull};
+
+use crate::config::ConfigStore;
+
+/// How often the door sensor is read
+const SENSOR_POLL: Duration = Duration::from_millis(50);
+
+fn settings(config: &DoorSensorConfig) -> Settings {
+    Settings {
+        held_open: config.held_open.as_millis() as u64,
+    }
+}
+
+/// Requests served by the door thread
+#[derive(Debug)]
+pub enum DoorCommand {
+    /// Unlocks the door, `None` uses the configured unlock time
+    Unlock(Option<Duration>),
+    /// Keeps the door unlocked until released
+    Hold,
+    Release,
+}
+
+/// Output level holding the lock released or locked
+fn level(config: &RelayConfig, unlocked: bool) -> Level {
+    // Fail safe locks are powered to stay locked
+    let energized = unlocked != config.fail_safe;
+    if energized != config.active_low {
+        Level::High
+    } else {
+        Level::Low
+    }
+}
+
+/// Drives the relay from the unlock requests and the door position sensor.
+///
+/// The sensor closes to ground while the door is closed, it needs a pin with
+/// a pull up.
+pub struct Door<'d, T: OutputPin, S: InputPin + OutputPin> {
+    relay: PinDriver<'d, T, Output>,
+    sensor: PinDriver<'d, S, Input>,
+    position: Position,
+    /// Settings the relay is currently driven with
+    relay_config: RelayConfig,
+    /// Previous sensor reading, a reading counts once two polls agree
+    reading: Option<bool>,
+    config_store: ConfigStore,
+    alert_tx: Sender<Alert>,
 }
 
-impl<T: OutputPin> Door<'_, T> {
-    pub fn new(pin: T) -> anyhow::Result<Self> {
-        let driver = PinDriver::output(pin)?;
-        Ok(Door { driver })
+impl<T: OutputPin, S: InputPin + OutputPin> Door<'_, T, S> {
+    pub fn new(
+        relay_pin: T,
+        sensor_pin: S,
+        config_store: ConfigStore,
+        alert_tx: Sender<Alert>,
+    ) -> anyhow::Result<Self> {
+        // Locks right away, fail safe locks release until the pin is driven
+        let relay_config = config_store.relay();
+        let mut relay = PinDriver::output(relay_pin)?;
+        relay.set_level(level(&relay_config, false))?;
+        let mut sensor = PinDriver::input(sensor_pin)?;
+        sensor.set_pull(Pull::Up)?;
+        Ok(Door {
+            relay,
+            sensor,
+            position: Default::default(),
+            relay_config,
+            reading: None,
+            config_store,
+            alert_tx,
+        })
+    }
+
+    /// Serves the door commands until the channel closes, the latest unlock
+    /// sets the time the door stays unlocked
+    pub fn run(&mut self, door_rx: Receiver<DoorCommand>) {
+        let started = Instant::now();
+        loop {
+            let request = door_rx.recv_timeout(SENSOR_POLL);
+            let now = started.elapsed().as_millis() as u64;
+            let relay_config = self.config_store.relay();
+            if relay_config != self.relay_config {
+                log::info!("Relay settings changed to {:?}", relay_config);
+                self.relay_config = relay_config;
+                self.drive_relay();
+            }
+            let config = self.config_store.door_sensor();
+            match request {
+                Ok(DoorCommand::Unlock(delay)) => {
+                    let delay = delay.unwrap_or(self.relay_config.unlock_time);
+                    let delay = delay.as_millis() as u64;
+                    self.update(&config, |position| position.unlock(now, delay));
+                }
+                Ok(DoorCommand::Hold) => self.update(&config, |position| position.hold()),
+                Ok(DoorCommand::Release) => {
+                    let settings = settings(&config);
+                    self.update(&config, |position| position.release(now, &settings));
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            // Without a sensor the door is taken as closed and the relay times out
+            let closed = if config.enabled {
+                self.read_sensor()
+            } else {
+                Some(true)
+            };
+            if let Some(closed) = closed {
+                let settings = settings(&config);
+                self.update(&config, |position| position.sense(closed, now, &settings));
+            }
+            self.update(&config, |position| position.poll(now));
+        }
+    }
+
+    /// Returns whether the door is closed once two readings agree
+    fn read_sensor(&mut self) -> Option<bool> {
+        let closed = self.sensor.is_low();
+        let previous = self.reading.replace(closed);
+        (previous == Some(closed)).then_some(closed)
     }
 
-    pub fn open(&mut self) -> anyhow::Result<()> {
-        self.driver.set_high()?;
-        Ok(())
+    fn drive_relay(&mut self) {
+        let unlocked = self.position.state().unlocked();
+        if let Err(e) = self.relay.set_level(level(&self.relay_config, unlocked)) {
+            log::error!("error driving the relay: {}", e);
+        }
     }
 
-    pub fn close(&mut self) -> anyhow::Result<()> {
-        self.driver.set_low()?;
-        Ok(())
+    /// Applies a change of the door state to the relay and raises the alerts
+    fn update(
+        &mut self,
+        config: &DoorSensorConfig,
+        change: impl FnOnce(&mut Position) -> Option<State>,
+    ) {
+        let previous = self.position.state();
+        let Some(state) = change(&mut self.position) else {
+            return;
+        };
+        log::info!("Door {:?}", state);
+        self.drive_relay();
+
+        let kind = match (previous, state) {
+            (_, State::Forced) => AlertKind::DoorForced,
+            (_, State::HeldOpen) => AlertKind::DoorHeldOpen {
+                held_open: config.held_open,
+            },
+            (State::Forced | State::HeldOpen, State::Locked | State::Unlocked) => {
+                AlertKind::DoorClosed
+            }
+            _ => return,
+        };
+        let alert = Alert {
+            timestamp: SystemTime::now(),
+            kind,
+        };
+        if let Err(e) = self.alert_tx.send(alert) {
+            log::error!("error sending alert: {}", e);
+        }
     }
 }
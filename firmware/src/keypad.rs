@@ -0,0 +1,71 @@
+//! Pin entry on the Wiegand keypad
+
+use std::time::Duration;
+
+use doorsys_protocol::{KeypadConfig, KeypadMode, LockoutConfig, Reason};
+use doorsys_wiegand::{keypad::Settings, lockout};
+
+pub use doorsys_wiegand::keypad::{AbortReason, Entry, Keypad};
+
+const PIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Frame size of the key presses in the given mode, burst keypads don't send keys
+pub fn key_bits(mode: KeypadMode) -> Option<usize> {
+    match mode {
+        KeypadMode::FourBit => Some(4),
+        KeypadMode::EightBit => Some(8),
+        KeypadMode::Burst => None,
+    }
+}
+
+pub fn settings(config: &KeypadConfig) -> Settings {
+    Settings {
+        min_pin_length: config.min_pin_length,
+        max_pin_length: config.max_pin_length,
+        submit_key: config.submit_key,
+        cancel_key: config.cancel_key,
+        auto_submit: config.auto_submit,
+        timeout: PIN_TIMEOUT.as_millis() as u64,
+    }
+}
+
+/// Reason recorded in the audit of a dropped entry
+pub fn abort_reason(reason: AbortReason) -> Reason {
+    match reason {
+        AbortReason::TooShort | AbortReason::TimedOut => Reason::IncompletePin,
+        AbortReason::TooLong => Reason::PinTooLong,
+        AbortReason::Cancelled => Reason::Cancelled,
+    }
+}
+
+/// Card waiting for its holder's pin
+#[derive(Debug)]
+pub struct PendingCard {
+    pub number: i32,
+    pub facility: u32,
+    /// Time of the read in milliseconds
+    since: u64,
+}
+
+impl PendingCard {
+    pub fn new(number: i32, facility: u32, now: u64) -> Self {
+        PendingCard {
+            number,
+            facility,
+            since: now,
+        }
+    }
+
+    /// The pin has as long as a pin entry to follow the card
+    pub fn expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.since) >= PIN_TIMEOUT.as_millis() as u64
+    }
+}
+
+pub fn lockout_settings(config: &LockoutConfig) -> lockout::Settings {
+    lockout::Settings {
+        max_failures: config.max_failures,
+        window: config.window.as_millis() as u64,
+        duration: config.duration.as_millis() as u64,
+    }
+}
@@ -1,15 +1,22 @@
 // Reference: https://docs.espressif.com/projects/esp-idf/en/latest/esp32/api-reference/system/freertos.html
 
+mod access;
+mod audit;
 mod buttons;
+mod clock;
+mod config;
 mod door;
+mod keypad;
 mod mqtt;
 mod network;
+mod ota;
 mod user;
 mod wiegand;
 
-use doorsys_protocol::{Audit, CodeType};
+use doorsys_protocol::{Alert, AlertKind, Audit, CodeType, LongPress, Reason, ResyncRequest};
+use doorsys_wiegand::button::{Debouncer, Press};
 use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::hal::gpio::{Output, OutputPin, PinDriver};
+use esp_idf_svc::hal::gpio::{InputPin, OutputPin, PinDriver};
 use esp_idf_svc::hal::prelude::Peripherals;
 use esp_idf_svc::mqtt::client::QoS;
 use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
@@ -24,185 +31,208 @@ use std::mem;
 use std::ptr;
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 use std::{thread, time::Duration};
-use wiegand::Packet;
 
+use crate::access::Access;
+use crate::audit::AuditLog;
 use crate::buttons::Button;
+use crate::config::ConfigStore;
+use crate::door::DoorCommand;
 use crate::user::UserDB;
 use crate::wiegand::Reader;
 
-const MAX_PIN_LENGTH: usize = 8;
-const STAR_KEY: u8 = 0x0A;
-const HASH_KEY: u8 = 0x0B;
-const DOOR_OPEN_DELAY: Duration = Duration::from_secs(4);
+const DIGEST_INTERVAL: Duration = Duration::from_secs(300);
+/// Matches CONFIG_MQTT_OUTBOX_EXPIRED_TIMEOUT_MS. The outbox resends the audit
+/// on every reconnect until then, it is only enqueued again once it expired.
+const AUDIT_ACK_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+const AUDIT_RETRY_DELAY: Duration = Duration::from_secs(5);
 
 const GPIO_D0: i32 = 4;
 const GPIO_D1: i32 = 5;
 const GPIO_BUTTON: i32 = 6;
+/// Button sampling while a press is being debounced or timed
+const BUTTON_POLL: Duration = Duration::from_millis(10);
 
-fn setup_button(door_tx: Sender<()>) {
+/// Request to exit button, long presses run the configured action
+fn setup_button(door_tx: Sender<DoorCommand>, config_store: ConfigStore, audit_tx: Sender<Audit>) {
     thread::spawn(move || {
         let mut button = Button::new(GPIO_BUTTON);
         button.start().unwrap();
+        let mut debouncer = Debouncer::default();
+        // Door kept unlocked by a long press, the next press releases it
+        let mut holding = false;
+        let started = Instant::now();
 
         loop {
-            if button.wait_for_press() {
-                log::info!("button press");
-                door_tx.send(()).unwrap();
+            if debouncer.is_settled() {
+                button.wait_for_edge();
+            } else {
+                thread::sleep(BUTTON_POLL);
             }
+            let config = config_store.button();
+            let settings = buttons::settings(&config);
+            let now = started.elapsed().as_millis() as u64;
+            let Some(press) = debouncer.sample(button.is_pressed(), now, &settings) else {
+                continue;
+            };
+            log::info!("Button {:?}", press);
+            let command = match (press, config.long_press_action) {
+                (Press::Down, _) if holding => continue,
+                _ if holding => {
+                    holding = false;
+                    DoorCommand::Release
+                }
+                // Provision is no longer honored, anyone inside could take
+                // the door offline with it
+                (Press::Down, LongPress::Unlock | LongPress::Provision)
+                | (Press::Short, LongPress::HoldOpen) => DoorCommand::Unlock(None),
+                (Press::Long, LongPress::HoldOpen) => {
+                    holding = true;
+                    DoorCommand::Hold
+                }
+                _ => continue,
+            };
+            if !matches!(command, DoorCommand::Release) {
+                let timestamp = SystemTime::now();
+                let reason = Reason::Granted;
+                access::send_audit(&audit_tx, 0, None, CodeType::Button, timestamp, reason);
+            }
+            door_tx.send(command).unwrap();
         }
     });
 }
 
-fn setup_door(pin: impl OutputPin, door_rx: Receiver<()>) -> anyhow::Result<()> {
-    let mut door = door::Door::new(pin)?;
-
-    thread::spawn(move || loop {
-        door_rx.recv().unwrap();
-        if let Err(e) = door.open() {
-            log::error!("error: {}", e);
-        }
-        // Drain the queue while the door is open
-        while door_rx.recv_timeout(DOOR_OPEN_DELAY).is_ok() {}
-        if let Err(e) = door.close() {
-            log::error!("error: {}", e);
-        }
-    });
-
-    Ok(())
-}
-
-fn keypad_feedback(
-    success: bool,
-    pin: &mut PinDriver<'_, impl OutputPin, Output>,
+fn setup_door(
+    relay_pin: impl OutputPin,
+    sensor_pin: impl InputPin + OutputPin,
+    config_store: ConfigStore,
+    alert_tx: Sender<Alert>,
+    door_rx: Receiver<DoorCommand>,
 ) -> anyhow::Result<()> {
-    for _ in 0..8 {
-        if success {
-            pin.set_low()?;
-        } else {
-            pin.toggle()?;
-        }
-        thread::sleep(Duration::from_millis(100));
-    }
-    pin.set_high()?;
+    let mut door = door::Door::new(relay_pin, sensor_pin, config_store, alert_tx)?;
+    thread::spawn(move || door.run(door_rx));
     Ok(())
 }
 
-/// Converts a key press sequence into an integer
-fn keys_to_int(keys: &[u8]) -> i32 {
-    keys.iter()
-        .cloned()
-        .rev()
-        .enumerate()
-        .fold(0, |acc, (i, num)| acc + 10i32.pow(i as u32) * num as i32)
-}
-
 fn setup_reader(
-    door_tx: Sender<()>,
+    door_tx: Sender<DoorCommand>,
     user_db: UserDB,
+    config_store: ConfigStore,
     audit_tx: Sender<Audit>,
+    alert_tx: Sender<Alert>,
     signal_pin: impl OutputPin,
 ) -> anyhow::Result<()> {
     let mut signal_driver = PinDriver::output_od(signal_pin)?;
     signal_driver.set_high()?;
+    let mut access = Access::new(
+        user_db,
+        config_store,
+        door_tx,
+        audit_tx,
+        alert_tx,
+        signal_driver,
+    );
 
     thread::spawn(move || {
         let mut reader = Reader::new(GPIO_D0, GPIO_D1);
         reader.start().unwrap();
-
-        let mut keys = Vec::with_capacity(MAX_PIN_LENGTH);
+        let started = Instant::now();
 
         // Reads the queue in a loop.
         for packet in reader {
-            match packet {
-                Ok(Packet::Key { key }) => {
-                    if key == HASH_KEY {
-                        let pin = keys_to_int(&keys);
-                        let success = user_db.contains(pin);
-                        log::info!("Valid pin {}: {}", pin, success);
-                        if success {
-                            door_tx.send(()).unwrap();
-                        }
-                        let audit = Audit {
-                            code: pin,
-                            code_type: CodeType::Pin,
-                            timestamp: SystemTime::now(),
-                            success,
-                        };
-                        if let Err(e) = audit_tx.send(audit) {
-                            log::error!("error sending audit record: {}", e);
-                        }
-                        keys.clear();
-                        if let Err(e) = keypad_feedback(success, &mut signal_driver) {
-                            log::warn!("error playing feedback: {}", e);
-                        }
-                    } else if key == STAR_KEY {
-                        log::info!("Cancel sequence");
-                        keys.clear();
-                        if let Err(e) = keypad_feedback(false, &mut signal_driver) {
-                            log::warn!("error playing feedback: {}", e);
-                        }
-                    } else if keys.len() == MAX_PIN_LENGTH {
-                        log::warn!("pin sequence is too big {:?}", keys);
-                        keys.clear();
-                        if let Err(e) = keypad_feedback(false, &mut signal_driver) {
-                            log::warn!("error playing feedback: {}", e);
-                        }
-                    } else {
-                        keys.push(key);
-                    }
+            let now = started.elapsed().as_millis() as u64;
+            access.handle(packet.ok(), now);
+        }
+    });
+
+    Ok(())
+}
+
+/// Persists audits as they come from the reader
+fn setup_audit_recorder(audit_log: AuditLog, audit_rx: Receiver<Audit>) {
+    thread::spawn(move || {
+        for audit in audit_rx {
+            if let Err(e) = audit_log.push(&audit) {
+                log::error!("error storing audit: {:#}", e);
+            }
+        }
+    });
+}
+
+/// Publishes stored audits in order, removing each one once the broker acknowledges
+/// it. Records the log dropped while full are reported once it reaches the broker.
+fn setup_audit_publiher(
+    device_id: &str,
+    mqtt_client: Arc<Mutex<MqttClient>>,
+    audit_log: AuditLog,
+    published_rx: Receiver<u32>,
+    alert_tx: Sender<Alert>,
+) {
+    let topic = format!("doorsys/audit/{device_id}");
+    thread::spawn(move || loop {
+        let (seq, buffer) = audit_log.next();
+        let msg_id =
+            match mqtt_client
+                .lock()
+                .unwrap()
+                .enqueue(&topic, QoS::AtLeastOnce, false, &buffer)
+            {
+                Ok(msg_id) => msg_id,
+                Err(e) => {
+                    log::error!("error sending audit: {}", e);
+                    thread::sleep(AUDIT_RETRY_DELAY);
+                    continue;
                 }
-                Ok(Packet::Card { rfid }) => {
-                    let success = user_db.contains(rfid);
-                    log::info!("Valid rfid {}: {}", rfid, success);
-                    if success {
-                        door_tx.send(()).unwrap();
-                    }
-                    let audit = Audit {
-                        code: rfid,
-                        code_type: CodeType::Fob,
+            };
+        if wait_published(&published_rx, msg_id) {
+            if let Err(e) = audit_log.remove(seq) {
+                log::error!("error removing audit: {:#}", e);
+            }
+            match audit_log.take_dropped() {
+                Ok(0) => {}
+                Ok(records) => {
+                    let alert = Alert {
                         timestamp: SystemTime::now(),
-                        success,
+                        kind: AlertKind::AuditsDropped { records },
                     };
-                    if let Err(e) = audit_tx.send(audit) {
-                        log::error!("error sending audit record: {}", e);
-                    }
-                    keys.clear();
-                    if let Err(e) = keypad_feedback(success, &mut signal_driver) {
-                        log::warn!("error playing feedback: {}", e);
-                    }
-                }
-                Ok(Packet::Unknown { bits, data }) => {
-                    log::warn!("pattern not recognized bits: {}, data: {:02X?}", bits, data);
-                }
-                Err(_e) => {
-                    if !keys.is_empty() {
-                        log::warn!("incomplete pin sequence {:?}", keys);
-                        keys.clear();
-                        if let Err(e) = keypad_feedback(false, &mut signal_driver) {
-                            log::warn!("error playing feedback: {}", e);
-                        }
+                    if let Err(e) = alert_tx.send(alert) {
+                        log::error!("error sending alert: {}", e);
                     }
                 }
+                Err(e) => log::error!("error reading dropped audits: {:#}", e),
             }
+        } else {
+            log::warn!("audit {} not acknowledged, sending again", seq);
         }
     });
+}
 
-    Ok(())
+fn wait_published(published_rx: &Receiver<u32>, msg_id: u32) -> bool {
+    let deadline = Instant::now() + AUDIT_ACK_TIMEOUT;
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        match published_rx.recv_timeout(remaining) {
+            Ok(id) if id == msg_id => return true,
+            // Acks for other messages
+            Ok(_) => {}
+            Err(_) => return false,
+        }
+    }
+    false
 }
 
-fn setup_audit_publiher(
-    device_id: &str,
+/// Publishes alerts as they are raised, the ones raised before the broker
+/// connection wait in the channel
+fn setup_alert_publisher(
+    net_id: &str,
     mqtt_client: Arc<Mutex<MqttClient>>,
-    audit_rx: Receiver<Audit>,
+    alert_rx: Receiver<Alert>,
 ) {
-    let topic = format!("doorsys/audit/{device_id}");
+    let topic = format!("doorsys/alert/{net_id}");
     thread::spawn(move || {
-        let config = bincode::config::standard();
-
-        for audit in audit_rx {
-            match bincode::encode_to_vec(audit, config) {
+        for alert in alert_rx {
+            log::warn!("Publishing {:?}", alert);
+            match doorsys_protocol::encode(&alert) {
                 Ok(buffer) => {
                     if let Err(e) = mqtt_client.lock().unwrap().enqueue(
                         &topic,
@@ -210,18 +240,78 @@ fn setup_audit_publiher(
                         false,
                         &buffer,
                     ) {
-                        log::error!("error sending audit: {}", e);
+                        log::error!("error sending alert: {}", e);
                     }
                 }
                 Err(e) => {
-                    log::error!("error encoding audit: {}", e);
+                    log::error!("error encoding alert: {}", e);
                 }
             }
         }
     });
 }
 
-fn health_check(net_id: &str, mqtt_client: Arc<Mutex<MqttClient>>) -> anyhow::Result<()> {
+/// Periodically drops the expired visitor codes and publishes a digest of the
+/// stored codes so the api can detect drift
+fn setup_digest_publisher(net_id: &str, mqtt_client: Arc<Mutex<MqttClient>>, user_db: UserDB) {
+    let net_id = net_id.to_owned();
+    let topic = format!("doorsys/digest/{net_id}");
+    thread::spawn(move || loop {
+        thread::sleep(DIGEST_INTERVAL);
+        if let Err(e) = user_db.purge_expired(SystemTime::now()) {
+            log::error!("error dropping expired codes: {}", e);
+        }
+        let corrupted = user_db.stats().corrupted;
+        if corrupted > 0 {
+            request_resync(&net_id, &mqtt_client, corrupted);
+        }
+        let digest = user_db.digest();
+        log::info!("Publishing {:?}", digest);
+        match doorsys_protocol::encode(&digest) {
+            Ok(buffer) => {
+                if let Err(e) =
+                    mqtt_client
+                        .lock()
+                        .unwrap()
+                        .publish(&topic, QoS::AtMostOnce, false, &buffer)
+                {
+                    log::warn!("mqtt publish error: {}", e);
+                }
+            }
+            Err(e) => {
+                log::error!("error encoding digest: {}", e);
+            }
+        }
+    });
+}
+
+/// Asks the api for all the codes once corrupted pages were dropped at boot,
+/// repeated with every digest until a sync replaces the store
+fn request_resync(net_id: &str, mqtt_client: &Arc<Mutex<MqttClient>>, corrupted: u32) {
+    log::warn!("Requesting a resync for {} corrupted pages", corrupted);
+    let topic = format!("doorsys/resync/{net_id}");
+    match doorsys_protocol::encode(&ResyncRequest { corrupted }) {
+        Ok(buffer) => {
+            if let Err(e) =
+                mqtt_client
+                    .lock()
+                    .unwrap()
+                    .enqueue(&topic, QoS::AtLeastOnce, false, &buffer)
+            {
+                log::error!("error requesting resync: {}", e);
+            }
+        }
+        Err(e) => {
+            log::error!("error encoding resync request: {}", e);
+        }
+    }
+}
+
+fn health_check(
+    net_id: &str,
+    mqtt_client: Arc<Mutex<MqttClient>>,
+    user_db: UserDB,
+) -> anyhow::Result<()> {
     let systime = EspSystemTime {};
 
     let mqtt_client = mqtt_client.clone();
@@ -270,6 +360,34 @@ fn health_check(net_id: &str, mqtt_client: Arc<Mutex<MqttClient>>) -> anyhow::Re
             log::warn!("mqtt publish error: {}", e);
         }
 
+        let store = user_db.stats();
+        let codes = format!(
+            "codes,host={net_id},version={version} stored={},capacity={},corrupted={} {time}",
+            store.stored, store.capacity, store.corrupted
+        );
+        log::info!("{}", codes);
+        if let Err(e) = mqtt_client.lock().unwrap().publish(
+            "doorsys/status",
+            QoS::AtMostOnce,
+            false,
+            codes.as_bytes(),
+        ) {
+            log::warn!("mqtt publish error: {}", e);
+        }
+
+        let tls_failures = mqtt::tls_failures();
+        let mqtt_stats =
+            format!("mqtt,host={net_id},version={version} tls_failures={tls_failures} {time}");
+        log::info!("{}", mqtt_stats);
+        if let Err(e) = mqtt_client.lock().unwrap().publish(
+            "doorsys/status",
+            QoS::AtMostOnce,
+            false,
+            mqtt_stats.as_bytes(),
+        ) {
+            log::warn!("mqtt publish error: {}", e);
+        }
+
         thread::sleep(Duration::from_secs(60));
     });
 
@@ -308,27 +426,89 @@ fn main() -> anyhow::Result<()> {
     let nvs_part = EspDefaultNvsPartition::take()?;
 
     let doorsys_nvs = EspNvs::new(nvs_part.clone(), "doorsys", true)?;
+    let config_store = ConfigStore::new(EspNvs::new(nvs_part.clone(), "doorsys", true)?);
 
     let user_db = UserDB::new(doorsys_nvs)?;
 
+    let audit_nvs = EspNvs::new(nvs_part.clone(), "audit", true)?;
+    let audit_log = AuditLog::new(audit_nvs)?;
+
+    let ota_nvs = EspNvs::new(nvs_part.clone(), "ota", true)?;
+
     log::info!("Starting application");
 
+    let (alert_tx, alert_rx) = mpsc::channel();
     let (door_tx, door_rx) = mpsc::channel();
-    setup_door(peripherals.pins.gpio10, door_rx)?;
-
-    setup_button(door_tx.clone());
+    setup_door(
+        peripherals.pins.gpio10,
+        peripherals.pins.gpio3,
+        config_store.clone(),
+        alert_tx.clone(),
+        door_rx,
+    )?;
 
     let (audit_tx, audit_rx) = mpsc::channel();
+    setup_audit_recorder(audit_log.clone(), audit_rx);
+
+    setup_button(door_tx.clone(), config_store.clone(), audit_tx.clone());
     let signal_pin = peripherals.pins.gpio7;
-    setup_reader(door_tx.clone(), user_db.clone(), audit_tx, signal_pin)?;
+    setup_reader(
+        door_tx.clone(),
+        user_db.clone(),
+        config_store.clone(),
+        audit_tx,
+        alert_tx.clone(),
+        signal_pin,
+    )?;
+
+    // The door keeps working from the local database while waiting for credentials
+    let Some(net_config) = config_store.load()? else {
+        log::warn!("No network credentials");
+        config::provision(&config_store);
+    };
+    ota::setup_boot_deadline();
+    config::setup_console(config_store.clone());
+    config::setup_rollback_watchdog(config_store.clone());
+
+    let net_id = network::setup_wireless(
+        peripherals.modem,
+        sysloop.clone(),
+        nvs_part.clone(),
+        &net_config,
+    )?;
+
+    let (published_tx, published_rx) = mpsc::channel();
+    let (ota_tx, ota_rx) = mpsc::channel();
+    let mqtt_client = mqtt::setup_mqtt(
+        &net_id,
+        &net_config,
+        config_store,
+        user_db.clone(),
+        door_tx,
+        published_tx,
+        ota_tx,
+    )?;
+
+    let corrupted = user_db.stats().corrupted;
+    if corrupted > 0 {
+        request_resync(&net_id, &mqtt_client, corrupted);
+    }
+
+    setup_audit_publiher(
+        &net_id,
+        mqtt_client.clone(),
+        audit_log,
+        published_rx,
+        alert_tx,
+    );
 
-    let net_id = network::setup_wireless(peripherals.modem, sysloop.clone(), nvs_part.clone())?;
+    setup_alert_publisher(&net_id, mqtt_client.clone(), alert_rx);
 
-    let mqtt_client = mqtt::setup_mqtt(&net_id, user_db.clone())?;
+    setup_digest_publisher(&net_id, mqtt_client.clone(), user_db.clone());
 
-    setup_audit_publiher(&net_id, mqtt_client.clone(), audit_rx);
+    health_check(&net_id, mqtt_client.clone(), user_db)?;
 
-    health_check(&net_id, mqtt_client.clone())?;
+    ota::setup_ota(&net_id, mqtt_client.clone(), ota_nvs, ota_rx)?;
 
     log::info!("Application fully functional");
 
@@ -1,129 +1,430 @@
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
-use std::thread;
+use std::time::Duration;
+use std::{ptr, thread};
 
-use doorsys_protocol::UserAction;
+use anyhow::Context;
+use doorsys_protocol::{Ack, DeviceCommand, Reassembler, UserAction, UserUpdate};
+use esp_idf_svc::hal::reset;
+use esp_idf_svc::handle::RawHandle;
 use esp_idf_svc::mqtt::client::{
     Details, EspMqttClient, EventPayload, MqttClientConfiguration, QoS,
 };
+use esp_idf_svc::sys::{
+    esp, esp_crt_bundle_attach, esp_event_base_t, esp_mqtt_client_register_event,
+    esp_mqtt_error_type_t_MQTT_ERROR_TYPE_TCP_TRANSPORT, esp_mqtt_event_handle_t,
+    esp_mqtt_event_id_t_MQTT_EVENT_ERROR,
+};
+use esp_idf_svc::tls::X509;
 
-use crate::user::UserDB;
+use crate::{
+    config::{ConfigStore, NetConfig},
+    door::DoorCommand,
+    ota::UpdateRequest,
+    user::UserDB,
+};
 
-const MQTT_URL: &str = env!("MQTT_URL");
-const MQTT_USER: &str = env!("MQTT_USER");
-const MQTT_PASS: &str = env!("MQTT_PASS");
+const MAX_UNLOCK_DURATION: Duration = Duration::from_secs(60);
+/// Leaves time for the broker to settle the command before restarting
+const RESTART_DELAY: Duration = Duration::from_secs(2);
 
-const BINCODE_CONFIG: bincode::config::Configuration = bincode::config::standard();
+/// Largest message put back together from chunks, fits a sync of a full code
+/// store even with every card paired with a pin
+const MAX_MESSAGE_SIZE: usize = 48 * 1024;
 
-static mut SHARED_BUF: Vec<u8> = Vec::new();
-static mut SHARED_TOPIC: String = String::new();
+/// TLS handshake failures since boot, reported by the health check
+static TLS_FAILURES: AtomicU32 = AtomicU32::new(0);
 
 pub type MqttClient = EspMqttClient<'static>;
 
-pub fn setup_mqtt(net_id: &str, user_db: UserDB) -> anyhow::Result<Arc<Mutex<MqttClient>>> {
-    let mqtt_config = MqttClientConfiguration {
+/// State needed by the incoming message handlers
+struct Handlers {
+    user_topic: String,
+    device_topic: String,
+    user_db: UserDB,
+    /// Whether the broker connection is encrypted, a site key is refused otherwise
+    tls: bool,
+    ack_tx: mpsc::Sender<Ack>,
+    door_tx: mpsc::Sender<DoorCommand>,
+    ota_tx: mpsc::Sender<UpdateRequest>,
+    config_store: ConfigStore,
+}
+
+pub fn setup_mqtt(
+    net_id: &str,
+    config: &NetConfig,
+    config_store: ConfigStore,
+    user_db: UserDB,
+    door_tx: mpsc::Sender<DoorCommand>,
+    published_tx: mpsc::Sender<u32>,
+    ota_tx: mpsc::Sender<UpdateRequest>,
+) -> anyhow::Result<Arc<Mutex<MqttClient>>> {
+    let mut mqtt_config = MqttClientConfiguration {
         client_id: Some(net_id),
-        username: Some(MQTT_USER),
-        password: Some(MQTT_PASS),
+        username: Some(config.mqtt_user.as_str()),
+        password: Some(config.mqtt_pass.as_str()),
         disable_clean_session: true,
         ..Default::default()
     };
+    let tls = config.mqtt_url.starts_with("mqtts://");
+    if tls {
+        configure_tls(&mut mqtt_config, config);
+    } else {
+        log::error!("Broker connection isn't encrypted, a site key sent over it is refused");
+    }
 
     let (conn_sender, conn_receiver) = mpsc::channel();
+    let (ack_tx, ack_rx) = mpsc::channel();
+    let user_topic = format!("doorsys/user/{net_id}");
+    let device_topic = format!("doorsys/device/{net_id}");
+    let topics = vec![user_topic.clone(), device_topic.clone()];
+    let handlers = Handlers {
+        user_topic,
+        device_topic,
+        user_db,
+        tls,
+        ack_tx,
+        door_tx,
+        ota_tx,
+        config_store,
+    };
 
-    let client =
-        EspMqttClient::new_cb(MQTT_URL, &mqtt_config, move |event| match event.payload() {
+    let mut reassembler = Reassembler::new(MAX_MESSAGE_SIZE);
+    let client = EspMqttClient::new_cb(&config.mqtt_url, &mqtt_config, move |event| {
+        match event.payload() {
             EventPayload::Received {
-                id: _,
+                id,
                 topic,
                 data,
                 details,
-            } => route_message(topic, data, details, &user_db),
+            } => route_message(id, topic, data, details, &mut reassembler, &handlers),
             EventPayload::Connected(session) => {
                 log::info!("Connected session = {session}");
+                if let Err(e) = handlers.config_store.confirm() {
+                    log::error!("error confirming credentials: {:#}", e);
+                }
                 conn_sender.send(()).unwrap();
             }
+            EventPayload::Published(id) => {
+                // Only the audit publisher waits on these, nobody listening is fine
+                let _ = published_tx.send(id);
+            }
             EventPayload::Error(e) => log::error!("from mqtt: {:?}", e),
             event => log::info!("mqtt event: {:?}", event),
-        })?;
+        }
+    })?;
+    esp!(unsafe {
+        esp_mqtt_client_register_event(
+            client.handle(),
+            esp_mqtt_event_id_t_MQTT_EVENT_ERROR,
+            Some(count_tls_failures),
+            ptr::null_mut(),
+        )
+    })?;
     let client = Arc::new(Mutex::new(client));
 
-    subscriber_thread(client.clone(), conn_receiver);
+    subscriber_thread(client.clone(), conn_receiver, topics);
+    ack_publisher(net_id, client.clone(), ack_rx);
 
     Ok(client)
 }
 
+/// Pins the server certificate when one is provisioned, otherwise the broker
+/// is verified against the public CA bundle
+fn configure_tls(mqtt_config: &mut MqttClientConfiguration, config: &NetConfig) {
+    // esp-mqtt keeps pointers to the certificates for the lifetime of the client,
+    // which is the lifetime of the firmware
+    let leak = |pem: &Vec<u8>| X509::pem_until_nul(Box::leak(pem.clone().into_boxed_slice()));
+    match &config.ca_cert {
+        Some(ca_cert) => mqtt_config.server_certificate = Some(leak(ca_cert)),
+        None => mqtt_config.crt_bundle_attach = Some(esp_crt_bundle_attach),
+    }
+    match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => {
+            mqtt_config.client_certificate = Some(leak(cert));
+            mqtt_config.private_key = Some(leak(key));
+        }
+        (None, None) => {}
+        _ => log::warn!("client certificate and key must be provisioned together, ignoring"),
+    }
+}
+
+/// Raw error handler, the details of transport errors aren't exposed by
+/// [`EventPayload::Error`]
+unsafe extern "C" fn count_tls_failures(
+    _arg: *mut c_void,
+    _base: esp_event_base_t,
+    _id: i32,
+    data: *mut c_void,
+) {
+    let event = data as esp_mqtt_event_handle_t;
+    if event.is_null() || (*event).error_handle.is_null() {
+        return;
+    }
+    let error = &*(*event).error_handle;
+    if error.error_type == esp_mqtt_error_type_t_MQTT_ERROR_TYPE_TCP_TRANSPORT
+        && (error.esp_tls_stack_err != 0 || error.esp_tls_cert_verify_flags != 0)
+    {
+        let failures = TLS_FAILURES.fetch_add(1, Ordering::Relaxed) + 1;
+        log::error!(
+            "TLS handshake failed [{}], tls error: {:#x}, verify flags: {:#x}",
+            failures,
+            error.esp_tls_stack_err,
+            error.esp_tls_cert_verify_flags
+        );
+    }
+}
+
+pub fn tls_failures() -> u32 {
+    TLS_FAILURES.load(Ordering::Relaxed)
+}
+
 fn subscriber_thread(
     client: Arc<Mutex<EspMqttClient<'static>>>,
     conn_receiver: mpsc::Receiver<()>,
+    topics: Vec<String>,
 ) {
     thread::spawn(move || {
         while conn_receiver.recv().is_ok() {
-            let topic = "doorsys/user";
-            match client.lock().unwrap().subscribe(topic, QoS::AtLeastOnce) {
-                Ok(id) => log::info!("Subscribed to {topic} {id}"),
-                Err(e) => log::error!("Failed to subscribe to topic {topic}: {e}"),
-            };
+            for topic in &topics {
+                match client.lock().unwrap().subscribe(topic, QoS::AtLeastOnce) {
+                    Ok(id) => log::info!("Subscribed to {topic} {id}"),
+                    Err(e) => log::error!("Failed to subscribe to topic {topic}: {e}"),
+                };
+            }
         }
     });
 }
 
-fn route_message(topic: Option<&str>, data: &[u8], details: Details, user_db: &UserDB) {
+/// Publishes the outcome of every user update so the api can track delivery
+fn ack_publisher(net_id: &str, client: Arc<Mutex<MqttClient>>, ack_rx: mpsc::Receiver<Ack>) {
+    let topic = format!("doorsys/ack/{net_id}");
+    thread::spawn(move || {
+        for ack in ack_rx {
+            match doorsys_protocol::encode(&ack) {
+                Ok(buffer) => {
+                    if let Err(e) =
+                        client
+                            .lock()
+                            .unwrap()
+                            .enqueue(&topic, QoS::AtLeastOnce, false, &buffer)
+                    {
+                        log::error!("error sending ack: {}", e);
+                    }
+                }
+                Err(e) => {
+                    log::error!("error encoding ack: {}", e);
+                }
+            }
+        }
+    });
+}
+
+/// Handles a received message, putting chunked ones back together first
+fn route_message(
+    id: u32,
+    topic: Option<&str>,
+    data: &[u8],
+    details: Details,
+    reassembler: &mut Reassembler,
+    handlers: &Handlers,
+) {
     log::info!(
-        "Message received {:?} {:?}, {} bytes",
+        "Message {} received {:?} {:?}, {} bytes",
+        id,
         topic,
         details,
         data.len()
     );
-    let (topic, data) = match details {
-        Details::InitialChunk(init) => unsafe {
-            SHARED_BUF = Vec::with_capacity(init.total_data_size);
-            SHARED_BUF.extend_from_slice(data);
-            SHARED_TOPIC = String::from(topic.unwrap());
-            return;
-        },
-        Details::SubsequentChunk(_sub) => unsafe {
-            SHARED_BUF.extend_from_slice(data);
-            if SHARED_BUF.len() != SHARED_BUF.capacity() {
-                return;
-            }
-            (&*SHARED_TOPIC, &*SHARED_BUF)
-        },
-        Details::Complete => (topic.unwrap(), data),
+    let chunk = match details {
+        Details::InitialChunk(init) => {
+            if reassembler.in_progress() {
+                log::warn!("message {} drops an unfinished one", id);
+            }
+            let topic = topic.unwrap_or_default();
+            reassembler.start(id, topic, init.total_data_size, data)
+        }
+        Details::SubsequentChunk(sub) => {
+            reassembler.append(id, sub.current_data_offset, sub.total_data_size, data)
+        }
+        Details::Complete => return dispatch(topic.unwrap_or_default(), data, handlers),
     };
+    match chunk {
+        Ok(Some(message)) => dispatch(&message.topic, &message.data, handlers),
+        Ok(None) => {}
+        Err(e) => log::error!("dropping chunked message {}: {}", id, e),
+    }
+}
+
+fn dispatch(topic: &str, data: &[u8], handlers: &Handlers) {
     match topic {
-        "doorsys/user" => process_user_message(data, user_db),
+        t if t == handlers.user_topic => process_user_message(data, handlers),
+        t if t == handlers.device_topic => process_device_command(data, handlers),
         _ => log::warn!("unknown topic {}", topic),
     };
 }
 
-fn process_user_message(data: &[u8], user_db: &UserDB) {
-    match bincode::decode_from_slice(data, BINCODE_CONFIG) {
-        Ok((UserAction::Add(code), _)) => {
-            log::info!("Adding code {}", code);
-            if let Err(e) = user_db.add(code) {
-                log::error!("Error adding new code {}", e);
-            }
+fn process_user_message(data: &[u8], handlers: &Handlers) {
+    let update: UserUpdate = match doorsys_protocol::decode(data) {
+        Ok(update) => update,
+        Err(e) => {
+            log::error!("decoding error: {}", e);
+            return;
         }
-        Ok((UserAction::Del(code), _)) => {
+    };
+
+    let result = apply_user_action(update.action, &handlers.user_db, handlers.tls);
+    if let Err(e) = &result {
+        log::error!("Error applying update {}: {:#}", update.seq, e);
+    }
+    let ack = Ack {
+        seq: update.seq,
+        success: result.is_ok(),
+        error: result.err().map(|e| format!("{:#}", e)),
+    };
+    if let Err(e) = handlers.ack_tx.send(ack) {
+        log::error!("error queueing ack: {}", e);
+    }
+}
+
+fn apply_user_action(action: UserAction, user_db: &UserDB, tls: bool) -> anyhow::Result<()> {
+    match action {
+        UserAction::Put(credential) => {
+            log::info!(
+                "Putting code {} with schedule {:?}",
+                credential.code,
+                credential.schedule
+            );
+            user_db.put(credential).context("error storing code")
+        }
+        UserAction::Del(code) => {
             log::info!("Deleting code {}", code);
-            if let Err(e) = user_db.delete(code) {
-                log::error!("Error deleting code {}", e);
-            }
+            user_db.delete(code).context("error deleting code")
         }
-        Ok((UserAction::Replace { old, new }, _)) => {
+        UserAction::Replace { old, new } => {
             log::info!("Replacing code {} with {}", old, new);
-            if let Err(e) = user_db.replace(old, new) {
-                log::error!("Error replacing code {}", e);
+            user_db.replace(old, new).context("error replacing code")
+        }
+        UserAction::SetSchedule(schedule) => {
+            log::info!("Setting schedule {}", schedule.id);
+            user_db
+                .set_schedule(schedule)
+                .context("error storing schedule")
+        }
+        UserAction::DelSchedule(id) => {
+            log::info!("Deleting schedule {}", id);
+            user_db
+                .delete_schedule(id)
+                .context("error deleting schedule")
+        }
+        UserAction::Sync {
+            key,
+            credentials,
+            schedules,
+        } => {
+            anyhow::ensure!(tls, "refusing a site key sent over an unencrypted broker");
+            log::info!(
+                "Syncing {} codes and {} schedules",
+                credentials.len(),
+                schedules.len()
+            );
+            user_db
+                .sync(key, credentials, schedules)
+                .context("error syncing codes")
+        }
+    }
+}
+
+fn process_device_command(data: &[u8], handlers: &Handlers) {
+    match doorsys_protocol::decode(data) {
+        Ok(DeviceCommand::Unlock { duration }) => {
+            let duration = duration.map(|duration| duration.min(MAX_UNLOCK_DURATION));
+            log::info!("Remote unlock for {:?}", duration);
+            if let Err(e) = handlers.door_tx.send(DoorCommand::Unlock(duration)) {
+                log::error!("error sending door command: {}", e);
+            }
+        }
+        Ok(DeviceCommand::Update { job, url, sha256 }) => {
+            let request = UpdateRequest { job, url, sha256 };
+            if let Err(e) = handlers.ota_tx.send(request) {
+                log::error!("error queueing update: {}", e);
+            }
+        }
+        Ok(DeviceCommand::SetNetwork(config)) => {
+            log::info!(
+                "Rotating network credentials, wifi: {}, mqtt: {}",
+                config.wifi_ssid.is_some() || config.wifi_pass.is_some(),
+                config.mqtt_url.is_some()
+                    || config.mqtt_user.is_some()
+                    || config.mqtt_pass.is_some()
+            );
+            match handlers.config_store.rotate(config) {
+                Ok(()) => {
+                    thread::spawn(|| {
+                        thread::sleep(RESTART_DELAY);
+                        reset::restart();
+                    });
+                }
+                Err(e) => log::error!("error storing credentials: {:#}", e),
+            }
+        }
+        Ok(DeviceCommand::SetFacilities(facilities)) => {
+            log::info!("Accepting facility codes {:?}", facilities);
+            if let Err(e) = handlers.user_db.set_facilities(facilities) {
+                log::error!("error storing facility codes: {:#}", e);
+            }
+        }
+        Ok(DeviceCommand::SetKeypad(config)) => {
+            log::info!("Keypad settings {:?}", config);
+            if let Err(e) = handlers.config_store.set_keypad(config) {
+                log::error!("error storing keypad settings: {:#}", e);
+            }
+        }
+        Ok(DeviceCommand::SetSecurityMode(mode)) => {
+            log::info!("Security mode {:?}", mode);
+            if let Err(e) = handlers.config_store.set_security(mode) {
+                log::error!("error storing security mode: {:#}", e);
+            }
+        }
+        Ok(DeviceCommand::SetLockout(config)) => {
+            log::info!("Lockout settings {:?}", config);
+            if let Err(e) = handlers.config_store.set_lockout(config) {
+                log::error!("error storing lockout settings: {:#}", e);
+            }
+        }
+        Ok(DeviceCommand::SetDoorSensor(config)) => {
+            log::info!("Door sensor settings {:?}", config);
+            if let Err(e) = handlers.config_store.set_door_sensor(config) {
+                log::error!("error storing door sensor settings: {:#}", e);
+            }
+        }
+        Ok(DeviceCommand::SetRelay(config)) => {
+            log::info!("Relay settings {:?}", config);
+            if let Err(e) = handlers.config_store.set_relay(config) {
+                log::error!("error storing relay settings: {:#}", e);
+            }
+        }
+        Ok(DeviceCommand::SetButton(config)) => {
+            log::info!("Button settings {:?}", config);
+            if let Err(e) = handlers.config_store.set_button(config) {
+                log::error!("error storing button settings: {:#}", e);
             }
         }
-        Ok((UserAction::Bulk(codes), _)) => {
-            log::info!("Bulk adding codes {}", codes.len());
-            if let Err(e) = user_db.bulk(codes) {
-                log::error!("Error bulk inserting codes {}", e);
+        Ok(DeviceCommand::SetTls(config)) => {
+            log::info!("Replacing broker certificates");
+            match handlers.config_store.rotate_tls(config) {
+                Ok(()) => {
+                    thread::spawn(|| {
+                        thread::sleep(RESTART_DELAY);
+                        reset::restart();
+                    });
+                }
+                Err(e) => log::error!("error storing certificates: {:#}", e),
             }
         }
         Err(e) => {
             log::error!("decoding error: {}", e);
         }
-    };
+    }
 }
@@ -1,6 +1,7 @@
 use std::ffi::CStr;
 use std::{thread, time::Duration};
 
+use anyhow::anyhow;
 use esp_idf_svc::eventloop::{EspEventLoop, System};
 use esp_idf_svc::hal::modem::Modem;
 use esp_idf_svc::nvs::{EspNvsPartition, NvsDefault};
@@ -10,8 +11,7 @@ use esp_idf_svc::wifi::{
     AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi, WifiDeviceId,
 };
 
-const SSID: &str = env!("WIFI_SSID");
-const PASSWORD: &str = env!("WIFI_PASS");
+use crate::config::NetConfig;
 
 const RECONNECT_COOLDOWN: Duration = Duration::from_secs(5);
 
@@ -19,6 +19,7 @@ pub fn setup_wireless(
     modem: Modem,
     sysloop: EspEventLoop<System>,
     nvs: EspNvsPartition<NvsDefault>,
+    config: &NetConfig,
 ) -> anyhow::Result<String> {
     let mut wifi = BlockingWifi::wrap(
         EspWifi::new(modem, sysloop.clone(), Some(nvs.clone()))?,
@@ -29,8 +30,16 @@ pub fn setup_wireless(
     log::info!("Device net_id: {net_id}");
 
     let wifi_configuration: Configuration = Configuration::Client(ClientConfiguration {
-        ssid: SSID.try_into().unwrap(),
-        password: PASSWORD.try_into().unwrap(),
+        ssid: config
+            .wifi_ssid
+            .as_str()
+            .try_into()
+            .map_err(|_| anyhow!("wifi ssid is too long"))?,
+        password: config
+            .wifi_pass
+            .as_str()
+            .try_into()
+            .map_err(|_| anyhow!("wifi password is too long"))?,
         auth_method: AuthMethod::WPA2Personal,
         ..Default::default()
     });
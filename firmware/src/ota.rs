@@ -0,0 +1,200 @@
+use std::{
+    sync::{mpsc::Receiver, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use doorsys_protocol::{FirmwareStatus, UpdateState};
+use esp_idf_svc::{
+    hal::reset,
+    http::{
+        client::{Configuration, EspHttpConnection},
+        Method,
+    },
+    mqtt::client::QoS,
+    nvs::{EspNvs, NvsDefault},
+    ota::EspOta,
+    sys::{
+        esp, esp_crt_bundle_attach, esp_ota_get_running_partition, esp_ota_get_state_partition,
+        esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY,
+        esp_ota_mark_app_invalid_rollback_and_reboot,
+    },
+};
+use sha2::{Digest, Sha256};
+
+use crate::mqtt::MqttClient;
+
+const JOB_KEY: &str = "job";
+const SLOT_KEY: &str = "slot";
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+const CHUNK_SIZE: usize = 4096;
+/// Gives the mqtt client a chance to deliver the last status before restarting
+const REBOOT_DELAY: Duration = Duration::from_secs(2);
+/// Time a new image gets to come online before the previous one is restored
+const BOOT_DEADLINE: Duration = Duration::from_secs(300);
+
+pub struct UpdateRequest {
+    pub job: u64,
+    pub url: String,
+    pub sha256: [u8; 32],
+}
+
+/// Restores the previous image if a new one isn't confirmed by [`setup_ota`]
+/// in time. Joining the network retries forever, an image that can't reach it
+/// would otherwise never restart into the bootloader's rollback.
+pub fn setup_boot_deadline() {
+    if !pending_verify() {
+        return;
+    }
+    log::warn!("New image pending confirmation");
+    thread::spawn(|| {
+        thread::sleep(BOOT_DEADLINE);
+        if pending_verify() {
+            log::error!("New image never came online, restoring the previous one");
+            let e = unsafe { esp_ota_mark_app_invalid_rollback_and_reboot() };
+            log::error!("error restoring the previous image: {}", e);
+        }
+    });
+}
+
+/// Whether the running image was just updated and isn't confirmed yet
+fn pending_verify() -> bool {
+    let mut state = 0;
+    let result =
+        esp!(unsafe { esp_ota_get_state_partition(esp_ota_get_running_partition(), &mut state) });
+    result.is_ok() && state == esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY
+}
+
+/// Confirms the running image and starts the thread that applies updates.
+///
+/// An image that fails before reaching this point is rolled back by the
+/// bootloader on the next restart, or by the boot deadline. The outcome of the update that led to this
+/// boot is reported once the device is back online.
+pub fn setup_ota(
+    net_id: &str,
+    mqtt_client: Arc<Mutex<MqttClient>>,
+    mut nvs: EspNvs<NvsDefault>,
+    ota_rx: Receiver<UpdateRequest>,
+) -> anyhow::Result<()> {
+    let mut ota = EspOta::new()?;
+    ota.mark_running_slot_valid()?;
+
+    let topic = format!("doorsys/firmware/{net_id}");
+    let report = move |job: u64, state: UpdateState| {
+        log::info!("Update {}: {:?}", job, state);
+        match doorsys_protocol::encode(&FirmwareStatus { job, state }) {
+            Ok(buffer) => {
+                if let Err(e) =
+                    mqtt_client
+                        .lock()
+                        .unwrap()
+                        .enqueue(&topic, QoS::AtLeastOnce, false, &buffer)
+                {
+                    log::error!("error sending update status: {}", e);
+                }
+            }
+            Err(e) => log::error!("error encoding update status: {}", e),
+        }
+    };
+
+    report_boot(&ota, &mut nvs, &report)?;
+
+    thread::spawn(move || {
+        for request in ota_rx {
+            let job = request.job;
+            if let Err(e) = apply_update(&mut ota, &mut nvs, request, &report) {
+                log::error!("Update {} failed: {:#}", job, e);
+                report(job, UpdateState::Failed(format!("{:#}", e)));
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Reports whether the pending update booted or was rolled back
+fn report_boot(
+    ota: &EspOta,
+    nvs: &mut EspNvs<NvsDefault>,
+    report: &impl Fn(u64, UpdateState),
+) -> anyhow::Result<()> {
+    let Some(job) = nvs.get_u64(JOB_KEY)? else {
+        return Ok(());
+    };
+    let running = ota.get_running_slot()?;
+    let mut buf = [0; 32];
+    let state = match nvs.get_str(SLOT_KEY, &mut buf)? {
+        Some(slot) if slot == running.label.as_str() => UpdateState::Completed {
+            version: crate::built_info::GIT_VERSION
+                .unwrap_or(crate::built_info::PKG_VERSION)
+                .to_string(),
+        },
+        _ => UpdateState::RolledBack,
+    };
+    report(job, state);
+    nvs.remove(JOB_KEY)?;
+    nvs.remove(SLOT_KEY)?;
+    Ok(())
+}
+
+/// Streams the image into the spare slot, checking its hash before switching to it
+fn apply_update(
+    ota: &mut EspOta,
+    nvs: &mut EspNvs<NvsDefault>,
+    request: UpdateRequest,
+    report: &impl Fn(u64, UpdateState),
+) -> anyhow::Result<()> {
+    // The query carries the download token
+    let path = request.url.split('?').next().unwrap_or_default();
+    log::info!("Starting update {} from {}", request.job, path);
+    let mut conn = EspHttpConnection::new(&Configuration {
+        timeout: Some(HTTP_TIMEOUT),
+        crt_bundle_attach: Some(esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    conn.initiate_request(Method::Get, &request.url, &[])?;
+    conn.initiate_response()?;
+    if conn.status() != 200 {
+        bail!("download failed with status {}", conn.status());
+    }
+    let total: Option<usize> = conn
+        .header("Content-Length")
+        .and_then(|len| len.parse().ok());
+
+    let slot = ota.get_update_slot()?;
+    let mut update = ota.initiate_update()?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut received = 0;
+    let mut reported = 0;
+    loop {
+        let len = conn.read(&mut buf).context("error downloading image")?;
+        if len == 0 {
+            break;
+        }
+        update.write(&buf[..len]).context("error writing image")?;
+        hasher.update(&buf[..len]);
+        received += len;
+        if let Some(total) = total.filter(|&t| t > 0) {
+            let percent = (received * 100 / total).min(100) as u8;
+            if percent >= reported + 10 {
+                reported = percent;
+                report(request.job, UpdateState::Downloading(percent));
+            }
+        }
+    }
+
+    let hash: [u8; 32] = hasher.finalize().into();
+    if hash != request.sha256 {
+        update.abort()?;
+        bail!("checksum mismatch after {} bytes", received);
+    }
+    update.complete().context("error activating image")?;
+
+    nvs.set_u64(JOB_KEY, request.job)?;
+    nvs.set_str(SLOT_KEY, &slot.label)?;
+    report(request.job, UpdateState::Rebooting);
+    thread::sleep(REBOOT_DELAY);
+    reset::restart();
+}
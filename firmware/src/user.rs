@@ -1,87 +1,725 @@
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet},
+    ffi::c_void,
     sync::{Arc, Mutex},
+    time::SystemTime,
 };
 
 use anyhow::Context;
+use bincode::{Decode, Encode};
+use doorsys_protocol::{
+    CodeHash, Credential, Digest, Reason, Schedule, SiteKey, Validity, DEVICE_CAPACITY,
+};
 use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use esp_idf_svc::sys::esp_fill_random;
+
+use crate::clock;
 
 const BINCODE_CONFIG: bincode::config::Configuration = bincode::config::standard();
-const NVS_NAMESPACE: &str = "codes";
+/// Most codes the store takes. At about 24 bytes a hashed code a full store
+/// fills around 24 KB of the 64 KB nvs partition, leaving room for the
+/// settings and the audit log.
+pub const CAPACITY: usize = DEVICE_CAPACITY;
+/// Pages the codes are spread over, changing it needs a new page key prefix
+const PAGE_COUNT: usize = 32;
+/// Schedules and accepted facility codes
+const RULES_KEY: &str = "rules";
+/// Key the stored codes are hashed with
+const SITE_KEY: &str = "sitekey";
+/// Set while the key is a local one, cleared by the first sync
+const LOCAL_KEY: &str = "localkey";
+/// Grants stored as a single blob before the paged layout
+const PERMITS_KEY: &str = "permits";
+/// Grants stored before visitor codes existed
+const ACCESS_KEY: &str = "access";
+/// Grants stored before duress pins existed
+const GRANTS_KEY: &str = "grants";
+/// Credentials stored before cards could be paired with a pin
+const CREDENTIALS_KEY: &str = "credentials";
+/// Codes and schedules stored before facility codes existed
+const USERS_KEY: &str = "users";
+/// Plain set of codes stored before schedules existed
+const LEGACY_KEY: &str = "codes";
+
+/// Facility code, `None` for pins, and the pin or card number of a code
+/// stored before they were hashed
+type PlainKey = (Option<u32>, i32);
+
+/// What a stored credential is allowed
+#[derive(Debug, Clone, Copy, Encode, Decode)]
+struct Grant {
+    /// Whether the code belongs to a card, only pins stand in for a duress pin
+    card: bool,
+    schedule: Option<u32>,
+    /// Hash of the pin that must follow the card
+    pin: Option<CodeHash>,
+    /// Hash of the duress variant of the pin, or of the pin following the card
+    duress: Option<CodeHash>,
+    /// Limits of a visitor code, the uses are counted down here
+    validity: Option<Validity>,
+}
+
+impl From<&Credential> for Grant {
+    fn from(credential: &Credential) -> Self {
+        Grant {
+            card: credential.card,
+            schedule: credential.schedule,
+            pin: credential.pin,
+            duress: credential.duress,
+            validity: credential.validity,
+        }
+    }
+}
+
+/// Grant stored before the codes were hashed
+#[derive(Debug, Clone, Copy, Default, Encode, Decode)]
+struct PlainGrant {
+    schedule: Option<u32>,
+    pin: Option<i32>,
+    duress: Option<i32>,
+    validity: Option<Validity>,
+}
+
+impl PlainGrant {
+    fn with_schedule(schedule: Option<u32>) -> Self {
+        PlainGrant {
+            schedule,
+            ..Default::default()
+        }
+    }
+
+    fn hash(&self, key: &SiteKey, card: bool) -> Grant {
+        Grant {
+            card,
+            schedule: self.schedule,
+            pin: self.pin.map(|pin| key.hash(pin, None)),
+            duress: self.duress.map(|pin| key.hash(pin, None)),
+            validity: self.validity,
+        }
+    }
+}
+
+/// Grant stored before duress pins existed
+#[derive(Decode)]
+struct GrantV1 {
+    schedule: Option<u32>,
+    pin: Option<i32>,
+}
+
+impl From<GrantV1> for PlainGrant {
+    fn from(grant: GrantV1) -> Self {
+        PlainGrant {
+            schedule: grant.schedule,
+            pin: grant.pin,
+            ..Default::default()
+        }
+    }
+}
+
+/// Grant stored before visitor codes existed
+#[derive(Decode)]
+struct GrantV2 {
+    schedule: Option<u32>,
+    pin: Option<i32>,
+    duress: Option<i32>,
+}
+
+impl From<GrantV2> for PlainGrant {
+    fn from(grant: GrantV2) -> Self {
+        PlainGrant {
+            schedule: grant.schedule,
+            pin: grant.pin,
+            duress: grant.duress,
+            validity: None,
+        }
+    }
+}
+
+/// Codes stored in plain by earlier versions, along with the nvs keys
+/// holding them
+struct PlainStore {
+    keys: Vec<String>,
+    codes: BTreeMap<PlainKey, PlainGrant>,
+    /// Rules stored in the same blob as the codes, the paged layout keeps
+    /// them apart
+    rules: Option<(BTreeMap<u32, Schedule>, BTreeSet<u32>)>,
+    corrupted: u32,
+}
+
+impl PlainStore {
+    fn new(
+        key: &str,
+        codes: BTreeMap<PlainKey, PlainGrant>,
+        rules: (BTreeMap<u32, Schedule>, BTreeSet<u32>),
+    ) -> Self {
+        PlainStore {
+            keys: vec![String::from(key)],
+            codes,
+            rules: Some(rules),
+            corrupted: 0,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct UserDB(Arc<Mutex<UserData>>);
 
 struct UserData {
     nvs: EspNvs<NvsDefault>,
-    codes: BTreeSet<i32>,
+    /// Key the api hashed the codes with, or a local one until the first sync
+    key: SiteKey,
+    /// Whether the key is the local one, codes hashed by the api don't match it
+    local: bool,
+    codes: BTreeMap<CodeHash, Grant>,
+    schedules: BTreeMap<u32, Schedule>,
+    /// Facility codes accepted on cards, empty accepts any
+    facilities: BTreeSet<u32>,
+    /// Pages dropped at boot for failing their integrity check
+    corrupted: u32,
+}
+
+/// Size of the code store, reported by the health check
+#[derive(Debug, Clone, Copy)]
+pub struct StoreStats {
+    pub stored: usize,
+    pub capacity: usize,
+    /// Pages dropped at boot for failing their integrity check
+    pub corrupted: u32,
+}
+
+fn page_of(code: &CodeHash) -> usize {
+    code.0[0] as usize % PAGE_COUNT
+}
+
+fn page_key(page: usize) -> String {
+    format!("hashed{:02}", page)
+}
+
+/// Page of codes stored in plain
+fn plain_page_key(page: usize) -> String {
+    format!("page{:02}", page)
 }
 
+/// Random key for the codes stored before the site key arrives, the api
+/// notices the digest mismatch and syncs the device with the site key.
+/// Actions hashed by the api are refused until then.
+fn local_key() -> SiteKey {
+    let mut key = SiteKey {
+        salt: [0; 16],
+        key: [0; 32],
+    };
+    unsafe {
+        esp_fill_random(key.salt.as_mut_ptr() as *mut c_void, key.salt.len());
+        esp_fill_random(key.key.as_mut_ptr() as *mut c_void, key.key.len());
+    }
+    key
+}
+
+/// FNV-1a hash guarding the stored blobs
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// Writes the value with its checksum ahead of it
+fn write_checked(
+    nvs: &mut EspNvs<NvsDefault>,
+    key: &str,
+    value: impl Encode,
+) -> anyhow::Result<()> {
+    let payload = bincode::encode_to_vec(value, BINCODE_CONFIG).context("encoding failure")?;
+    let mut buf = Vec::with_capacity(payload.len() + 4);
+    buf.extend_from_slice(&checksum(&payload).to_le_bytes());
+    buf.extend_from_slice(&payload);
+    nvs.set_raw(key, &buf).context("nvs failure")?;
+    Ok(())
+}
+
+/// Reads a value stored by [`write_checked`], failing when it doesn't match
+/// its checksum
+fn read_checked<T: Decode<()>>(nvs: &EspNvs<NvsDefault>, key: &str) -> anyhow::Result<Option<T>> {
+    let Some(len) = nvs.blob_len(key).context("nvs failure")? else {
+        return Ok(None);
+    };
+    let mut buf = vec![0; len];
+    let Some(blob) = nvs.get_raw(key, &mut buf).context("nvs failure")? else {
+        return Ok(None);
+    };
+    anyhow::ensure!(blob.len() >= 4, "truncated blob of {} bytes", blob.len());
+    let (sum, payload) = blob.split_at(4);
+    let sum = u32::from_le_bytes([sum[0], sum[1], sum[2], sum[3]]);
+    anyhow::ensure!(sum == checksum(payload), "checksum mismatch");
+    let (value, _) =
+        bincode::decode_from_slice(payload, BINCODE_CONFIG).context("decoding failure")?;
+    Ok(Some(value))
+}
+
+/// Rewrites the page, removing it once empty
+fn persist_page(data: &mut UserData, page: usize) -> anyhow::Result<()> {
+    let entries: Vec<_> = data
+        .codes
+        .iter()
+        .filter(|(code, _)| page_of(code) == page)
+        .collect();
+    let key = page_key(page);
+    if entries.is_empty() {
+        data.nvs.remove(&key).context("nvs failure")?;
+        return Ok(());
+    }
+    write_checked(&mut data.nvs, &key, &entries)
+}
+
+fn persist_rules(data: &mut UserData) -> anyhow::Result<()> {
+    write_checked(
+        &mut data.nvs,
+        RULES_KEY,
+        (&data.schedules, &data.facilities),
+    )
+}
+
+/// Rewrites the key, every page and the rules
 fn persist(data: &mut UserData) -> anyhow::Result<()> {
-    let buf = bincode::encode_to_vec(&data.codes, BINCODE_CONFIG).context("encoding failure")?;
-    data.nvs
-        .set_raw(NVS_NAMESPACE, &buf)
-        .context("nvs failure")?;
+    write_checked(&mut data.nvs, SITE_KEY, data.key)?;
+    for page in 0..PAGE_COUNT {
+        persist_page(data, page)?;
+    }
+    persist_rules(data)
+}
+
+fn ensure_capacity(count: usize) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        count <= CAPACITY,
+        "{} codes exceed the capacity of {}",
+        count,
+        CAPACITY
+    );
     Ok(())
 }
 
+/// Codes sent by the api are hashed with the site key, storing them under the
+/// local one would ack codes that never open the door
+fn ensure_synced(data: &UserData) -> anyhow::Result<()> {
+    anyhow::ensure!(!data.local, "no site key yet, waiting for a sync");
+    Ok(())
+}
+
+fn load<T: Decode<()>>(nvs: &EspNvs<NvsDefault>, key: &str) -> anyhow::Result<Option<T>> {
+    let blob_size = nvs.blob_len(key)?.unwrap_or(0);
+    let mut buf = vec![0; blob_size];
+    let maybe_blob = nvs.get_raw(key, &mut buf).context("error loading nvs")?;
+
+    match maybe_blob {
+        Some(slice) => {
+            let (value, bytes) = bincode::decode_from_slice(slice, BINCODE_CONFIG)
+                .context("error deconding blob")?;
+            log::info!("Loaded {} bytes for {}", bytes, key);
+            Ok(Some(value))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Reads the codes earlier versions stored in plain, from the pages first and
+/// then from each single blob layout
+fn load_plain(nvs: &EspNvs<NvsDefault>) -> anyhow::Result<Option<PlainStore>> {
+    let mut pages = PlainStore {
+        keys: Vec::new(),
+        codes: BTreeMap::new(),
+        rules: None,
+        corrupted: 0,
+    };
+    for page in 0..PAGE_COUNT {
+        let key = plain_page_key(page);
+        match read_checked::<Vec<(PlainKey, PlainGrant)>>(nvs, &key) {
+            Ok(None) => continue,
+            Ok(Some(entries)) => pages.codes.extend(entries),
+            Err(e) => {
+                log::error!("Dropping plain code page {}: {:#}", page, e);
+                pages.corrupted += 1;
+            }
+        }
+        pages.keys.push(key);
+    }
+    if !pages.keys.is_empty() {
+        return Ok(Some(pages));
+    }
+
+    // Legacy codes can't tell pins from cards, they are kept as pins until
+    // the api notices the digest mismatch and syncs the device
+    let store = if let Some((codes, schedules, facilities)) = load(nvs, PERMITS_KEY)? {
+        PlainStore::new(PERMITS_KEY, codes, (schedules, facilities))
+    } else if let Some((codes, schedules, facilities)) =
+        load::<(BTreeMap<PlainKey, GrantV2>, _, _)>(nvs, ACCESS_KEY)?
+    {
+        let codes = codes
+            .into_iter()
+            .map(|(key, grant)| (key, grant.into()))
+            .collect();
+        PlainStore::new(ACCESS_KEY, codes, (schedules, facilities))
+    } else if let Some((codes, schedules, facilities)) =
+        load::<(BTreeMap<PlainKey, GrantV1>, _, _)>(nvs, GRANTS_KEY)?
+    {
+        let codes = codes
+            .into_iter()
+            .map(|(key, grant)| (key, grant.into()))
+            .collect();
+        PlainStore::new(GRANTS_KEY, codes, (schedules, facilities))
+    } else if let Some((codes, schedules, facilities)) =
+        load::<(BTreeMap<PlainKey, Option<u32>>, _, _)>(nvs, CREDENTIALS_KEY)?
+    {
+        let codes = codes
+            .into_iter()
+            .map(|(key, schedule)| (key, PlainGrant::with_schedule(schedule)))
+            .collect();
+        PlainStore::new(CREDENTIALS_KEY, codes, (schedules, facilities))
+    } else if let Some((codes, schedules)) =
+        load::<(BTreeMap<i32, Option<u32>>, _)>(nvs, USERS_KEY)?
+    {
+        let codes = codes
+            .into_iter()
+            .map(|(code, schedule)| ((None, code), PlainGrant::with_schedule(schedule)))
+            .collect();
+        PlainStore::new(USERS_KEY, codes, (schedules, BTreeSet::new()))
+    } else if let Some(legacy) = load::<BTreeSet<i32>>(nvs, LEGACY_KEY)? {
+        let codes = legacy
+            .into_iter()
+            .map(|code| ((None, code), PlainGrant::default()))
+            .collect();
+        PlainStore::new(LEGACY_KEY, codes, (BTreeMap::new(), BTreeSet::new()))
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(store))
+}
+
+/// Reads the paged layout. Pages failing their integrity check are dropped
+/// and counted so the device can ask for its codes again, as are all the
+/// codes when their key is lost.
+fn load_pages(mut nvs: EspNvs<NvsDefault>) -> anyhow::Result<UserData> {
+    let mut codes = BTreeMap::new();
+    let mut corrupted = 0;
+    for page in 0..PAGE_COUNT {
+        let key = page_key(page);
+        match read_checked::<Vec<(CodeHash, Grant)>>(&nvs, &key) {
+            Ok(entries) => codes.extend(entries.into_iter().flatten()),
+            Err(e) => {
+                log::error!("Dropping code page {}: {:#}", page, e);
+                nvs.remove(&key).context("nvs failure")?;
+                corrupted += 1;
+            }
+        }
+    }
+    let (schedules, facilities) = match read_checked(&nvs, RULES_KEY) {
+        Ok(rules) => rules.unwrap_or_default(),
+        Err(e) => {
+            log::error!("Dropping schedules: {:#}", e);
+            nvs.remove(RULES_KEY).context("nvs failure")?;
+            corrupted += 1;
+            Default::default()
+        }
+    };
+    let key = match read_checked(&nvs, SITE_KEY) {
+        Ok(Some(key)) => key,
+        stored => {
+            // Codes hashed with a lost key never match again
+            if stored.is_err() || !codes.is_empty() {
+                log::error!("Site key lost, dropping {} codes", codes.len());
+                codes.clear();
+                corrupted += 1;
+                for page in 0..PAGE_COUNT {
+                    nvs.remove(&page_key(page)).context("nvs failure")?;
+                }
+            }
+            let key = local_key();
+            write_checked(&mut nvs, SITE_KEY, key)?;
+            nvs.set_u8(LOCAL_KEY, 1).context("nvs failure")?;
+            key
+        }
+    };
+    let local = nvs.contains(LOCAL_KEY)?;
+    log::info!(
+        "Loaded {} codes and {} schedules",
+        codes.len(),
+        schedules.len()
+    );
+    Ok(UserData {
+        nvs,
+        key,
+        local,
+        codes,
+        schedules,
+        facilities,
+        corrupted,
+    })
+}
+
 impl UserDB {
     pub fn new(nvs: EspNvs<NvsDefault>) -> anyhow::Result<Self> {
-        let blob_size = nvs.blob_len(NVS_NAMESPACE)?.unwrap_or(0);
-        let mut buf = vec![0; blob_size];
-        let maybe_blob = nvs
-            .get_raw(NVS_NAMESPACE, &mut buf)
-            .context("error loading nvs")?;
-
-        match maybe_blob {
-            Some(slice) => {
-                let (codes, bytes) = bincode::decode_from_slice(slice, BINCODE_CONFIG)
-                    .context("error deconding blob")?;
-
-                log::info!("Loaded {} bytes for codes", bytes);
-                Ok(UserDB(Arc::new(Mutex::new(UserData { nvs, codes }))))
+        let plain = load_plain(&nvs)?;
+        let mut data = load_pages(nvs)?;
+        if let Some(plain) = plain {
+            log::info!("Hashing {} plain codes", plain.codes.len());
+            let key = data.key;
+            data.codes
+                .extend(plain.codes.into_iter().map(|((facility, code), grant)| {
+                    let card = facility.is_some();
+                    (key.hash(code, facility), grant.hash(&key, card))
+                }));
+            if let Some((schedules, facilities)) = plain.rules {
+                data.schedules = schedules;
+                data.facilities = facilities;
             }
-            None => {
-                log::info!("No codes found, starting blank");
-                Ok(UserDB(Arc::new(Mutex::new(UserData {
-                    nvs,
-                    codes: BTreeSet::new(),
-                }))))
+            data.corrupted += plain.corrupted;
+            persist(&mut data)?;
+            for key in plain.keys {
+                data.nvs.remove(&key).context("nvs failure")?;
             }
         }
+        Ok(UserDB(Arc::new(Mutex::new(data))))
+    }
+
+    /// Hashes the pin or card number with the key of the stored codes,
+    /// `facility` is the facility code of a card and `None` for pins
+    pub fn hash(&self, code: i32, facility: Option<u32>) -> CodeHash {
+        let data = self.0.lock().unwrap();
+        data.key.hash(code, facility)
     }
 
-    pub fn add(&self, code: i32) -> anyhow::Result<()> {
+    pub fn put(&self, credential: Credential) -> anyhow::Result<()> {
         let mut data = self.0.lock().unwrap();
-        data.codes.insert(code);
-        persist(&mut data)?;
-        Ok(())
+        ensure_synced(&data)?;
+        let code = credential.code;
+        let mut grant = Grant::from(&credential);
+        match data.codes.get(&code) {
+            // Uses counted down here while the update was on its way stay spent
+            Some(stored) => {
+                let left = stored.validity.and_then(|validity| validity.uses);
+                if let (Some(left), Some(validity)) = (left, &mut grant.validity) {
+                    validity.uses = validity.uses.map(|uses| uses.min(left));
+                }
+            }
+            None => ensure_capacity(data.codes.len() + 1)?,
+        }
+        data.codes.insert(code, grant);
+        persist_page(&mut data, page_of(&code))
     }
 
-    pub fn bulk(&self, codes: Vec<i32>) -> anyhow::Result<()> {
+    /// Replaces the codes, the schedules and the key they are hashed with
+    pub fn sync(
+        &self,
+        key: SiteKey,
+        credentials: Vec<Credential>,
+        schedules: Vec<Schedule>,
+    ) -> anyhow::Result<()> {
+        ensure_capacity(credentials.len())?;
         let mut data = self.0.lock().unwrap();
-        data.codes = BTreeSet::from_iter(codes);
+        data.key = key;
+        data.codes = credentials
+            .iter()
+            .map(|c| (c.code, Grant::from(c)))
+            .collect();
+        data.schedules = schedules.into_iter().map(|s| (s.id, s)).collect();
         persist(&mut data)?;
+        data.nvs.remove(LOCAL_KEY).context("nvs failure")?;
+        data.local = false;
+        data.corrupted = 0;
         Ok(())
     }
 
-    pub fn replace(&self, old: i32, new: i32) -> anyhow::Result<()> {
+    /// Replaces the pin keeping its schedule, fails when the old pin isn't stored
+    pub fn replace(&self, old: CodeHash, new: CodeHash) -> anyhow::Result<()> {
         let mut data = self.0.lock().unwrap();
-        data.codes.remove(&old);
-        data.codes.insert(new);
-        persist(&mut data)?;
+        ensure_synced(&data)?;
+        let grant = data
+            .codes
+            .remove(&old)
+            .context("pin to replace not found")?;
+        data.codes.insert(new, grant);
+        persist_page(&mut data, page_of(&old))?;
+        persist_page(&mut data, page_of(&new))
+    }
+
+    pub fn set_schedule(&self, schedule: Schedule) -> anyhow::Result<()> {
+        let mut data = self.0.lock().unwrap();
+        data.schedules.insert(schedule.id, schedule);
+        persist_rules(&mut data)
+    }
+
+    pub fn delete_schedule(&self, id: u32) -> anyhow::Result<()> {
+        let mut data = self.0.lock().unwrap();
+        data.schedules.remove(&id);
+        persist_rules(&mut data)
+    }
+
+    /// Facility codes accepted on cards, empty accepts any
+    pub fn set_facilities(&self, facilities: Vec<u32>) -> anyhow::Result<()> {
+        let mut data = self.0.lock().unwrap();
+        data.facilities = facilities.into_iter().collect();
+        persist_rules(&mut data)
+    }
+
+    /// Whether the facility code is on the list of the ones accepted
+    pub fn lists_facility(&self, facility: u32) -> bool {
+        self.0.lock().unwrap().facilities.contains(&facility)
+    }
+
+    /// Checks if the hashed code opens the door at the given time, `facility`
+    /// is the facility code of the card read and `None` for pins.
+    ///
+    /// Codes bound to a missing schedule, or checked before the clock is
+    /// synchronized, are treated as outside their schedule. Visitor codes with
+    /// time limits are not valid yet until the clock is synchronized.
+    pub fn check(&self, code: CodeHash, facility: Option<u32>, now: SystemTime) -> Reason {
+        let data = self.0.lock().unwrap();
+        if let Some(facility) = facility {
+            if !data.facilities.is_empty() && !data.facilities.contains(&facility) {
+                return Reason::WrongFacility;
+            }
+        }
+        let Some(grant) = data.codes.get(&code) else {
+            return Reason::UnknownCode;
+        };
+        if let Some(validity) = grant.validity {
+            let timed = validity.start.is_some() || validity.end.is_some();
+            if (timed && !clock::is_synchronized(now)) || !validity.started(now) {
+                return Reason::NotYetValid;
+            }
+            if validity.expired(now) {
+                return Reason::Expired;
+            }
+        }
+        let schedule = match grant.schedule {
+            None => return Reason::Granted,
+            Some(id) => data.schedules.get(&id),
+        };
+        let allowed = schedule.is_some_and(|schedule| {
+            clock::local_time(now, &schedule.timezone)
+                .is_some_and(|(weekday, minute)| schedule.allows(weekday, minute))
+        });
+        match allowed {
+            true => Reason::Granted,
+            false => Reason::OutsideSchedule,
+        }
+    }
+
+    /// Whether the card only opens the door followed by its holder's pin
+    pub fn requires_pin(&self, code: CodeHash) -> bool {
+        let data = self.0.lock().unwrap();
+        data.codes
+            .get(&code)
+            .is_some_and(|grant| grant.pin.is_some())
+    }
+
+    /// Regular pin of the holder the duress pin belongs to, `None` when the
+    /// pin is not a duress pin
+    pub fn duress_owner(&self, pin: CodeHash) -> Option<CodeHash> {
+        let data = self.0.lock().unwrap();
+        if data.codes.contains_key(&pin) {
+            return None;
+        }
+        data.codes
+            .iter()
+            .find(|(_, grant)| !grant.card && grant.duress == Some(pin))
+            .map(|(&code, _)| code)
+    }
+
+    /// Checks a card followed by a pin, the pin must be the one paired with it
+    /// or its duress variant. Returns whether the duress pin was entered.
+    pub fn check_pair(
+        &self,
+        code: CodeHash,
+        facility: u32,
+        pin: CodeHash,
+        now: SystemTime,
+    ) -> (Reason, bool) {
+        let (paired, duress) = {
+            let data = self.0.lock().unwrap();
+            data.codes
+                .get(&code)
+                .map_or((None, None), |grant| (grant.pin, grant.duress))
+        };
+        let duress = duress == Some(pin);
+        let reason = match self.check(code, Some(facility), now) {
+            Reason::Granted if paired == Some(pin) || duress => Reason::Granted,
+            Reason::Granted => Reason::PinMismatch,
+            reason => reason,
+        };
+        (reason, duress)
+    }
+
+    /// Counts a granted entry against a visitor code with limited uses, the
+    /// code is dropped once they run out
+    pub fn count_use(&self, code: CodeHash) -> anyhow::Result<()> {
+        let mut data = self.0.lock().unwrap();
+        let uses = data
+            .codes
+            .get_mut(&code)
+            .and_then(|grant| grant.validity.as_mut())
+            .and_then(|validity| validity.uses.as_mut());
+        let Some(uses) = uses else {
+            return Ok(());
+        };
+        *uses = uses.saturating_sub(1);
+        if *uses == 0 {
+            log::info!("Visitor code {} used up", code);
+            data.codes.remove(&code);
+        }
+        persist_page(&mut data, page_of(&code))
+    }
+
+    /// Drops the visitor codes past their end time, nothing is dropped while
+    /// the clock is not synchronized
+    pub fn purge_expired(&self, now: SystemTime) -> anyhow::Result<()> {
+        if !clock::is_synchronized(now) {
+            return Ok(());
+        }
+        let mut data = self.0.lock().unwrap();
+        let expired: Vec<_> = data
+            .codes
+            .iter()
+            .filter(|(_, grant)| grant.validity.is_some_and(|v| v.expired(now)))
+            .map(|(&code, _)| code)
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+        log::info!("Dropping {} expired codes", expired.len());
+        let pages: BTreeSet<_> = expired.iter().map(page_of).collect();
+        for code in &expired {
+            data.codes.remove(code);
+        }
+        for page in pages {
+            persist_page(&mut data, page)?;
+        }
         Ok(())
     }
 
-    pub fn contains(&self, code: i32) -> bool {
+    pub fn digest(&self) -> Digest {
         let data = self.0.lock().unwrap();
-        data.codes.contains(&code)
+        Digest::from_credentials(data.codes.iter().map(|(&code, grant)| Credential {
+            code,
+            card: grant.card,
+            schedule: grant.schedule,
+            pin: grant.pin,
+            duress: grant.duress,
+            validity: grant.validity,
+        }))
     }
 
-    pub fn delete(&self, code: i32) -> anyhow::Result<()> {
+    pub fn delete(&self, code: CodeHash) -> anyhow::Result<()> {
         let mut data = self.0.lock().unwrap();
+        ensure_synced(&data)?;
         data.codes.remove(&code);
-        persist(&mut data)?;
-        Ok(())
+        persist_page(&mut data, page_of(&code))
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        let data = self.0.lock().unwrap();
+        StoreStats {
+            stored: data.codes.len(),
+            capacity: CAPACITY,
+            corrupted: data.corrupted,
+        }
     }
 }
@@ -14,9 +14,12 @@ use esp_idf_svc::sys::{
     gpio_mode_t_GPIO_MODE_INPUT, gpio_reset_pin, gpio_set_intr_type,
 };
 
+use doorsys_wiegand::Frame;
+pub use doorsys_wiegand::Packet;
+
 const WIEGAND_TIMEOUT: u64 = 50000; // 50ms
-const BUFFER_SIZE: usize = 4;
-const PIN_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the reader wakes up without data so pin entries can time out
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 #[link_section = ".iram0.text"]
 unsafe extern "C" fn wiegand_interrupt(arg: *mut c_void) {
@@ -27,7 +30,7 @@ unsafe extern "C" fn wiegand_interrupt(arg: *mut c_void) {
         return;
     }
     // Overflow
-    if reader.bits > reader.data.len() * 8 {
+    if reader.frame.is_full() {
         return;
     }
 
@@ -35,9 +38,7 @@ unsafe extern "C" fn wiegand_interrupt(arg: *mut c_void) {
 
     esp_timer_stop(timer);
 
-    let value = if d0 == 0 { 0 } else { 0x80 };
-    reader.data[reader.bits / 8] |= value >> (reader.bits % 8);
-    reader.bits += 1;
+    reader.frame.push(d0 != 0);
 
     esp_timer_start_once(timer, WIEGAND_TIMEOUT);
 }
@@ -46,7 +47,7 @@ unsafe extern "C" fn timer_interrupt(arg: *mut c_void) {
     let reader = &mut *(arg as *mut Reader);
     reader.stop();
 
-    let packet = Packet::new(reader.bits, reader.data);
+    let packet = reader.frame.decode();
 
     if let Err(e) = reader.reader_tx.send(packet) {
         log::error!("send error {}", e);
@@ -54,85 +55,8 @@ unsafe extern "C" fn timer_interrupt(arg: *mut c_void) {
     reader.reset();
 }
 
-/// Check parity bits 25 (even) and 0 (odd)
-///
-/// Reference:
-/// https://getsafeandsound.com/blog/26-bit-wiegand-format/
-/// Calculator
-/// http://www.ccdesignworks.com/wiegand_calc.htm
-fn parity_check_26bits(mut rfid: u32) -> bool {
-    // Odd parity is checked over the rightmost 13 bits.
-    let mut count = 0;
-    for _ in 0..13 {
-        count += rfid & 1;
-        rfid >>= 1;
-    }
-    if count % 2 == 0 {
-        return false;
-    }
-
-    // Even parity is checked over the leftmost 13 bits
-    let mut count = 0;
-    for _ in 0..13 {
-        count += rfid & 1;
-        rfid >>= 1;
-    }
-    if count % 2 == 1 {
-        return false;
-    }
-
-    true
-}
-
-#[derive(Debug)]
-pub enum Packet {
-    Key {
-        key: u8,
-    },
-    Card {
-        rfid: i32,
-    },
-    Unknown {
-        bits: usize,
-        data: [u8; BUFFER_SIZE],
-    },
-}
-
-impl Packet {
-    fn new(bits: usize, data: [u8; BUFFER_SIZE]) -> Self {
-        log::info!("data received; bits: {}, data: {:02X?}", bits, data);
-        match bits {
-            4 => Self::Key { key: data[0] >> 4 },
-            26 => {
-                let mut rfid: u32 = (data[0] as u32) << 24
-                    | (data[1] as u32) << 16
-                    | (data[2] as u32) << 8
-                    | (data[3] as u32);
-
-                // Remove padding bits
-                rfid >>= 6;
-
-                if !parity_check_26bits(rfid) {
-                    log::warn!("Parity check failed");
-                    return Self::Unknown { bits, data };
-                }
-
-                // Remove partiy check bits
-                rfid &= !(1 << 25);
-                rfid >>= 1;
-
-                let rfid = rfid as i32;
-
-                Self::Card { rfid }
-            }
-            _ => Self::Unknown { bits, data },
-        }
-    }
-}
-
 pub struct Reader {
-    bits: usize,
-    data: [u8; BUFFER_SIZE],
+    frame: Frame,
     gpio_d0: i32,
     gpio_d1: i32,
     timer: Option<esp_timer_handle_t>,
@@ -146,8 +70,7 @@ impl Reader {
         Reader {
             gpio_d0,
             gpio_d1,
-            data: [0; BUFFER_SIZE],
-            bits: 0,
+            frame: Frame::default(),
             timer: None,
             reader_tx,
             reader_rx,
@@ -213,8 +136,7 @@ impl Reader {
             gpio_set_intr_type(self.gpio_d0, gpio_int_type_t_GPIO_INTR_NEGEDGE);
             gpio_set_intr_type(self.gpio_d1, gpio_int_type_t_GPIO_INTR_NEGEDGE);
         }
-        self.data = [0; BUFFER_SIZE];
-        self.bits = 0;
+        self.frame.clear();
     }
 }
 
@@ -222,7 +144,7 @@ impl Iterator for Reader {
     type Item = Result<Packet, RecvTimeoutError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        Some(self.reader_rx.recv_timeout(PIN_TIMEOUT))
+        Some(self.reader_rx.recv_timeout(POLL_INTERVAL))
     }
 }
 
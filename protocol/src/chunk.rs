@@ -0,0 +1,417 @@
+//! Reassembly of messages the transport delivers in chunks
+
+use std::fmt;
+
+/// A message put back together from its chunks
+#[derive(Debug, PartialEq, Eq)]
+pub struct Assembled {
+    pub topic: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChunkError {
+    /// The message is larger than the reassembler accepts
+    TooLarge { size: usize, max: usize },
+    /// A chunk arrived without the first chunk of its message
+    NotStarted { id: u32 },
+    /// A chunk of another message arrived before the one in progress completed
+    WrongMessage { expected: u32, found: u32 },
+    /// A chunk doesn't start where the previous one ended
+    WrongOffset { expected: usize, found: usize },
+    /// A chunk announces a different size than the first one
+    SizeChanged { expected: usize, found: usize },
+    /// A chunk runs past the end of the message
+    Overrun { size: usize, end: usize },
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkError::TooLarge { size, max } => {
+                write!(f, "message of {size} bytes exceeds the limit of {max}")
+            }
+            ChunkError::NotStarted { id } => write!(f, "chunk of message {id} without a start"),
+            ChunkError::WrongMessage { expected, found } => {
+                write!(
+                    f,
+                    "chunk of message {found} while {expected} is in progress"
+                )
+            }
+            ChunkError::WrongOffset { expected, found } => {
+                write!(f, "chunk at offset {found}, expected {expected}")
+            }
+            ChunkError::SizeChanged { expected, found } => {
+                write!(f, "chunk of a {found} bytes message, expected {expected}")
+            }
+            ChunkError::Overrun { size, end } => {
+                write!(f, "chunk ending at {end} in a message of {size} bytes")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChunkError {}
+
+#[derive(Debug)]
+struct Pending {
+    id: u32,
+    topic: String,
+    size: usize,
+    data: Vec<u8>,
+}
+
+/// Puts chunked messages back together, one message at a time.
+///
+/// The chunks of a message must arrive in order. A new message drops the one
+/// in progress, and any chunk that doesn't fit drops it as well.
+#[derive(Debug)]
+pub struct Reassembler {
+    max_size: usize,
+    pending: Option<Pending>,
+}
+
+impl Reassembler {
+    /// Accepts messages of up to `max_size` bytes
+    pub fn new(max_size: usize) -> Self {
+        Reassembler {
+            max_size,
+            pending: None,
+        }
+    }
+
+    /// Whether a message is waiting for more chunks
+    pub fn in_progress(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Starts message `id` of `size` bytes on `topic` with its first chunk,
+    /// returns it right away when the chunk holds all of it
+    pub fn start(
+        &mut self,
+        id: u32,
+        topic: &str,
+        size: usize,
+        data: &[u8],
+    ) -> Result<Option<Assembled>, ChunkError> {
+        self.pending = None;
+        if size > self.max_size {
+            return Err(ChunkError::TooLarge {
+                size,
+                max: self.max_size,
+            });
+        }
+        if data.len() > size {
+            return Err(ChunkError::Overrun {
+                size,
+                end: data.len(),
+            });
+        }
+        let mut buf = Vec::with_capacity(size);
+        buf.extend_from_slice(data);
+        self.pending = Some(Pending {
+            id,
+            topic: String::from(topic),
+            size,
+            data: buf,
+        });
+        Ok(self.take_complete())
+    }
+
+    /// Adds the chunk of message `id` at `offset`, returns the message once
+    /// its last byte arrives
+    pub fn append(
+        &mut self,
+        id: u32,
+        offset: usize,
+        size: usize,
+        data: &[u8],
+    ) -> Result<Option<Assembled>, ChunkError> {
+        let result = self.check(id, offset, size, data.len());
+        if result.is_err() {
+            self.pending = None;
+        }
+        result?;
+        if let Some(pending) = &mut self.pending {
+            pending.data.extend_from_slice(data);
+        }
+        Ok(self.take_complete())
+    }
+
+    fn check(&self, id: u32, offset: usize, size: usize, len: usize) -> Result<(), ChunkError> {
+        let Some(pending) = &self.pending else {
+            return Err(ChunkError::NotStarted { id });
+        };
+        if id != pending.id {
+            return Err(ChunkError::WrongMessage {
+                expected: pending.id,
+                found: id,
+            });
+        }
+        if offset != pending.data.len() {
+            return Err(ChunkError::WrongOffset {
+                expected: pending.data.len(),
+                found: offset,
+            });
+        }
+        if size != pending.size {
+            return Err(ChunkError::SizeChanged {
+                expected: pending.size,
+                found: size,
+            });
+        }
+        let end = offset.saturating_add(len);
+        if end > pending.size {
+            return Err(ChunkError::Overrun {
+                size: pending.size,
+                end,
+            });
+        }
+        Ok(())
+    }
+
+    fn take_complete(&mut self) -> Option<Assembled> {
+        match &self.pending {
+            Some(pending) if pending.data.len() == pending.size => {
+                self.pending.take().map(|pending| Assembled {
+                    topic: pending.topic,
+                    data: pending.data,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Credential, SiteKey, UserAction, UserUpdate};
+
+    const TOPIC: &str = "doorsys/user/aabbcc";
+
+    fn assembled(data: &[u8]) -> Option<Assembled> {
+        Some(Assembled {
+            topic: String::from(TOPIC),
+            data: data.to_vec(),
+        })
+    }
+
+    #[test]
+    fn reassembles_chunks() {
+        let mut reassembler = Reassembler::new(16);
+        assert_eq!(reassembler.start(1, TOPIC, 10, b"0123"), Ok(None));
+        assert!(reassembler.in_progress());
+        assert_eq!(reassembler.append(1, 4, 10, b"456"), Ok(None));
+        assert_eq!(
+            reassembler.append(1, 7, 10, b"789"),
+            Ok(assembled(b"0123456789"))
+        );
+        assert!(!reassembler.in_progress());
+    }
+
+    #[test]
+    fn single_chunk_completes() {
+        let mut reassembler = Reassembler::new(16);
+        assert_eq!(
+            reassembler.start(1, TOPIC, 3, b"abc"),
+            Ok(assembled(b"abc"))
+        );
+        assert!(!reassembler.in_progress());
+    }
+
+    #[test]
+    fn completes_at_the_limit() {
+        let mut reassembler = Reassembler::new(8);
+        reassembler.start(1, TOPIC, 8, b"0123").unwrap();
+        assert_eq!(
+            reassembler.append(1, 4, 8, b"4567"),
+            Ok(assembled(b"01234567"))
+        );
+    }
+
+    #[test]
+    fn rejects_oversize() {
+        let mut reassembler = Reassembler::new(8);
+        assert_eq!(
+            reassembler.start(1, TOPIC, 9, b"0123"),
+            Err(ChunkError::TooLarge { size: 9, max: 8 })
+        );
+        assert!(!reassembler.in_progress());
+        // The rest of the rejected message is dropped too
+        assert_eq!(
+            reassembler.append(1, 4, 9, b"45678"),
+            Err(ChunkError::NotStarted { id: 1 })
+        );
+    }
+
+    #[test]
+    fn rejects_chunk_past_the_end() {
+        let mut reassembler = Reassembler::new(16);
+        reassembler.start(1, TOPIC, 6, b"0123").unwrap();
+        assert_eq!(
+            reassembler.append(1, 4, 6, b"456"),
+            Err(ChunkError::Overrun { size: 6, end: 7 })
+        );
+        assert!(!reassembler.in_progress());
+    }
+
+    #[test]
+    fn rejects_first_chunk_past_the_end() {
+        let mut reassembler = Reassembler::new(16);
+        assert_eq!(
+            reassembler.start(1, TOPIC, 2, b"0123"),
+            Err(ChunkError::Overrun { size: 2, end: 4 })
+        );
+        assert!(!reassembler.in_progress());
+    }
+
+    #[test]
+    fn rejects_size_change() {
+        let mut reassembler = Reassembler::new(16);
+        reassembler.start(1, TOPIC, 6, b"0123").unwrap();
+        assert_eq!(
+            reassembler.append(1, 4, 8, b"45"),
+            Err(ChunkError::SizeChanged {
+                expected: 6,
+                found: 8
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_chunk_without_start() {
+        let mut reassembler = Reassembler::new(16);
+        assert_eq!(
+            reassembler.append(1, 4, 8, b"4567"),
+            Err(ChunkError::NotStarted { id: 1 })
+        );
+    }
+
+    #[test]
+    fn rejects_gaps_and_repeats() {
+        let mut reassembler = Reassembler::new(16);
+        reassembler.start(1, TOPIC, 10, b"0123").unwrap();
+        assert_eq!(
+            reassembler.append(1, 6, 10, b"6789"),
+            Err(ChunkError::WrongOffset {
+                expected: 4,
+                found: 6
+            })
+        );
+        assert!(!reassembler.in_progress());
+
+        reassembler.start(2, TOPIC, 10, b"0123").unwrap();
+        assert_eq!(
+            reassembler.append(2, 0, 10, b"0123"),
+            Err(ChunkError::WrongOffset {
+                expected: 4,
+                found: 0
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_interleaved_message() {
+        let mut reassembler = Reassembler::new(16);
+        reassembler.start(1, TOPIC, 10, b"0123").unwrap();
+        assert_eq!(
+            reassembler.append(2, 4, 10, b"4567"),
+            Err(ChunkError::WrongMessage {
+                expected: 1,
+                found: 2
+            })
+        );
+        assert!(!reassembler.in_progress());
+    }
+
+    #[test]
+    fn new_message_replaces_unfinished_one() {
+        let mut reassembler = Reassembler::new(16);
+        reassembler.start(1, TOPIC, 10, b"0123").unwrap();
+        reassembler
+            .start(2, "doorsys/device/aabbcc", 6, b"abc")
+            .unwrap();
+        assert_eq!(
+            reassembler.append(1, 4, 10, b"456789"),
+            Err(ChunkError::WrongMessage {
+                expected: 2,
+                found: 1
+            })
+        );
+        reassembler.start(3, TOPIC, 6, b"abc").unwrap();
+        assert_eq!(
+            reassembler.append(3, 3, 6, b"def"),
+            Ok(assembled(b"abcdef"))
+        );
+    }
+
+    #[test]
+    fn reuses_after_completion() {
+        let mut reassembler = Reassembler::new(16);
+        reassembler.start(7, TOPIC, 4, b"ab").unwrap();
+        assert_eq!(reassembler.append(7, 2, 4, b"cd"), Ok(assembled(b"abcd")));
+        // The same id can come around again once the message completed
+        reassembler.start(7, TOPIC, 4, b"wx").unwrap();
+        assert_eq!(reassembler.append(7, 2, 4, b"yz"), Ok(assembled(b"wxyz")));
+    }
+
+    #[test]
+    fn reassembles_uneven_chunks() {
+        // Completion doesn't depend on the capacity the allocator hands out
+        let mut reassembler = Reassembler::new(4096);
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        reassembler
+            .start(1, TOPIC, data.len(), &data[..333])
+            .unwrap();
+        assert_eq!(reassembler.append(1, 333, 1000, &data[333..666]), Ok(None));
+        assert_eq!(
+            reassembler.append(1, 666, 1000, &data[666..]),
+            Ok(assembled(&data))
+        );
+    }
+
+    #[test]
+    fn reassembles_full_sync() {
+        let key = SiteKey {
+            salt: [1; 16],
+            key: [2; 32],
+        };
+        let credentials: Vec<_> = (100_000..101_000)
+            .map(|code| Credential {
+                code: key.hash(code, Some(12)),
+                card: true,
+                schedule: Some(3),
+                pin: Some(key.hash(code % 10_000, None)),
+                duress: None,
+                validity: None,
+            })
+            .collect();
+        let update = UserUpdate {
+            seq: 42,
+            action: UserAction::Sync {
+                key,
+                credentials: credentials.clone(),
+                schedules: Vec::new(),
+            },
+        };
+        let payload = crate::encode(&update).unwrap();
+        let mut reassembler = Reassembler::new(48 * 1024);
+        let mut chunks = payload.chunks(1024);
+        let first = chunks.next().unwrap();
+        let mut message = reassembler.start(9, TOPIC, payload.len(), first).unwrap();
+        let mut offset = first.len();
+        for chunk in chunks {
+            assert!(message.is_none());
+            message = reassembler.append(9, offset, payload.len(), chunk).unwrap();
+            offset += chunk.len();
+        }
+        let message = message.unwrap();
+        assert_eq!(message.topic, TOPIC);
+        let decoded: UserUpdate = crate::decode(&message.data).unwrap();
+        assert_eq!(decoded.seq, 42);
+        assert!(
+            matches!(decoded.action, UserAction::Sync { credentials: c, .. } if c == credentials)
+        );
+    }
+}
@@ -0,0 +1,309 @@
+use std::fmt;
+
+use bincode::{
+    config::Configuration,
+    error::{DecodeError, EncodeError},
+    Decode, Encode,
+};
+
+/// Marks a payload as an enveloped doorsys message
+pub const MAGIC: [u8; 4] = *b"DSYS";
+
+/// Current version of the wire format.
+///
+/// Bump it whenever the layout of an existing message changes and teach the
+/// affected [`Message::upgrade`] how to read the previous layout. Appending new
+/// enum variants or message kinds does not require a bump.
+pub const PROTOCOL_VERSION: u8 = 8;
+
+pub(crate) const BINCODE_CONFIG: Configuration = bincode::config::standard();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Audit,
+    UserUpdate,
+    Ack,
+    Digest,
+    DeviceCommand,
+    FirmwareStatus,
+    Alert,
+    ResyncRequest,
+}
+
+impl From<MessageKind> for u8 {
+    fn from(kind: MessageKind) -> Self {
+        match kind {
+            MessageKind::Audit => 0,
+            MessageKind::UserUpdate => 1,
+            MessageKind::Ack => 2,
+            MessageKind::Digest => 3,
+            MessageKind::DeviceCommand => 4,
+            MessageKind::FirmwareStatus => 5,
+            MessageKind::Alert => 6,
+            MessageKind::ResyncRequest => 7,
+        }
+    }
+}
+
+impl TryFrom<u8> for MessageKind {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(MessageKind::Audit),
+            1 => Ok(MessageKind::UserUpdate),
+            2 => Ok(MessageKind::Ack),
+            3 => Ok(MessageKind::Digest),
+            4 => Ok(MessageKind::DeviceCommand),
+            5 => Ok(MessageKind::FirmwareStatus),
+            6 => Ok(MessageKind::Alert),
+            7 => Ok(MessageKind::ResyncRequest),
+            k => Err(ProtocolError::UnknownKind(k)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    UnexpectedKind {
+        expected: MessageKind,
+        found: MessageKind,
+    },
+    Encode(EncodeError),
+    Decode(DecodeError),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::BadMagic => write!(f, "not a doorsys message"),
+            ProtocolError::UnsupportedVersion(v) => {
+                write!(f, "unsupported protocol version {v}")
+            }
+            ProtocolError::UnknownKind(k) => write!(f, "unknown message kind {k}"),
+            ProtocolError::UnexpectedKind { expected, found } => {
+                write!(f, "expected {expected:?} message but found {found:?}")
+            }
+            ProtocolError::Encode(e) => write!(f, "encoding error: {e}"),
+            ProtocolError::Decode(e) => write!(f, "decoding error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<EncodeError> for ProtocolError {
+    fn from(e: EncodeError) -> Self {
+        ProtocolError::Encode(e)
+    }
+}
+
+impl From<DecodeError> for ProtocolError {
+    fn from(e: DecodeError) -> Self {
+        ProtocolError::Decode(e)
+    }
+}
+
+/// A type that can travel inside an [`Envelope`]
+pub trait Message: Encode + Decode<()> + Sized {
+    const KIND: MessageKind;
+
+    /// Reads a payload written by an older protocol version.
+    ///
+    /// Version 0 stands for the bare bincode payloads sent before the envelope
+    /// existed. Versions that can't be converted are rejected.
+    fn upgrade(version: u8, payload: &[u8]) -> Result<Self, ProtocolError> {
+        let _ = payload;
+        Err(ProtocolError::UnsupportedVersion(version))
+    }
+}
+
+/// Wire container for every doorsys message.
+///
+/// Layout: magic, protocol version, message kind and the bincode payload.
+#[derive(Debug)]
+pub struct Envelope {
+    pub version: u8,
+    pub kind: MessageKind,
+    pub payload: Vec<u8>,
+}
+
+impl Envelope {
+    pub fn new<T: Message>(message: &T) -> Result<Self, ProtocolError> {
+        Ok(Envelope {
+            version: PROTOCOL_VERSION,
+            kind: T::KIND,
+            payload: bincode::encode_to_vec(message, BINCODE_CONFIG)?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtocolError> {
+        let header = (MAGIC, self.version, u8::from(self.kind));
+        let mut buf = bincode::encode_to_vec(header, BINCODE_CONFIG)?;
+        buf.extend_from_slice(&self.payload);
+        Ok(buf)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        if !bytes.starts_with(&MAGIC) {
+            return Err(ProtocolError::BadMagic);
+        }
+        let ((_, version, kind), len): (([u8; 4], u8, u8), _) =
+            bincode::decode_from_slice(bytes, BINCODE_CONFIG)?;
+        Ok(Envelope {
+            version,
+            kind: kind.try_into()?,
+            payload: bytes[len..].to_vec(),
+        })
+    }
+
+    /// Extracts the message, converting payloads from older versions
+    pub fn open<T: Message>(&self) -> Result<T, ProtocolError> {
+        if self.kind != T::KIND {
+            return Err(ProtocolError::UnexpectedKind {
+                expected: T::KIND,
+                found: self.kind,
+            });
+        }
+        match self.version {
+            PROTOCOL_VERSION => decode_payload(&self.payload),
+            v if v > PROTOCOL_VERSION => Err(ProtocolError::UnsupportedVersion(v)),
+            v => T::upgrade(v, &self.payload),
+        }
+    }
+}
+
+/// Decodes a bare bincode payload
+pub fn decode_payload<T: Decode<()>>(payload: &[u8]) -> Result<T, ProtocolError> {
+    let (message, _) = bincode::decode_from_slice(payload, BINCODE_CONFIG)?;
+    Ok(message)
+}
+
+/// Wraps the message in an envelope and serializes it
+pub fn encode<T: Message>(message: &T) -> Result<Vec<u8>, ProtocolError> {
+    Envelope::new(message)?.to_bytes()
+}
+
+/// Decodes an enveloped message, falling back to the legacy bare format
+pub fn decode<T: Message>(bytes: &[u8]) -> Result<T, ProtocolError> {
+    match Envelope::from_bytes(bytes) {
+        Ok(envelope) => envelope.open(),
+        Err(ProtocolError::BadMagic) => T::upgrade(0, bytes),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Encode, Decode)]
+    struct Ping(u32);
+
+    impl Message for Ping {
+        const KIND: MessageKind = MessageKind::Ack;
+
+        fn upgrade(version: u8, payload: &[u8]) -> Result<Self, ProtocolError> {
+            match version {
+                0 => decode_payload(payload),
+                v => Err(ProtocolError::UnsupportedVersion(v)),
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq, Encode, Decode)]
+    struct Pong(u32);
+
+    impl Message for Pong {
+        const KIND: MessageKind = MessageKind::Digest;
+    }
+
+    fn with_version(version: u8, payload: &[u8]) -> Vec<u8> {
+        Envelope {
+            version,
+            kind: MessageKind::Ack,
+            payload: payload.to_vec(),
+        }
+        .to_bytes()
+        .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = encode(&Ping(42)).unwrap();
+        assert!(bytes.starts_with(&MAGIC));
+        assert_eq!(bytes[4], PROTOCOL_VERSION);
+        assert_eq!(decode::<Ping>(&bytes).unwrap(), Ping(42));
+    }
+
+    #[test]
+    fn every_kind_round_trips() {
+        for value in 0..=7 {
+            let kind = MessageKind::try_from(value).unwrap();
+            assert_eq!(u8::from(kind), value);
+        }
+        assert!(matches!(
+            MessageKind::try_from(8),
+            Err(ProtocolError::UnknownKind(8))
+        ));
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut bytes = encode(&Ping(42)).unwrap();
+        bytes[0] = b'X';
+        assert!(matches!(
+            Envelope::from_bytes(&bytes),
+            Err(ProtocolError::BadMagic)
+        ));
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let payload = bincode::encode_to_vec(Ping(42), BINCODE_CONFIG).unwrap();
+        let bytes = with_version(PROTOCOL_VERSION + 1, &payload);
+        assert!(matches!(
+            decode::<Ping>(&bytes),
+            Err(ProtocolError::UnsupportedVersion(v)) if v == PROTOCOL_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn older_version_goes_through_upgrade() {
+        let payload = bincode::encode_to_vec(Ping(42), BINCODE_CONFIG).unwrap();
+        assert!(matches!(
+            decode::<Ping>(&with_version(1, &payload)),
+            Err(ProtocolError::UnsupportedVersion(1))
+        ));
+        assert!(matches!(
+            decode::<Pong>(&with_version(1, &payload)),
+            Err(ProtocolError::UnexpectedKind {
+                expected: MessageKind::Digest,
+                found: MessageKind::Ack,
+            })
+        ));
+    }
+
+    #[test]
+    fn bare_payload_falls_back_to_legacy() {
+        let payload = bincode::encode_to_vec(Ping(42), BINCODE_CONFIG).unwrap();
+        assert_eq!(decode::<Ping>(&payload).unwrap(), Ping(42));
+        assert!(matches!(
+            decode::<Pong>(&payload),
+            Err(ProtocolError::UnsupportedVersion(0))
+        ));
+    }
+
+    #[test]
+    fn unknown_kind_is_rejected() {
+        let mut bytes = encode(&Ping(42)).unwrap();
+        bytes[5] = 200;
+        assert!(matches!(
+            Envelope::from_bytes(&bytes),
+            Err(ProtocolError::UnknownKind(200))
+        ));
+    }
+}
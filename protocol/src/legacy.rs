@@ -0,0 +1,90 @@
+//! Message layouts from older protocol versions, kept to upgrade old payloads
+
+use std::time::SystemTime;
+
+use bincode::Decode;
+
+use crate::{Audit, CodeType, Reason};
+
+/// Audit layout up to version 2, before denial reasons
+#[derive(Decode)]
+pub(crate) struct AuditV2 {
+    timestamp: SystemTime,
+    code: i32,
+    code_type: CodeType,
+    success: bool,
+}
+
+impl From<AuditV2> for Audit {
+    fn from(audit: AuditV2) -> Self {
+        let reason = match audit.success {
+            true => Reason::Granted,
+            false => Reason::UnknownCode,
+        };
+        AuditV3 {
+            timestamp: audit.timestamp,
+            code: audit.code,
+            code_type: audit.code_type,
+            success: audit.success,
+            reason,
+        }
+        .into()
+    }
+}
+
+/// Audit layout of version 3, before facility codes
+#[derive(Decode)]
+pub(crate) struct AuditV3 {
+    timestamp: SystemTime,
+    code: i32,
+    code_type: CodeType,
+    success: bool,
+    reason: Reason,
+}
+
+impl From<AuditV3> for Audit {
+    /// Cards were only read as 26-bit, with the facility code in the upper
+    /// bits of the code
+    fn from(audit: AuditV3) -> Self {
+        let (code, facility) = match audit.code_type {
+            CodeType::Fob if audit.code != 0 => {
+                (audit.code & 0xffff, Some((audit.code >> 16) as u32))
+            }
+            _ => (audit.code, None),
+        };
+        Audit {
+            timestamp: audit.timestamp,
+            code,
+            code_type: audit.code_type,
+            success: audit.success,
+            reason: audit.reason,
+            facility,
+            duress: false,
+        }
+    }
+}
+
+/// Audit layout of versions 4 and 5, before duress pins
+#[derive(Decode)]
+pub(crate) struct AuditV5 {
+    timestamp: SystemTime,
+    code: i32,
+    code_type: CodeType,
+    success: bool,
+    reason: Reason,
+    facility: Option<u32>,
+}
+
+impl From<AuditV5> for Audit {
+    fn from(audit: AuditV5) -> Self {
+        Audit {
+            timestamp: audit.timestamp,
+            code: audit.code,
+            code_type: audit.code_type,
+            success: audit.success,
+            reason: audit.reason,
+            facility: audit.facility,
+            duress: false,
+        }
+    }
+}
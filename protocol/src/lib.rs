@@ -1,18 +1,96 @@
-use std::time::SystemTime;
+mod chunk;
+mod envelope;
+mod hash;
+mod legacy;
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use bincode::{Decode, Encode};
 
+pub use chunk::{Assembled, ChunkError, Reassembler};
+pub use envelope::{
+    decode, decode_payload, encode, Envelope, Message, MessageKind, ProtocolError, MAGIC,
+    PROTOCOL_VERSION,
+};
+pub use hash::{CodeHash, SiteKey};
+
 #[derive(Debug, Encode, Decode)]
 pub enum CodeType {
     Pin,
     Fob,
+    /// Request to exit button, the code is always 0
+    Button,
 }
 
-impl ToString for CodeType {
-    fn to_string(&self) -> String {
+impl fmt::Display for CodeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            CodeType::Pin => String::from("pin"),
-            CodeType::Fob => String::from("fob"),
+            CodeType::Pin => write!(f, "pin"),
+            CodeType::Fob => write!(f, "fob"),
+            CodeType::Button => write!(f, "button"),
+        }
+    }
+}
+
+/// Why access was granted or denied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum Reason {
+    Granted,
+    UnknownCode,
+    OutsideSchedule,
+    /// The code belongs to a disabled user. Doors drop those codes, so this is
+    /// only assigned by the server when it recognizes an unknown code.
+    UserDisabled,
+    /// Card read failed the Wiegand parity check. Current doors raise a
+    /// [`AlertKind::ReaderNoise`] alert instead.
+    ParityError,
+    /// Card read with a bit count no format matches. Current doors raise a
+    /// [`AlertKind::ReaderNoise`] alert instead.
+    UnknownFormat,
+    /// More keys were pressed than a pin can hold
+    PinTooLong,
+    /// Pin entry timed out before the confirmation key
+    IncompletePin,
+    /// Pin entry cancelled with the cancel key
+    Cancelled,
+    /// Card facility code is not allowed on the door
+    WrongFacility,
+    /// Card presented without the pin following it in time
+    PinRequired,
+    /// Pin entered after the card doesn't belong to the card holder
+    PinMismatch,
+    /// Pin entered alone on a door that requires a card first
+    CardRequired,
+    /// Too many failed attempts, the keypad is locked
+    LockedOut,
+    /// Visitor code used before its start time
+    NotYetValid,
+    /// Visitor code past its end time or out of uses
+    Expired,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Granted => write!(f, "granted"),
+            Reason::UnknownCode => write!(f, "unknown_code"),
+            Reason::OutsideSchedule => write!(f, "outside_schedule"),
+            Reason::UserDisabled => write!(f, "user_disabled"),
+            Reason::ParityError => write!(f, "parity_error"),
+            Reason::UnknownFormat => write!(f, "unknown_format"),
+            Reason::PinTooLong => write!(f, "pin_too_long"),
+            Reason::IncompletePin => write!(f, "incomplete_pin"),
+            Reason::Cancelled => write!(f, "cancelled"),
+            Reason::WrongFacility => write!(f, "wrong_facility"),
+            Reason::PinRequired => write!(f, "pin_required"),
+            Reason::PinMismatch => write!(f, "pin_mismatch"),
+            Reason::CardRequired => write!(f, "card_required"),
+            Reason::LockedOut => write!(f, "locked_out"),
+            Reason::NotYetValid => write!(f, "not_yet_valid"),
+            Reason::Expired => write!(f, "expired"),
         }
     }
 }
@@ -20,15 +98,794 @@ impl ToString for CodeType {
 #[derive(Debug, Encode, Decode)]
 pub struct Audit {
     pub timestamp: SystemTime,
+    /// Pin or card number in the clear, the api needs it to tell who entered.
+    /// Doors keep it in encrypted nvs only until the broker takes the audit.
     pub code: i32,
     pub code_type: CodeType,
     pub success: bool,
+    pub reason: Reason,
+    /// Facility code of the card read, `None` for pins and unreadable cards
+    pub facility: Option<u32>,
+    /// Entered with the holder's duress pin, the code is the duress pin.
+    /// Doors before version 8 reported the regular pin instead.
+    pub duress: bool,
 }
 
+impl Message for Audit {
+    const KIND: MessageKind = MessageKind::Audit;
+
+    fn upgrade(version: u8, payload: &[u8]) -> Result<Self, ProtocolError> {
+        match version {
+            0..=2 => Ok(decode_payload::<legacy::AuditV2>(payload)?.into()),
+            3 => Ok(decode_payload::<legacy::AuditV3>(payload)?.into()),
+            4 | 5 => Ok(decode_payload::<legacy::AuditV5>(payload)?.into()),
+            6 | 7 => decode_payload(payload),
+            v => Err(ProtocolError::UnsupportedVersion(v)),
+        }
+    }
+}
+
+/// Weekly time window in the schedule's local time
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct TimeWindow {
+    /// Bit mask of the days the window opens on, bit 0 is Sunday
+    pub weekdays: u8,
+    /// Minutes since midnight, inclusive
+    pub start: u16,
+    /// Minutes since midnight, exclusive. Windows ending before they start
+    /// run past midnight into the next day.
+    pub end: u16,
+}
+
+impl TimeWindow {
+    fn opens_on(&self, weekday: u8) -> bool {
+        self.weekdays & (1 << (weekday % 7)) != 0
+    }
+
+    /// Checks the window against a weekday (0 is Sunday) and minute of the day
+    pub fn contains(&self, weekday: u8, minute: u16) -> bool {
+        if self.start <= self.end {
+            self.opens_on(weekday) && (self.start..self.end).contains(&minute)
+        } else {
+            (self.opens_on(weekday) && minute >= self.start)
+                || (self.opens_on(weekday + 6) && minute < self.end)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct Schedule {
+    pub id: u32,
+    /// POSIX TZ string, e.g. `EST5EDT,M3.2.0,M11.1.0`
+    pub timezone: String,
+    pub windows: Vec<TimeWindow>,
+}
+
+impl Schedule {
+    /// Checks the local weekday (0 is Sunday) and minute of the day against every window
+    pub fn allows(&self, weekday: u8, minute: u16) -> bool {
+        self.windows.iter().any(|w| w.contains(weekday, minute))
+    }
+}
+
+/// A code accepted by the door, optionally restricted to a schedule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct Credential {
+    /// Hash of the pin, or of the card number along with its facility code
+    pub code: CodeHash,
+    /// Whether the code belongs to a card rather than a pin
+    pub card: bool,
+    pub schedule: Option<u32>,
+    /// Hash of the card holder's pin that must follow the card, `None` opens
+    /// on the card alone unless the door requires both
+    pub pin: Option<CodeHash>,
+    /// Hash of the duress variant of the pin, or of the pin following the
+    /// card. It opens the same way and flags the audit.
+    pub duress: Option<CodeHash>,
+    /// Limits of a visitor code, `None` for codes that don't expire
+    pub validity: Option<Validity>,
+}
+
+/// When and how many times a visitor code opens the door
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct Validity {
+    /// Refused before this time
+    pub start: Option<SystemTime>,
+    /// Refused from this time on, doors drop the code once it passes
+    pub end: Option<SystemTime>,
+    /// Entries left, doors count them down and drop the code when none remain.
+    /// A code sent again keeps the lower of the two counts.
+    pub uses: Option<u32>,
+}
+
+impl Validity {
+    pub fn started(&self, now: SystemTime) -> bool {
+        match self.start {
+            Some(start) => now >= start,
+            None => true,
+        }
+    }
+
+    /// Whether the end time passed or the uses ran out
+    pub fn expired(&self, now: SystemTime) -> bool {
+        self.end.is_some_and(|end| now >= end) || self.uses == Some(0)
+    }
+}
+
+/// Most codes a door stores, it refuses a sync carrying more
+pub const DEVICE_CAPACITY: usize = 1000;
+
+/// Changes to the codes a door holds, codes travel hashed with the
+/// [`SiteKey`] the door received on its last sync
 #[derive(Debug, Encode, Decode)]
 pub enum UserAction {
-    Add(i32),
-    Del(i32),
-    Replace { old: i32, new: i32 },
-    Bulk(Vec<i32>),
+    /// Adds the credential or updates it
+    Put(Credential),
+    /// Removes a pin or card
+    Del(CodeHash),
+    /// Replaces a pin keeping what it grants, refused by doors without the old pin
+    Replace {
+        old: CodeHash,
+        new: CodeHash,
+    },
+    SetSchedule(Schedule),
+    DelSchedule(u32),
+    /// Replaces every credential and schedule on the device along with the
+    /// key they are hashed with
+    Sync {
+        key: SiteKey,
+        credentials: Vec<Credential>,
+        schedules: Vec<Schedule>,
+    },
+}
+
+/// A [`UserAction`] tagged with the sequence number devices acknowledge.
+///
+/// Sequence 0 means the sender doesn't track delivery.
+#[derive(Debug, Encode, Decode)]
+pub struct UserUpdate {
+    pub seq: u64,
+    pub action: UserAction,
+}
+
+impl Message for UserUpdate {
+    const KIND: MessageKind = MessageKind::UserUpdate;
+
+    /// Versions prior to 8 carried plain codes, they can't be hashed without
+    /// the site key so doors wait for the next sync instead
+    fn upgrade(version: u8, _payload: &[u8]) -> Result<Self, ProtocolError> {
+        Err(ProtocolError::UnsupportedVersion(version))
+    }
+}
+
+/// Sent by a device after applying (or failing to apply) a [`UserUpdate`]
+#[derive(Debug, Encode, Decode)]
+pub struct Ack {
+    pub seq: u64,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+impl Message for Ack {
+    const KIND: MessageKind = MessageKind::Ack;
+
+    /// Unchanged since it was introduced in version 2
+    fn upgrade(version: u8, payload: &[u8]) -> Result<Self, ProtocolError> {
+        match version {
+            2..=7 => decode_payload(payload),
+            v => Err(ProtocolError::UnsupportedVersion(v)),
+        }
+    }
+}
+
+/// Summary of the credentials stored on a device, used to detect drift
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct Digest {
+    pub count: u32,
+    pub hash: u64,
+}
+
+impl Digest {
+    const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    /// Computes the FNV-1a hash of the code hashes, schedules, pins, duress
+    /// pins and visitor time limits. The uses left are counted down on both
+    /// sides independently and left out.
+    ///
+    /// Credentials must be sorted by code hash and free of duplicates so both
+    /// sides of the wire produce the same value.
+    pub fn from_credentials(credentials: impl IntoIterator<Item = Credential>) -> Self {
+        let mut count = 0;
+        let mut hash = Self::FNV_OFFSET;
+        for credential in credentials {
+            count += 1;
+            let schedule = credential.schedule.map_or(0, |id| id as u64 + 1);
+            let pin = credential.pin.map_or([0; 16], |pin| pin.0);
+            let duress = credential.duress.map_or([0; 16], |pin| pin.0);
+            let (start, end) = credential.validity.map_or((0, 0), |validity| {
+                let start = validity.start.map_or(0, |t| epoch_secs(t) + 1);
+                let end = validity.end.map_or(0, |t| epoch_secs(t) + 1);
+                (start, end)
+            });
+            let bytes = credential.code.0.into_iter();
+            for byte in bytes
+                .chain([credential.card as u8])
+                .chain(schedule.to_le_bytes())
+                .chain([credential.pin.is_some() as u8])
+                .chain(pin)
+                .chain([credential.duress.is_some() as u8])
+                .chain(duress)
+                .chain(start.to_le_bytes())
+                .chain(end.to_le_bytes())
+            {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(Self::FNV_PRIME);
+            }
+        }
+        Digest { count, hash }
+    }
+}
+
+fn epoch_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl Message for Digest {
+    const KIND: MessageKind = MessageKind::Digest;
+
+    /// Unchanged since it was introduced in version 2
+    fn upgrade(version: u8, payload: &[u8]) -> Result<Self, ProtocolError> {
+        match version {
+            2..=7 => decode_payload(payload),
+            v => Err(ProtocolError::UnsupportedVersion(v)),
+        }
+    }
+}
+
+/// Commands addressed to a single device
+#[derive(Debug, Encode, Decode)]
+pub enum DeviceCommand {
+    /// Opens the door, `None` uses the device default
+    Unlock {
+        duration: Option<Duration>,
+    },
+    /// Downloads the image from `url` into the spare OTA slot and boots it
+    Update {
+        job: u64,
+        url: String,
+        sha256: [u8; 32],
+    },
+    /// Replaces the given network credentials and restarts the device
+    SetNetwork(NetworkConfig),
+    /// Replaces the broker certificates and restarts the device
+    SetTls(TlsConfig),
+    /// Facility codes accepted by the door, empty accepts any
+    SetFacilities(Vec<u32>),
+    /// Replaces the keypad settings
+    SetKeypad(KeypadConfig),
+    SetSecurityMode(SecurityMode),
+    SetLockout(LockoutConfig),
+    SetDoorSensor(DoorSensorConfig),
+    SetRelay(RelayConfig),
+    SetButton(ButtonConfig),
+}
+
+/// Credentials a door needs before it opens
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Encode, Decode)]
+pub enum SecurityMode {
+    /// A card or a pin, cards paired with a pin still require it
+    #[default]
+    CardOrPin,
+    /// Every card must be followed by its holder's pin, pins alone are refused
+    CardAndPin,
+}
+
+/// Network credentials provisioned on a device, `None` keeps the current value
+#[derive(Debug, Default, Encode, Decode)]
+pub struct NetworkConfig {
+    pub wifi_ssid: Option<String>,
+    pub wifi_pass: Option<String>,
+    pub mqtt_url: Option<String>,
+    pub mqtt_user: Option<String>,
+    pub mqtt_pass: Option<String>,
+}
+
+/// PEM certificates used for the broker connection, `None` keeps the current
+/// value and an empty string removes it
+#[derive(Debug, Default, Encode, Decode)]
+pub struct TlsConfig {
+    /// CA or pinned server certificate
+    pub ca_cert: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+/// How the keypad reports key presses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum KeypadMode {
+    /// One 4-bit frame per key
+    FourBit,
+    /// One 8-bit frame per key, the key's complement followed by the key
+    EightBit,
+    /// The whole pin as a 26-bit frame once the submit key is pressed
+    Burst,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct KeypadConfig {
+    pub mode: KeypadMode,
+    pub min_pin_length: u8,
+    pub max_pin_length: u8,
+    pub submit_key: u8,
+    pub cancel_key: u8,
+    /// Submits the pin as soon as it reaches the maximum length
+    pub auto_submit: bool,
+    /// Facility code burst keypads send pins with
+    pub burst_facility: u32,
+}
+
+impl Default for KeypadConfig {
+    fn default() -> Self {
+        KeypadConfig {
+            mode: KeypadMode::FourBit,
+            min_pin_length: 1,
+            max_pin_length: 8,
+            submit_key: 0x0B,
+            cancel_key: 0x0A,
+            auto_submit: false,
+            burst_facility: 0,
+        }
+    }
+}
+
+/// Pins must fit an i32 on the device
+pub const MAX_PIN_LENGTH: u8 = 9;
+
+impl KeypadConfig {
+    /// Checks the settings leave a usable keypad, returning why they don't
+    pub fn validate(&self) -> Option<&'static str> {
+        if !(1..=self.max_pin_length).contains(&self.min_pin_length)
+            || self.max_pin_length > MAX_PIN_LENGTH
+        {
+            return Some("pin length must be between 1 and 9 with min not above max");
+        }
+        if self.submit_key > 0x0f || self.cancel_key > 0x0f {
+            return Some("keys must be between 0 and 15");
+        }
+        if self.submit_key < 10 || self.cancel_key < 10 || self.submit_key == self.cancel_key {
+            return Some("submit and cancel keys must be distinct non digit keys");
+        }
+        None
+    }
+}
+
+/// Keypad lockout after repeated failed attempts
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct LockoutConfig {
+    /// Failed attempts within the window that lock the keypad, 0 disables the lockout
+    pub max_failures: u8,
+    /// Time the failures are counted over
+    pub window: Duration,
+    /// Time the keypad stays locked
+    pub duration: Duration,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        LockoutConfig {
+            max_failures: 5,
+            window: Duration::from_secs(60),
+            duration: Duration::from_secs(300),
+        }
+    }
+}
+
+impl Message for DeviceCommand {
+    const KIND: MessageKind = MessageKind::DeviceCommand;
+
+    /// Only gained variants since it was introduced in version 2
+    fn upgrade(version: u8, payload: &[u8]) -> Result<Self, ProtocolError> {
+        match version {
+            2..=7 => decode_payload(payload),
+            v => Err(ProtocolError::UnsupportedVersion(v)),
+        }
+    }
+}
+
+/// Door position sensor
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct DoorSensorConfig {
+    /// Whether a sensor is wired, without one the relay just times out
+    pub enabled: bool,
+    /// Time the door can stay open after an unlock
+    pub held_open: Duration,
+}
+
+impl Default for DoorSensorConfig {
+    fn default() -> Self {
+        DoorSensorConfig {
+            enabled: false,
+            held_open: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Door relay wiring
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct RelayConfig {
+    /// Time the door stays unlocked when the unlock doesn't set one
+    pub unlock_time: Duration,
+    /// The relay triggers on a low output
+    pub active_low: bool,
+    /// The lock releases without power, like maglocks, instead of staying
+    /// locked like most strikes
+    pub fail_safe: bool,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        RelayConfig {
+            unlock_time: Duration::from_secs(4),
+            active_low: false,
+            fail_safe: false,
+        }
+    }
+}
+
+/// What holding the exit button down does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Encode, Decode)]
+pub enum LongPress {
+    /// Nothing more, the door unlocks as soon as the button goes down
+    #[default]
+    Unlock,
+    /// Keeps the door unlocked until the button is pressed again
+    HoldOpen,
+    /// Restarted the device into the provisioning console. Doors no longer
+    /// honor it and unlock as soon as the button goes down, the console is
+    /// entered by typing `provision` on the serial console instead.
+    Provision,
+}
+
+/// Request to exit button
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct ButtonConfig {
+    /// Time the button must be held for a long press
+    pub long_press: Duration,
+    pub long_press_action: LongPress,
+}
+
+impl Default for ButtonConfig {
+    fn default() -> Self {
+        ButtonConfig {
+            long_press: Duration::from_secs(3),
+            long_press_action: LongPress::Unlock,
+        }
+    }
+}
+
+/// Security event raised by a device
+#[derive(Debug, Encode, Decode)]
+pub enum AlertKind {
+    /// The keypad locked after too many failed attempts
+    Lockout { failures: u8, duration: Duration },
+    /// The door opened without an unlock
+    DoorForced,
+    /// The door stayed open for longer than allowed
+    DoorHeldOpen { held_open: Duration },
+    /// The door closed after being forced or held open
+    DoorClosed,
+    /// The reader sent frames that couldn't be decoded, summed up over the
+    /// period instead of audited one by one
+    ReaderNoise { frames: u32, period: Duration },
+    /// The audit log filled up while the broker was unreachable and dropped
+    /// its oldest records before they were published
+    AuditsDropped { records: u32 },
+}
+
+impl fmt::Display for AlertKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlertKind::Lockout { .. } => write!(f, "lockout"),
+            AlertKind::DoorForced => write!(f, "door_forced"),
+            AlertKind::DoorHeldOpen { .. } => write!(f, "door_held_open"),
+            AlertKind::DoorClosed => write!(f, "door_closed"),
+            AlertKind::ReaderNoise { .. } => write!(f, "reader_noise"),
+            AlertKind::AuditsDropped { .. } => write!(f, "audits_dropped"),
+        }
+    }
+}
+
+/// Sent by a device as soon as something needs attention
+#[derive(Debug, Encode, Decode)]
+pub struct Alert {
+    pub timestamp: SystemTime,
+    pub kind: AlertKind,
+}
+
+impl Message for Alert {
+    const KIND: MessageKind = MessageKind::Alert;
+
+    /// Unchanged since it was introduced in version 5
+    fn upgrade(version: u8, payload: &[u8]) -> Result<Self, ProtocolError> {
+        match version {
+            5..=7 => decode_payload(payload),
+            v => Err(ProtocolError::UnsupportedVersion(v)),
+        }
+    }
+}
+
+#[derive(Debug, Encode, Decode)]
+pub enum UpdateState {
+    /// Percentage of the image written so far
+    Downloading(u8),
+    /// Image verified, the device is restarting into it
+    Rebooting,
+    /// The new image booted and was marked valid
+    Completed {
+        version: String,
+    },
+    /// The new image failed to boot and the previous one is running again
+    RolledBack,
+    Failed(String),
+}
+
+/// Progress of a firmware update reported by the device
+#[derive(Debug, Encode, Decode)]
+pub struct FirmwareStatus {
+    pub job: u64,
+    pub state: UpdateState,
+}
+
+impl Message for FirmwareStatus {
+    const KIND: MessageKind = MessageKind::FirmwareStatus;
+
+    /// Unchanged since it was introduced in version 3
+    fn upgrade(version: u8, payload: &[u8]) -> Result<Self, ProtocolError> {
+        match version {
+            3..=7 => decode_payload(payload),
+            v => Err(ProtocolError::UnsupportedVersion(v)),
+        }
+    }
+}
+
+/// Sent by a device that lost stored codes, asks for all of them again
+#[derive(Debug, Encode, Decode)]
+pub struct ResyncRequest {
+    /// Pages of the code store that failed their integrity check
+    pub corrupted: u32,
+}
+
+impl Message for ResyncRequest {
+    const KIND: MessageKind = MessageKind::ResyncRequest;
+
+    /// Unchanged since it was introduced in version 7
+    fn upgrade(version: u8, payload: &[u8]) -> Result<Self, ProtocolError> {
+        match version {
+            7 => decode_payload(payload),
+            v => Err(ProtocolError::UnsupportedVersion(v)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUNDAY: u8 = 0;
+    const MONDAY: u8 = 1;
+    const TUESDAY: u8 = 2;
+    const FRIDAY: u8 = 5;
+    const SATURDAY: u8 = 6;
+
+    /// Monday to Friday
+    const WEEKDAYS: u8 = 0b0111110;
+
+    fn window(weekdays: u8, start: u16, end: u16) -> TimeWindow {
+        TimeWindow {
+            weekdays,
+            start,
+            end,
+        }
+    }
+
+    fn credential(code: u8) -> Credential {
+        Credential {
+            code: CodeHash([code; 16]),
+            card: false,
+            schedule: None,
+            pin: None,
+            duress: None,
+            validity: None,
+        }
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let office = window(WEEKDAYS, 9 * 60, 17 * 60);
+        assert!(office.contains(MONDAY, 9 * 60));
+        assert!(office.contains(FRIDAY, 17 * 60 - 1));
+        assert!(!office.contains(MONDAY, 9 * 60 - 1));
+        assert!(!office.contains(MONDAY, 17 * 60));
+        assert!(!office.contains(SATURDAY, 12 * 60));
+        assert!(!office.contains(SUNDAY, 12 * 60));
+    }
+
+    #[test]
+    fn overnight_window_runs_into_the_next_day() {
+        let night = window(WEEKDAYS, 22 * 60, 6 * 60);
+        assert!(night.contains(MONDAY, 23 * 60));
+        assert!(night.contains(TUESDAY, 5 * 60));
+        assert!(!night.contains(TUESDAY, 6 * 60));
+        assert!(!night.contains(MONDAY, 5 * 60), "sunday night is closed");
+        assert!(night.contains(SATURDAY, 5 * 60), "friday night spills over");
+        assert!(!night.contains(SATURDAY, 23 * 60));
+    }
+
+    #[test]
+    fn overnight_window_wraps_from_saturday_to_sunday() {
+        let weekend = window(1 << SATURDAY, 20 * 60, 4 * 60);
+        assert!(weekend.contains(SATURDAY, 21 * 60));
+        assert!(weekend.contains(SUNDAY, 3 * 60));
+        assert!(!weekend.contains(SUNDAY, 21 * 60));
+        assert!(!weekend.contains(SATURDAY, 3 * 60));
+    }
+
+    #[test]
+    fn schedule_allows_any_window() {
+        let schedule = Schedule {
+            id: 1,
+            timezone: String::from("UTC0"),
+            windows: vec![
+                window(WEEKDAYS, 9 * 60, 17 * 60),
+                window(1 << SATURDAY, 10 * 60, 12 * 60),
+            ],
+        };
+        assert!(schedule.allows(MONDAY, 10 * 60));
+        assert!(schedule.allows(SATURDAY, 11 * 60));
+        assert!(!schedule.allows(SATURDAY, 13 * 60));
+        assert!(!schedule.allows(SUNDAY, 11 * 60));
+
+        let closed = Schedule {
+            windows: vec![],
+            ..schedule
+        };
+        assert!(!closed.allows(MONDAY, 10 * 60));
+    }
+
+    #[test]
+    fn keypad_validation() {
+        assert_eq!(KeypadConfig::default().validate(), None);
+        let invalid = [
+            KeypadConfig {
+                max_pin_length: 10,
+                ..Default::default()
+            },
+            KeypadConfig {
+                min_pin_length: 0,
+                ..Default::default()
+            },
+            KeypadConfig {
+                min_pin_length: 6,
+                max_pin_length: 4,
+                ..Default::default()
+            },
+            KeypadConfig {
+                submit_key: 16,
+                ..Default::default()
+            },
+            KeypadConfig {
+                cancel_key: 5,
+                ..Default::default()
+            },
+            KeypadConfig {
+                submit_key: 0x0A,
+                ..Default::default()
+            },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_some(), "{config:?}");
+        }
+    }
+
+    #[test]
+    fn validity_limits() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_000);
+        let end = UNIX_EPOCH + Duration::from_secs(2_000);
+        let validity = Validity {
+            start: Some(start),
+            end: Some(end),
+            uses: Some(3),
+        };
+        assert!(!validity.started(start - Duration::from_secs(1)));
+        assert!(validity.started(start));
+        assert!(!validity.expired(end - Duration::from_secs(1)));
+        assert!(validity.expired(end));
+
+        let used_up = Validity {
+            uses: Some(0),
+            ..validity
+        };
+        assert!(used_up.expired(start));
+
+        let open = Validity {
+            start: None,
+            end: None,
+            uses: None,
+        };
+        assert!(open.started(UNIX_EPOCH));
+        assert!(!open.expired(end + Duration::from_secs(1_000_000)));
+    }
+
+    #[test]
+    fn digest_ignores_uses_left() {
+        let visitor = |uses| Credential {
+            validity: Some(Validity {
+                start: None,
+                end: Some(UNIX_EPOCH + Duration::from_secs(2_000)),
+                uses,
+            }),
+            ..credential(1)
+        };
+        assert_eq!(
+            Digest::from_credentials([visitor(Some(3))]),
+            Digest::from_credentials([visitor(Some(1))])
+        );
+        assert_ne!(
+            Digest::from_credentials([visitor(None)]),
+            Digest::from_credentials([credential(1)])
+        );
+    }
+
+    #[test]
+    fn empty_digest() {
+        let digest = Digest::from_credentials([]);
+        assert_eq!(digest.count, 0);
+        assert_eq!(digest.hash, Digest::FNV_OFFSET);
+    }
+
+    #[test]
+    fn digest_covers_every_field() {
+        let base = Digest::from_credentials([credential(1), credential(2)]);
+        assert_eq!(base.count, 2);
+        assert_eq!(
+            base,
+            Digest::from_credentials([credential(1), credential(2)])
+        );
+
+        let changes = [
+            Credential {
+                card: true,
+                ..credential(2)
+            },
+            Credential {
+                schedule: Some(0),
+                ..credential(2)
+            },
+            Credential {
+                pin: Some(CodeHash([0; 16])),
+                ..credential(2)
+            },
+            Credential {
+                duress: Some(CodeHash([9; 16])),
+                ..credential(2)
+            },
+            credential(3),
+        ];
+        for changed in changes {
+            assert_ne!(
+                base,
+                Digest::from_credentials([credential(1), changed]),
+                "{changed:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn digest_depends_on_order() {
+        assert_ne!(
+            Digest::from_credentials([credential(1), credential(2)]),
+            Digest::from_credentials([credential(2), credential(1)])
+        );
+    }
 }
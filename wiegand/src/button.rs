@@ -0,0 +1,156 @@
+//! Push button debouncing and long presses
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    /// Milliseconds the level must hold before it counts
+    pub debounce: u64,
+    /// Milliseconds the button must be held for a long press
+    pub long_press: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            debounce: 50,
+            long_press: 3_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Press {
+    /// The button went down
+    Down,
+    /// Released before the long press time
+    Short,
+    /// Held for the long press time, reported while still down
+    Long,
+}
+
+/// Turns raw button readings into presses
+#[derive(Debug, Default)]
+pub struct Debouncer {
+    /// Debounced level
+    pressed: bool,
+    /// When the raw level started differing from the debounced one
+    bounce: Option<u64>,
+    /// When the current press started, until it is reported as long
+    down_since: Option<u64>,
+}
+
+impl Debouncer {
+    /// Feeds a raw reading at `now`, a monotonic time in milliseconds
+    pub fn sample(&mut self, pressed: bool, now: u64, settings: &Settings) -> Option<Press> {
+        if pressed == self.pressed {
+            self.bounce = None;
+        } else {
+            let since = *self.bounce.get_or_insert(now);
+            if now.saturating_sub(since) >= settings.debounce {
+                self.bounce = None;
+                self.pressed = pressed;
+                if pressed {
+                    self.down_since = Some(since);
+                    return Some(Press::Down);
+                }
+                if self.down_since.take().is_some() {
+                    return Some(Press::Short);
+                }
+            }
+        }
+        match self.down_since {
+            Some(since) if self.pressed && now.saturating_sub(since) >= settings.long_press => {
+                self.down_since = None;
+                Some(Press::Long)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether nothing happens until the raw level changes again
+    pub fn is_settled(&self) -> bool {
+        self.bounce.is_none() && self.down_since.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTINGS: Settings = Settings {
+        debounce: 50,
+        long_press: 3_000,
+    };
+
+    /// Feeds readings as (level, time), collecting the presses
+    fn feed(debouncer: &mut Debouncer, readings: &[(bool, u64)]) -> Vec<Press> {
+        readings
+            .iter()
+            .filter_map(|&(pressed, now)| debouncer.sample(pressed, now, &SETTINGS))
+            .collect()
+    }
+
+    #[test]
+    fn short_press() {
+        let mut debouncer = Debouncer::default();
+        let presses = feed(
+            &mut debouncer,
+            &[(true, 0), (true, 50), (false, 500), (false, 550)],
+        );
+        assert_eq!(presses, [Press::Down, Press::Short]);
+        assert!(debouncer.is_settled());
+    }
+
+    #[test]
+    fn long_press() {
+        let mut debouncer = Debouncer::default();
+        let presses = feed(
+            &mut debouncer,
+            &[(true, 0), (true, 50), (true, 2_999), (true, 3_000)],
+        );
+        assert_eq!(presses, [Press::Down, Press::Long]);
+        assert!(debouncer.is_settled());
+        // Releasing after a long press reports nothing
+        assert!(feed(&mut debouncer, &[(false, 5_000), (false, 5_050)]).is_empty());
+    }
+
+    #[test]
+    fn ignores_bounces() {
+        let mut debouncer = Debouncer::default();
+        let presses = feed(
+            &mut debouncer,
+            &[
+                (true, 0),
+                (false, 10),
+                (true, 20),
+                (false, 30),
+                (false, 100),
+            ],
+        );
+        assert!(presses.is_empty());
+        assert!(debouncer.is_settled());
+    }
+
+    #[test]
+    fn bounce_restarts_debounce() {
+        let mut debouncer = Debouncer::default();
+        assert!(feed(&mut debouncer, &[(true, 0), (false, 40), (true, 60)]).is_empty());
+        assert!(!debouncer.is_settled());
+        assert!(feed(&mut debouncer, &[(true, 100)]).is_empty());
+        assert_eq!(feed(&mut debouncer, &[(true, 110)]), [Press::Down]);
+    }
+
+    #[test]
+    fn ignores_bounces_on_release() {
+        let mut debouncer = Debouncer::default();
+        feed(&mut debouncer, &[(true, 0), (true, 50)]);
+        let presses = feed(
+            &mut debouncer,
+            &[(false, 500), (true, 510), (false, 520), (true, 530)],
+        );
+        assert!(presses.is_empty());
+        assert_eq!(
+            feed(&mut debouncer, &[(false, 600), (false, 650)]),
+            [Press::Short]
+        );
+    }
+}
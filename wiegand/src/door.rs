@@ -0,0 +1,249 @@
+//! Door position tracking from the unlock requests and the door sensor
+
+/// Where the door stands
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum State {
+    /// Closed and locked
+    #[default]
+    Locked,
+    /// Lock released, waiting for the door to open
+    Unlocked,
+    /// Opened after an unlock, the lock stays released until it closes
+    Open,
+    /// Left open for longer than allowed
+    HeldOpen,
+    /// Opened without an unlock
+    Forced,
+}
+
+impl State {
+    /// Whether the lock is released in this state
+    pub fn unlocked(self) -> bool {
+        matches!(self, State::Unlocked | State::Open)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    /// Milliseconds the door can stay open after an unlock
+    pub held_open: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings { held_open: 30_000 }
+    }
+}
+
+/// Door state driven by unlock requests, sensor readings and time.
+///
+/// Every method returns the new state when it changes.
+#[derive(Debug, Default)]
+pub struct Door {
+    state: State,
+    /// When an unlocked door relocks or an open one is held open
+    deadline: u64,
+    /// Kept unlocked until released
+    held: bool,
+}
+
+impl Door {
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Releases the lock for `duration` milliseconds from `now`.
+    ///
+    /// An unlocked door has its deadline moved, an open one is left alone.
+    pub fn unlock(&mut self, now: u64, duration: u64) -> Option<State> {
+        match self.state {
+            State::Locked => {
+                self.deadline = now.saturating_add(duration);
+                self.enter(State::Unlocked)
+            }
+            State::Unlocked if !self.held => {
+                self.deadline = now.saturating_add(duration);
+                None
+            }
+            State::Unlocked | State::Open | State::HeldOpen | State::Forced => None,
+        }
+    }
+
+    /// Keeps the lock released until [`Door::release`], a door that is
+    /// already open stays unlocked once it closes
+    pub fn hold(&mut self) -> Option<State> {
+        self.held = true;
+        match self.state {
+            State::Locked => {
+                self.deadline = u64::MAX;
+                self.enter(State::Unlocked)
+            }
+            State::Unlocked | State::Open => {
+                self.deadline = u64::MAX;
+                None
+            }
+            State::HeldOpen | State::Forced => None,
+        }
+    }
+
+    /// Ends a hold, the door locks now or once it closes
+    pub fn release(&mut self, now: u64, settings: &Settings) -> Option<State> {
+        if !self.held {
+            return None;
+        }
+        self.held = false;
+        match self.state {
+            State::Unlocked => self.enter(State::Locked),
+            State::Open => {
+                self.deadline = now.saturating_add(settings.held_open);
+                None
+            }
+            State::Locked | State::HeldOpen | State::Forced => None,
+        }
+    }
+
+    /// Updates the door with a debounced sensor reading at `now`
+    pub fn sense(&mut self, closed: bool, now: u64, settings: &Settings) -> Option<State> {
+        match (self.state, closed) {
+            (State::Locked, false) => self.enter(State::Forced),
+            (State::Unlocked, false) => {
+                if !self.held {
+                    self.deadline = now.saturating_add(settings.held_open);
+                }
+                self.enter(State::Open)
+            }
+            // Relocks as soon as the door closes
+            (State::Open | State::HeldOpen | State::Forced, true) if self.held => {
+                self.enter(State::Unlocked)
+            }
+            (State::Open | State::HeldOpen | State::Forced, true) => self.enter(State::Locked),
+            _ => None,
+        }
+    }
+
+    /// Relocks an unlock nobody used and flags a door left open
+    pub fn poll(&mut self, now: u64) -> Option<State> {
+        match self.state {
+            State::Unlocked if now >= self.deadline => self.enter(State::Locked),
+            State::Open if now >= self.deadline => self.enter(State::HeldOpen),
+            _ => None,
+        }
+    }
+
+    fn enter(&mut self, state: State) -> Option<State> {
+        self.state = state;
+        Some(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTINGS: Settings = Settings { held_open: 30_000 };
+
+    #[test]
+    fn relocks_unused_unlock() {
+        let mut door = Door::default();
+        assert_eq!(door.unlock(0, 4_000), Some(State::Unlocked));
+        assert_eq!(door.poll(3_999), None);
+        assert_eq!(door.poll(4_000), Some(State::Locked));
+        assert!(!door.state().unlocked());
+    }
+
+    #[test]
+    fn unlock_extends_deadline() {
+        let mut door = Door::default();
+        door.unlock(0, 4_000);
+        assert_eq!(door.unlock(3_000, 4_000), None);
+        assert_eq!(door.poll(4_000), None);
+        assert_eq!(door.poll(7_000), Some(State::Locked));
+    }
+
+    #[test]
+    fn relocks_when_closed() {
+        let mut door = Door::default();
+        door.unlock(0, 4_000);
+        assert_eq!(door.sense(false, 1_000, &SETTINGS), Some(State::Open));
+        assert!(door.state().unlocked());
+        // The lock stays released past the unlock time while the door is open
+        assert_eq!(door.poll(5_000), None);
+        assert_eq!(door.sense(true, 6_000, &SETTINGS), Some(State::Locked));
+    }
+
+    #[test]
+    fn flags_held_open() {
+        let mut door = Door::default();
+        door.unlock(0, 4_000);
+        door.sense(false, 1_000, &SETTINGS);
+        assert_eq!(door.poll(30_999), None);
+        assert_eq!(door.poll(31_000), Some(State::HeldOpen));
+        assert!(!door.state().unlocked());
+        assert_eq!(door.poll(60_000), None);
+        assert_eq!(door.sense(true, 90_000, &SETTINGS), Some(State::Locked));
+    }
+
+    #[test]
+    fn flags_forced_open() {
+        let mut door = Door::default();
+        assert_eq!(door.sense(false, 0, &SETTINGS), Some(State::Forced));
+        assert!(!door.state().unlocked());
+        assert_eq!(door.unlock(1_000, 4_000), None);
+        assert_eq!(door.poll(60_000), None);
+        assert_eq!(door.sense(true, 60_000, &SETTINGS), Some(State::Locked));
+    }
+
+    #[test]
+    fn holds_until_released() {
+        let mut door = Door::default();
+        assert_eq!(door.hold(), Some(State::Unlocked));
+        assert_eq!(door.unlock(1_000, 4_000), None);
+        assert_eq!(door.poll(3_600_000), None);
+        assert_eq!(door.release(3_600_000, &SETTINGS), Some(State::Locked));
+        assert_eq!(door.release(3_600_000, &SETTINGS), None);
+    }
+
+    #[test]
+    fn held_door_stays_unlocked_when_closed() {
+        let mut door = Door::default();
+        door.hold();
+        assert_eq!(door.sense(false, 1_000, &SETTINGS), Some(State::Open));
+        assert_eq!(door.poll(3_600_000), None);
+        assert_eq!(
+            door.sense(true, 3_600_000, &SETTINGS),
+            Some(State::Unlocked)
+        );
+        assert!(door.state().unlocked());
+    }
+
+    #[test]
+    fn release_while_open_starts_held_open_time() {
+        let mut door = Door::default();
+        door.hold();
+        door.sense(false, 1_000, &SETTINGS);
+        assert_eq!(door.release(100_000, &SETTINGS), None);
+        assert_eq!(door.poll(129_999), None);
+        assert_eq!(door.poll(130_000), Some(State::HeldOpen));
+        assert_eq!(door.sense(true, 140_000, &SETTINGS), Some(State::Locked));
+    }
+
+    #[test]
+    fn hold_extends_unlock() {
+        let mut door = Door::default();
+        door.unlock(0, 4_000);
+        assert_eq!(door.hold(), None);
+        assert_eq!(door.poll(4_000), None);
+        assert_eq!(door.release(5_000, &SETTINGS), Some(State::Locked));
+    }
+
+    #[test]
+    fn ignores_repeated_readings() {
+        let mut door = Door::default();
+        assert_eq!(door.sense(true, 0, &SETTINGS), None);
+        door.unlock(0, 4_000);
+        assert_eq!(door.sense(true, 1_000, &SETTINGS), None);
+        door.sense(false, 2_000, &SETTINGS);
+        assert_eq!(door.sense(false, 3_000, &SETTINGS), None);
+        assert_eq!(door.state(), State::Open);
+    }
+}
@@ -0,0 +1,400 @@
+/// Fits the widest card format, 64 bits
+pub const BUFFER_SIZE: usize = 8;
+
+/// Bits received from a reader, the first one in the most significant position
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Frame {
+    bits: usize,
+    data: [u8; BUFFER_SIZE],
+}
+
+impl Frame {
+    /// Always inlined so interrupt handlers keep it in IRAM
+    #[inline(always)]
+    pub fn is_full(&self) -> bool {
+        self.bits >= BUFFER_SIZE * 8
+    }
+
+    /// Appends a bit, ignored once the buffer is full
+    #[inline(always)]
+    pub fn push(&mut self, one: bool) {
+        if self.is_full() {
+            return;
+        }
+        if one {
+            self.data[self.bits / 8] |= 0x80 >> (self.bits % 8);
+        }
+        self.bits += 1;
+    }
+
+    pub fn bits(&self) -> usize {
+        self.bits
+    }
+
+    pub fn clear(&mut self) {
+        *self = Frame::default();
+    }
+
+    pub fn decode(&self) -> Packet {
+        Packet::new(self.bits, self.data)
+    }
+}
+
+/// Bits `from..=to` of a frame, counted from the first bit received
+const fn span(bits: usize, from: usize, to: usize) -> u64 {
+    let mut mask = 0;
+    let mut pos = from;
+    while pos <= to {
+        mask |= 1 << (bits - 1 - pos);
+        pos += 1;
+    }
+    mask
+}
+
+/// Bits `from..=to` of a frame skipping every position where `pos % 3 == skip`
+const fn every_third_skipped(bits: usize, from: usize, to: usize, skip: usize) -> u64 {
+    let mut mask = 0;
+    let mut pos = from;
+    while pos <= to {
+        if pos % 3 != skip {
+            mask |= 1 << (bits - 1 - pos);
+        }
+        pos += 1;
+    }
+    mask
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Parity {
+    Even,
+    Odd,
+}
+
+/// Parity over the masked bits, the parity bit itself included
+#[derive(Debug)]
+struct ParityRule {
+    parity: Parity,
+    mask: u64,
+}
+
+impl ParityRule {
+    fn check(&self, value: u64) -> bool {
+        let odd = (value & self.mask).count_ones() % 2 == 1;
+        match self.parity {
+            Parity::Even => !odd,
+            Parity::Odd => odd,
+        }
+    }
+}
+
+/// Bits `start..start + len` of a frame
+#[derive(Debug)]
+struct Field {
+    start: usize,
+    len: usize,
+}
+
+impl Field {
+    fn extract(&self, bits: usize, value: u64) -> u32 {
+        ((value >> (bits - self.start - self.len)) & ((1 << self.len) - 1)) as u32
+    }
+}
+
+/// Layout of a card format
+#[derive(Debug)]
+struct Format {
+    name: &'static str,
+    bits: usize,
+    parity: &'static [ParityRule],
+    facility: Field,
+    number: Field,
+}
+
+/// Card formats recognized by the reader, the first one matching the frame
+/// length and parity wins.
+///
+/// Reference:
+/// https://getsafeandsound.com/blog/26-bit-wiegand-format/
+/// https://www.hidglobal.com/sites/default/files/hid-understanding_card_data_formats-wp-en.pdf
+/// Calculator
+/// http://www.ccdesignworks.com/wiegand_calc.htm
+static FORMATS: &[Format] = &[
+    // H10301, standard 26-bit
+    Format {
+        name: "H10301",
+        bits: 26,
+        parity: &[
+            ParityRule {
+                parity: Parity::Even,
+                mask: span(26, 0, 12),
+            },
+            ParityRule {
+                parity: Parity::Odd,
+                mask: span(26, 13, 25),
+            },
+        ],
+        facility: Field { start: 1, len: 8 },
+        number: Field { start: 9, len: 16 },
+    },
+    // H10306, 34-bit with a 16-bit facility code
+    Format {
+        name: "H10306",
+        bits: 34,
+        parity: &[
+            ParityRule {
+                parity: Parity::Even,
+                mask: span(34, 0, 16),
+            },
+            ParityRule {
+                parity: Parity::Odd,
+                mask: span(34, 17, 33),
+            },
+        ],
+        facility: Field { start: 1, len: 16 },
+        number: Field { start: 17, len: 16 },
+    },
+    // HID Corporate 1000, 35-bit
+    Format {
+        name: "C1000",
+        bits: 35,
+        parity: &[
+            ParityRule {
+                parity: Parity::Even,
+                mask: span(35, 1, 1) | every_third_skipped(35, 2, 33, 1),
+            },
+            ParityRule {
+                parity: Parity::Odd,
+                mask: every_third_skipped(35, 1, 32, 0) | span(35, 34, 34),
+            },
+            ParityRule {
+                parity: Parity::Odd,
+                mask: span(35, 0, 34),
+            },
+        ],
+        facility: Field { start: 2, len: 12 },
+        number: Field { start: 14, len: 20 },
+    },
+    // H10304, 37-bit with a 16-bit facility code
+    Format {
+        name: "H10304",
+        bits: 37,
+        parity: &[
+            ParityRule {
+                parity: Parity::Even,
+                mask: span(37, 0, 18),
+            },
+            ParityRule {
+                parity: Parity::Odd,
+                mask: span(37, 18, 36),
+            },
+        ],
+        facility: Field { start: 1, len: 16 },
+        number: Field { start: 17, len: 19 },
+    },
+];
+
+#[derive(Debug)]
+pub enum Packet {
+    /// Key press, from a 4-bit frame or an 8-bit frame with its complement
+    Key { key: u8, bits: usize },
+    Card {
+        format: &'static str,
+        facility: u32,
+        number: i32,
+    },
+    BadParity {
+        bits: usize,
+        data: [u8; BUFFER_SIZE],
+    },
+    Unknown {
+        bits: usize,
+        data: [u8; BUFFER_SIZE],
+    },
+}
+
+impl Packet {
+    fn new(bits: usize, data: [u8; BUFFER_SIZE]) -> Self {
+        log::info!("data received; bits: {}, data: {:02X?}", bits, data);
+        match bits {
+            4 => {
+                return Self::Key {
+                    key: data[0] >> 4,
+                    bits,
+                }
+            }
+            8 => {
+                let key = data[0] & 0x0f;
+                return match data[0] >> 4 == !key & 0x0f {
+                    true => Self::Key { key, bits },
+                    false => Self::BadParity { bits, data },
+                };
+            }
+            _ => {}
+        }
+
+        let mut formats = FORMATS.iter().filter(|f| f.bits == bits).peekable();
+        if formats.peek().is_none() {
+            return Self::Unknown { bits, data };
+        }
+
+        // Frame bits are stored from the most significant bit
+        let value = u64::from_be_bytes(data) >> (BUFFER_SIZE * 8 - bits);
+        for format in formats {
+            if !format.parity.iter().all(|rule| rule.check(value)) {
+                continue;
+            }
+            return Self::Card {
+                format: format.name,
+                facility: format.facility.extract(bits, value),
+                number: format.number.extract(bits, value) as i32,
+            };
+        }
+        Self::BadParity { bits, data }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(bits: &str) -> Frame {
+        let mut frame = Frame::default();
+        for bit in bits.chars().filter(|c| !c.is_whitespace()) {
+            frame.push(bit == '1');
+        }
+        frame
+    }
+
+    fn frame_from_bytes(bits: usize, bytes: &[u8]) -> Frame {
+        let mut frame = Frame::default();
+        for i in 0..bits {
+            frame.push(bytes[i / 8] & (0x80 >> (i % 8)) != 0);
+        }
+        frame
+    }
+
+    /// Flips a single bit, breaking the parity of the frame
+    fn flip(frame: &Frame, pos: usize) -> Frame {
+        let mut flipped = *frame;
+        flipped.data[pos / 8] ^= 0x80 >> (pos % 8);
+        flipped
+    }
+
+    fn assert_card(frame: &Frame, format: &str, facility: u32, number: i32) {
+        match frame.decode() {
+            Packet::Card {
+                format: f,
+                facility: fc,
+                number: n,
+            } => assert_eq!((f, fc, n), (format, facility, number)),
+            packet => panic!("expected card, found {packet:?}"),
+        }
+    }
+
+    fn assert_bad_parity(frame: &Frame) {
+        assert!(
+            matches!(frame.decode(), Packet::BadParity { .. }),
+            "expected bad parity for {frame:?}"
+        );
+    }
+
+    #[test]
+    fn push_stores_bits_from_the_most_significant() {
+        let frame = frame("1010 0001 1");
+        assert_eq!(frame.bits(), 9);
+        assert_eq!(frame.data[..2], [0xA1, 0x80]);
+    }
+
+    #[test]
+    fn push_ignores_bits_past_the_buffer() {
+        let mut frame = Frame::default();
+        for _ in 0..BUFFER_SIZE * 8 {
+            assert!(!frame.is_full());
+            frame.push(true);
+        }
+        assert!(frame.is_full());
+        frame.push(true);
+        assert_eq!(frame.bits(), BUFFER_SIZE * 8);
+    }
+
+    #[test]
+    fn clear_resets_the_frame() {
+        let mut frame = frame("1111");
+        frame.clear();
+        assert_eq!(frame.bits(), 0);
+        assert_eq!(frame.data, [0; BUFFER_SIZE]);
+    }
+
+    #[test]
+    fn decodes_4_bit_keys() {
+        for key in 0..16u8 {
+            let frame = frame_from_bytes(4, &[key << 4]);
+            assert!(matches!(frame.decode(), Packet::Key { key: k, bits: 4 } if k == key));
+        }
+    }
+
+    #[test]
+    fn decodes_8_bit_keys_with_complement() {
+        for key in 0..16u8 {
+            let frame = frame_from_bytes(8, &[(!key << 4) | key]);
+            assert!(matches!(frame.decode(), Packet::Key { key: k, bits: 8 } if k == key));
+        }
+    }
+
+    #[test]
+    fn rejects_8_bit_keys_without_complement() {
+        assert_bad_parity(&frame("0000 0101"));
+        assert_bad_parity(&frame("1111 0101"));
+    }
+
+    #[test]
+    fn decodes_26_bit_cards() {
+        assert_card(&frame("1 00000001 0000000000000001 0"), "H10301", 1, 1);
+        let frame = frame_from_bytes(26, &[0x09, 0x18, 0x1C, 0xC0]);
+        assert_card(&frame, "H10301", 18, 12345);
+    }
+
+    #[test]
+    fn decodes_34_bit_cards() {
+        let frame = frame_from_bytes(34, &[0xEA, 0x18, 0x88, 0x49, 0x40]);
+        assert_card(&frame, "H10306", 54321, 4242);
+    }
+
+    #[test]
+    fn decodes_35_bit_corporate_1000_cards() {
+        let frame = frame_from_bytes(35, &[0xEE, 0xE7, 0xC4, 0x81, 0xA0]);
+        assert_card(&frame, "C1000", 3001, 987654);
+    }
+
+    #[test]
+    fn decodes_37_bit_cards() {
+        let frame = frame_from_bytes(37, &[0xF5, 0x30, 0x7A, 0x12, 0x08]);
+        assert_card(&frame, "H10304", 60000, 500000);
+    }
+
+    #[test]
+    fn detects_any_single_bit_error() {
+        let cards = [
+            frame_from_bytes(26, &[0x09, 0x18, 0x1C, 0xC0]),
+            frame_from_bytes(34, &[0xEA, 0x18, 0x88, 0x49, 0x40]),
+            frame_from_bytes(35, &[0xEE, 0xE7, 0xC4, 0x81, 0xA0]),
+            frame_from_bytes(37, &[0xF5, 0x30, 0x7A, 0x12, 0x08]),
+        ];
+        for card in &cards {
+            for pos in 0..card.bits() {
+                assert_bad_parity(&flip(card, pos));
+            }
+        }
+    }
+
+    #[test]
+    fn reports_unknown_lengths() {
+        for bits in [0, 1, 12, 25, 27, 36, 64] {
+            let frame = frame_from_bytes(bits, &[0xFF; BUFFER_SIZE]);
+            assert!(
+                matches!(frame.decode(), Packet::Unknown { bits: b, .. } if b == bits),
+                "{bits} bits"
+            );
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! Pin entry from keypad key presses
+
+/// Longest pin that fits an `i32`
+pub const MAX_PIN_LENGTH: u8 = 9;
+
+/// Why a pin entry was dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbortReason {
+    /// Submitted with fewer keys than the minimum length
+    TooShort,
+    /// More keys pressed than the maximum length
+    TooLong,
+    Cancelled,
+    /// No key pressed for longer than the timeout
+    TimedOut,
+}
+
+/// Outcome of a key press
+#[derive(Debug, PartialEq, Eq)]
+pub enum Entry {
+    /// Pin ready to be checked
+    Submit(i32),
+    /// Entry dropped, with the keys pressed so far when there were any
+    Abort(Option<i32>, AbortReason),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub min_pin_length: u8,
+    /// Capped at [`MAX_PIN_LENGTH`]
+    pub max_pin_length: u8,
+    pub submit_key: u8,
+    pub cancel_key: u8,
+    /// Submits the pin as soon as it reaches the maximum length
+    pub auto_submit: bool,
+    /// Milliseconds without a key press before the entry is dropped
+    pub timeout: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            min_pin_length: 1,
+            max_pin_length: 8,
+            submit_key: 0x0B,
+            cancel_key: 0x0A,
+            auto_submit: false,
+            timeout: 10_000,
+        }
+    }
+}
+
+impl Settings {
+    fn max_len(&self) -> u8 {
+        self.max_pin_length.min(MAX_PIN_LENGTH)
+    }
+}
+
+/// Pin being entered, built as keys are pressed
+#[derive(Debug, Default)]
+pub struct Keypad {
+    pin: i32,
+    len: u8,
+    /// Time of the last key press in milliseconds
+    last_press: u64,
+}
+
+impl Keypad {
+    /// Handles a key pressed at `now`, a monotonic time in milliseconds.
+    ///
+    /// [`Keypad::poll`] must be called first so a stale entry times out
+    /// instead of taking the key.
+    pub fn press(&mut self, key: u8, now: u64, settings: &Settings) -> Option<Entry> {
+        self.last_press = now;
+        if key == settings.submit_key {
+            if self.len < settings.min_pin_length {
+                log::warn!("pin sequence is too short, {} keys", self.len);
+                return Some(Entry::Abort(self.take(), AbortReason::TooShort));
+            }
+            return self.take().map(Entry::Submit);
+        }
+        if key == settings.cancel_key {
+            log::info!("Cancel sequence");
+            return Some(Entry::Abort(self.take(), AbortReason::Cancelled));
+        }
+        if key > 9 {
+            log::warn!("ignoring key {:X}", key);
+            return None;
+        }
+        if self.len >= settings.max_len() {
+            log::warn!("pin sequence is too big, {} keys", self.len);
+            return Some(Entry::Abort(self.take(), AbortReason::TooLong));
+        }
+        self.pin = self.pin * 10 + key as i32;
+        self.len += 1;
+        if settings.auto_submit && self.len == settings.max_len() {
+            return self.take().map(Entry::Submit);
+        }
+        None
+    }
+
+    /// Drops the entry when no key was pressed within the timeout
+    pub fn poll(&mut self, now: u64, settings: &Settings) -> Option<Entry> {
+        if self.len == 0 || now.saturating_sub(self.last_press) < settings.timeout {
+            return None;
+        }
+        let pin = self.take();
+        log::warn!("incomplete pin sequence {:?}", pin);
+        Some(Entry::Abort(pin, AbortReason::TimedOut))
+    }
+
+    pub fn clear(&mut self) {
+        self.take();
+    }
+
+    fn take(&mut self) -> Option<i32> {
+        let pin = (self.len > 0).then_some(self.pin);
+        self.pin = 0;
+        self.len = 0;
+        pin
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUBMIT: u8 = 0x0B;
+    const CANCEL: u8 = 0x0A;
+
+    /// Presses the keys one millisecond apart, returning the last outcome
+    fn press_all(keypad: &mut Keypad, keys: &[u8], settings: &Settings) -> Option<Entry> {
+        let mut entry = None;
+        for (i, &key) in keys.iter().enumerate() {
+            assert_eq!(entry, None, "entry finished before the last key");
+            entry = keypad.press(key, i as u64, settings);
+        }
+        entry
+    }
+
+    #[test]
+    fn submits_pin() {
+        let mut keypad = Keypad::default();
+        let entry = press_all(&mut keypad, &[1, 2, 3, 4, SUBMIT], &Settings::default());
+        assert_eq!(entry, Some(Entry::Submit(1234)));
+    }
+
+    #[test]
+    fn leading_zeros_are_dropped() {
+        let mut keypad = Keypad::default();
+        let entry = press_all(&mut keypad, &[0, 0, 7, SUBMIT], &Settings::default());
+        assert_eq!(entry, Some(Entry::Submit(7)));
+    }
+
+    #[test]
+    fn starts_over_after_submit() {
+        let settings = Settings::default();
+        let mut keypad = Keypad::default();
+        press_all(&mut keypad, &[1, 2, SUBMIT], &settings);
+        let entry = press_all(&mut keypad, &[3, SUBMIT], &settings);
+        assert_eq!(entry, Some(Entry::Submit(3)));
+    }
+
+    #[test]
+    fn rejects_short_pins() {
+        let settings = Settings {
+            min_pin_length: 4,
+            ..Default::default()
+        };
+        let mut keypad = Keypad::default();
+        let entry = press_all(&mut keypad, &[1, 2, 3, SUBMIT], &settings);
+        assert_eq!(entry, Some(Entry::Abort(Some(123), AbortReason::TooShort)));
+    }
+
+    #[test]
+    fn rejects_empty_submit() {
+        let mut keypad = Keypad::default();
+        let entry = press_all(&mut keypad, &[SUBMIT], &Settings::default());
+        assert_eq!(entry, Some(Entry::Abort(None, AbortReason::TooShort)));
+    }
+
+    #[test]
+    fn rejects_long_pins() {
+        let settings = Settings {
+            max_pin_length: 4,
+            ..Default::default()
+        };
+        let mut keypad = Keypad::default();
+        let entry = press_all(&mut keypad, &[1, 2, 3, 4, 5], &settings);
+        assert_eq!(entry, Some(Entry::Abort(Some(1234), AbortReason::TooLong)));
+    }
+
+    #[test]
+    fn caps_length_to_fit_an_i32() {
+        let settings = Settings {
+            max_pin_length: 12,
+            ..Default::default()
+        };
+        let mut keypad = Keypad::default();
+        let entry = press_all(&mut keypad, &[9; 10], &settings);
+        assert_eq!(
+            entry,
+            Some(Entry::Abort(Some(999_999_999), AbortReason::TooLong))
+        );
+    }
+
+    #[test]
+    fn auto_submits_at_max_length() {
+        let settings = Settings {
+            max_pin_length: 4,
+            auto_submit: true,
+            ..Default::default()
+        };
+        let mut keypad = Keypad::default();
+        let entry = press_all(&mut keypad, &[4, 3, 2, 1], &settings);
+        assert_eq!(entry, Some(Entry::Submit(4321)));
+    }
+
+    #[test]
+    fn auto_submit_still_accepts_submit_key() {
+        let settings = Settings {
+            max_pin_length: 6,
+            auto_submit: true,
+            ..Default::default()
+        };
+        let mut keypad = Keypad::default();
+        let entry = press_all(&mut keypad, &[4, 3, 2, 1, SUBMIT], &settings);
+        assert_eq!(entry, Some(Entry::Submit(4321)));
+    }
+
+    #[test]
+    fn cancels_entry() {
+        let mut keypad = Keypad::default();
+        let entry = press_all(&mut keypad, &[1, 2, CANCEL], &Settings::default());
+        assert_eq!(entry, Some(Entry::Abort(Some(12), AbortReason::Cancelled)));
+
+        let entry = press_all(&mut keypad, &[CANCEL], &Settings::default());
+        assert_eq!(entry, Some(Entry::Abort(None, AbortReason::Cancelled)));
+    }
+
+    #[test]
+    fn uses_configured_keys() {
+        let settings = Settings {
+            submit_key: 0x0E,
+            cancel_key: 0x0F,
+            ..Default::default()
+        };
+        let mut keypad = Keypad::default();
+        let entry = press_all(&mut keypad, &[5, SUBMIT, 6, 0x0E], &settings);
+        assert_eq!(entry, Some(Entry::Submit(56)));
+
+        let entry = press_all(&mut keypad, &[5, 0x0F], &settings);
+        assert_eq!(entry, Some(Entry::Abort(Some(5), AbortReason::Cancelled)));
+    }
+
+    #[test]
+    fn ignores_other_keys() {
+        let mut keypad = Keypad::default();
+        let entry = press_all(
+            &mut keypad,
+            &[1, 0x0C, 0x0D, 2, SUBMIT],
+            &Settings::default(),
+        );
+        assert_eq!(entry, Some(Entry::Submit(12)));
+    }
+
+    #[test]
+    fn times_out_idle_entry() {
+        let settings = Settings::default();
+        let mut keypad = Keypad::default();
+        keypad.press(1, 1_000, &settings);
+        keypad.press(2, 2_000, &settings);
+        assert_eq!(keypad.poll(11_999, &settings), None);
+        assert_eq!(
+            keypad.poll(12_000, &settings),
+            Some(Entry::Abort(Some(12), AbortReason::TimedOut))
+        );
+        assert_eq!(keypad.poll(20_000, &settings), None);
+    }
+
+    #[test]
+    fn poll_ignores_empty_entry() {
+        let settings = Settings::default();
+        let mut keypad = Keypad::default();
+        assert_eq!(keypad.poll(u64::MAX, &settings), None);
+        press_all(&mut keypad, &[1, SUBMIT], &settings);
+        assert_eq!(keypad.poll(u64::MAX, &settings), None);
+    }
+
+    #[test]
+    fn poll_tolerates_clock_going_back() {
+        let settings = Settings::default();
+        let mut keypad = Keypad::default();
+        keypad.press(1, 5_000, &settings);
+        assert_eq!(keypad.poll(0, &settings), None);
+    }
+
+    #[test]
+    fn clear_drops_entry() {
+        let settings = Settings::default();
+        let mut keypad = Keypad::default();
+        press_all(&mut keypad, &[1, 2], &settings);
+        keypad.clear();
+        let entry = press_all(&mut keypad, &[3, SUBMIT], &settings);
+        assert_eq!(entry, Some(Entry::Submit(3)));
+    }
+}
@@ -0,0 +1,14 @@
+//! Hardware independent Wiegand frame decoding, keypad pin entry, lockout,
+//! door position tracking and button presses.
+//!
+//! Doesn't depend on `std` or an allocator so it can be used from interrupt
+//! handlers and tested on the host.
+#![cfg_attr(not(test), no_std)]
+
+pub mod button;
+pub mod door;
+mod frame;
+pub mod keypad;
+pub mod lockout;
+
+pub use frame::{Frame, Packet, BUFFER_SIZE};
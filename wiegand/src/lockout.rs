@@ -0,0 +1,263 @@
+//! Keypad lockout after repeated failed attempts
+
+/// Most failures tracked, caps the configurable threshold
+pub const MAX_FAILURES: u8 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    /// Failed attempts within the window that lock the keypad, 0 disables the lockout
+    pub max_failures: u8,
+    /// Milliseconds failures are counted over
+    pub window: u64,
+    /// Milliseconds the keypad stays locked
+    pub duration: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            max_failures: 5,
+            window: 60_000,
+            duration: 300_000,
+        }
+    }
+}
+
+/// Part of the lockout kept across restarts, the times of the failures are
+/// monotonic and don't survive one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Saved {
+    pub failures: u8,
+    pub locked: bool,
+}
+
+/// Consecutive failed attempts and the lockout they trigger
+#[derive(Debug, Default)]
+pub struct Lockout {
+    /// Times of the failures since the last success, oldest first
+    failures: [u64; MAX_FAILURES as usize],
+    len: usize,
+    locked_until: Option<u64>,
+}
+
+impl Lockout {
+    /// Restores a saved lockout at `now`. The failures count as happening
+    /// now and a lock starts over, so a restart never shortens it.
+    pub fn restore(saved: Saved, now: u64, settings: &Settings) -> Self {
+        let len = (saved.failures as usize).min(MAX_FAILURES as usize - 1);
+        let mut lockout = Lockout {
+            len,
+            ..Default::default()
+        };
+        lockout.failures[..len].fill(now);
+        if saved.locked {
+            lockout.locked_until = Some(now.saturating_add(settings.duration));
+        }
+        lockout
+    }
+
+    /// State to persist so a restart doesn't clear the lockout
+    pub fn saved(&self) -> Saved {
+        Saved {
+            failures: self.len as u8,
+            locked: self.locked_until.is_some(),
+        }
+    }
+
+    /// Records a failed attempt at `now`, a monotonic time in milliseconds.
+    ///
+    /// Returns `true` when the failure locks the keypad.
+    pub fn failure(&mut self, now: u64, settings: &Settings) -> bool {
+        let max = settings.max_failures.min(MAX_FAILURES) as usize;
+        if max == 0 {
+            return false;
+        }
+        // Drops failures that left the window, and the oldest ones when
+        // the threshold was lowered
+        let expired = self.failures[..self.len]
+            .iter()
+            .position(|&time| now.saturating_sub(time) < settings.window)
+            .unwrap_or(self.len)
+            .max((self.len + 1).saturating_sub(max));
+        self.failures.copy_within(expired..self.len, 0);
+        self.len -= expired;
+
+        self.failures[self.len] = now;
+        self.len += 1;
+        if self.len < max {
+            return false;
+        }
+        log::warn!("{} failed attempts, locking keypad", self.len);
+        self.len = 0;
+        self.locked_until = Some(now.saturating_add(settings.duration));
+        true
+    }
+
+    /// A successful attempt ends the run of failures
+    pub fn success(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn is_locked(&mut self, now: u64) -> bool {
+        match self.locked_until {
+            Some(until) if now < until => true,
+            Some(_) => {
+                log::info!("Keypad unlocked");
+                self.locked_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTINGS: Settings = Settings {
+        max_failures: 3,
+        window: 60_000,
+        duration: 300_000,
+    };
+
+    /// Records a failure at each time, returning whether the last one locked
+    fn fail_at(lockout: &mut Lockout, times: &[u64], settings: &Settings) -> bool {
+        let mut locked = false;
+        for &time in times {
+            assert!(!locked, "locked before the last failure");
+            locked = lockout.failure(time, settings);
+        }
+        locked
+    }
+
+    #[test]
+    fn locks_after_max_failures() {
+        let mut lockout = Lockout::default();
+        assert!(fail_at(&mut lockout, &[0, 1_000, 2_000], &SETTINGS));
+        assert!(lockout.is_locked(2_000));
+    }
+
+    #[test]
+    fn unlocks_after_duration() {
+        let mut lockout = Lockout::default();
+        fail_at(&mut lockout, &[0, 1_000, 2_000], &SETTINGS);
+        assert!(lockout.is_locked(301_999));
+        assert!(!lockout.is_locked(302_000));
+        assert!(!lockout.is_locked(0));
+    }
+
+    #[test]
+    fn starts_counting_again_after_lockout() {
+        let mut lockout = Lockout::default();
+        fail_at(&mut lockout, &[0, 1_000, 2_000], &SETTINGS);
+        assert!(!lockout.failure(302_000, &SETTINGS));
+        assert!(!lockout.failure(303_000, &SETTINGS));
+        assert!(lockout.failure(304_000, &SETTINGS));
+    }
+
+    #[test]
+    fn ignores_failures_outside_the_window() {
+        let mut lockout = Lockout::default();
+        assert!(!fail_at(
+            &mut lockout,
+            &[0, 30_000, 60_000, 90_000],
+            &SETTINGS
+        ));
+        assert!(!lockout.is_locked(90_000));
+        assert!(lockout.failure(100_000, &SETTINGS));
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let mut lockout = Lockout::default();
+        fail_at(&mut lockout, &[0, 1_000], &SETTINGS);
+        lockout.success();
+        assert!(!fail_at(&mut lockout, &[2_000, 3_000], &SETTINGS));
+        assert!(!lockout.is_locked(3_000));
+    }
+
+    #[test]
+    fn zero_failures_disables_lockout() {
+        let settings = Settings {
+            max_failures: 0,
+            ..SETTINGS
+        };
+        let mut lockout = Lockout::default();
+        assert!(!fail_at(&mut lockout, &[0; 100], &settings));
+        assert!(!lockout.is_locked(0));
+    }
+
+    #[test]
+    fn caps_max_failures() {
+        let settings = Settings {
+            max_failures: u8::MAX,
+            ..SETTINGS
+        };
+        let mut lockout = Lockout::default();
+        let times: Vec<u64> = (0..MAX_FAILURES as u64).collect();
+        assert!(fail_at(&mut lockout, &times, &settings));
+    }
+
+    #[test]
+    fn lowering_max_failures_applies_to_the_next_failure() {
+        let mut lockout = Lockout::default();
+        fail_at(
+            &mut lockout,
+            &[0, 1_000, 2_000, 3_000],
+            &Settings::default(),
+        );
+        assert!(lockout.failure(4_000, &SETTINGS));
+    }
+
+    #[test]
+    fn restart_keeps_failures() {
+        let mut lockout = Lockout::default();
+        fail_at(&mut lockout, &[0, 1_000], &SETTINGS);
+        let saved = lockout.saved();
+        assert_eq!(
+            saved,
+            Saved {
+                failures: 2,
+                locked: false
+            }
+        );
+
+        let mut lockout = Lockout::restore(saved, 0, &SETTINGS);
+        assert!(!lockout.is_locked(0));
+        assert!(lockout.failure(500, &SETTINGS));
+    }
+
+    #[test]
+    fn restart_starts_the_lock_over() {
+        let mut lockout = Lockout::default();
+        fail_at(&mut lockout, &[0, 1_000, 2_000], &SETTINGS);
+        let saved = lockout.saved();
+        assert!(saved.locked);
+
+        let mut lockout = Lockout::restore(saved, 0, &SETTINGS);
+        assert!(lockout.is_locked(299_999));
+        assert!(!lockout.is_locked(300_000));
+        assert_eq!(lockout.saved(), Saved::default());
+    }
+
+    #[test]
+    fn restore_caps_failures() {
+        let saved = Saved {
+            failures: u8::MAX,
+            locked: false,
+        };
+        let mut lockout = Lockout::restore(saved, 0, &SETTINGS);
+        assert!(lockout.failure(0, &SETTINGS));
+    }
+
+    #[test]
+    fn locks_with_a_single_failure() {
+        let settings = Settings {
+            max_failures: 1,
+            ..SETTINGS
+        };
+        let mut lockout = Lockout::default();
+        assert!(lockout.failure(0, &settings));
+    }
+}